@@ -1,57 +1,119 @@
 use crate::prelude::*;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn ask(
     handle: GuyHandle,
+    profile: ChatGptProfile,
+    pricing: PricingTable,
+    request_log: Option<PathBuf>,
     role: ChatCompletionRole,
     message: Option<String>,
+    audio: Option<(PathBuf, TranscriptionOptions)>,
+    images: Vec<ImageUrl>,
     interactive: bool,
     completion: bool,
+    stream: bool,
+    parameters: ChatCompletionParameters,
 ) -> IaResult<()> {
-    let keychain = KeyChain::from_env();
-    let connector = OpenAIConnector::new(&keychain);
+    let connector = super::openai_connector(profile, request_log.as_deref())?;
+    let transcript = match audio {
+        Some((path, options)) => {
+            let transcription = connector.transcribe(&path, &options).await?;
+            print_success!("{:?} transcribed", path);
+            Some(transcription.text)
+        }
+        None => None,
+    };
+    let message = match images.is_empty() {
+        true => message.map(MessageContent::from),
+        false => {
+            let mut content = MessageContent::from(message.unwrap_or_default());
+            images.into_iter().for_each(|image| content.push_image(image));
+            Some(content)
+        }
+    };
 
     if interactive {
-        ask_interactive(connector, handle, role, message).await
+        ask_interactive(connector, handle, pricing, parameters, role, message, transcript, stream).await
     } else {
-        ask_non_interactive(connector, handle, role, message, completion).await
+        ask_non_interactive(connector, handle, pricing, parameters, role, message, transcript, completion, stream).await
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn ask_non_interactive(
     mut connector: OpenAIConnector,
     handle: GuyHandle,
+    pricing: PricingTable,
+    parameters: ChatCompletionParameters,
     role: ChatCompletionRole,
-    message: Option<String>,
+    message: Option<MessageContent>,
+    transcript: Option<String>,
     completion: bool,
+    stream: bool,
 ) -> IaResult<()> {
     let mut guy = handle.get_guy()?;
+    guy.pricing = pricing;
+    guy.parameters.merge(parameters);
+    attach_knowledge(&mut guy, &handle, &connector)?;
+    attach_guard(&mut guy, &connector);
+    register_unavailable_handlers(&mut guy);
+    if let Some(transcript) = transcript {
+        guy.push_message(transcript, ChatCompletionRole::User);
+    }
     if let Some(message) = message {
         guy.push_message(message, role);
     }
-    if completion && guy.history.len() > 0 {
-        let response = guy.completion(&mut connector).await?;
-        println!("{}", response.choices[0].message.content);
-    } else {
+    let mut result = Ok(());
+    if !completion || guy.history.is_empty() {
         print_warning!("Nothing to complete")
+    } else if !warn_context_overflow(&guy, &connector) {
+        let compactions = guy.compactions.len();
+        let flags = guy.moderation_flags.len();
+        let response = if stream {
+            guy.completion_stream(&connector, print_token).await.map(|_| println!())
+        } else {
+            let response = guy.completion(&mut connector).await;
+            response.map(|response| println!("{}", response.choices[0].message.content))
+        };
+        result = report_moderation(&mut guy, response, flags);
+        if result.is_ok() {
+            report_compactions(&guy, compactions);
+        }
+        warn_budget(&guy);
     }
-    let _ = handle.store_guy(guy.clone());
+    // Persisted even on error: the usage of the requests already billed counts in the budget.
     if let Err(e) = handle.store_guy(guy.clone()) {
         print_error!("Failed to persist guy's changes: {:?}", e)
     }
-    Ok(())
+    result
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn ask_interactive(
     mut connector: OpenAIConnector,
     handle: GuyHandle,
+    pricing: PricingTable,
+    parameters: ChatCompletionParameters,
     role: ChatCompletionRole,
-    message: Option<String>,
+    message: Option<MessageContent>,
+    transcript: Option<String>,
+    stream: bool,
 ) -> IaResult<()> {
     let mut guy = handle.get_guy()?;
-    let mut request: Option<(String, ChatCompletionRole)> = if let Some(message) = message {
-        Some((message, role))
-    } else {
-        None
+    guy.pricing = pricing;
+    guy.parameters.merge(parameters);
+    attach_knowledge(&mut guy, &handle, &connector)?;
+    attach_guard(&mut guy, &connector);
+    register_prompt_handlers(&mut guy);
+    let mut request: Option<(MessageContent, ChatCompletionRole)> = match (transcript, message) {
+        (Some(transcript), Some(message)) => {
+            guy.push_message(transcript, ChatCompletionRole::User);
+            Some((message, role))
+        }
+        (Some(transcript), None) => Some((transcript.into(), ChatCompletionRole::User)),
+        (None, Some(message)) => Some((message, role)),
+        (None, None) => None,
     };
 
     for (idx, message) in guy.history.iter().enumerate() {
@@ -61,11 +123,11 @@ async fn ask_interactive(
     loop {
         if let Some((message, role)) = request.take() {
             guy.push_message(message, role);
-            let response: ChatCompletionResponse = guy.completion(&mut connector).await?;
-            print_message(&response.choices[0].message, guy.history.len() - 1);
+            let result = interactive_completion(&mut guy, &mut connector, stream).await;
             if let Err(e) = handle.store_guy(guy.clone()) {
                 print_error!("Failed to persist guy's changes: {:?}", e)
             }
+            result?;
         }
         
         let input = Text::new("");
@@ -102,8 +164,13 @@ async fn ask_interactive(
                         return Ok(());
                     }
                     Some("\\completion") | Some("\\c") => {
-                        let response: ChatCompletionResponse = guy.completion(&mut connector).await?;
-                        print_message(&response.choices[0].message, guy.history.len() - 1);
+                        let result = interactive_completion(&mut guy, &mut connector, stream).await;
+                        if result.is_err() {
+                            if let Err(e) = handle.store_guy(guy.clone()) {
+                                print_error!("Failed to persist guy's changes: {:?}", e)
+                            }
+                        }
+                        result?;
                     }
                     Some("\\history") | Some("\\h") => {
                         for (idx, message) in guy.history.iter().enumerate() {
@@ -124,26 +191,259 @@ async fn ask_interactive(
                 }
             }
             _ => {
-                request = Some((input.to_string(), ChatCompletionRole::User));
+                request = Some((input.into(), ChatCompletionRole::User));
+            }
+        }
+    }
+}
+
+async fn interactive_completion(
+    guy: &mut Guy,
+    connector: &mut OpenAIConnector,
+    stream: bool,
+) -> IaResult<()> {
+    if warn_context_overflow(guy, connector) {
+        return Ok(());
+    }
+    let compactions = guy.compactions.len();
+    let flags = guy.moderation_flags.len();
+    let result = if stream {
+        print_header(&ChatCompletionRole::Assistant, guy.history.len());
+        let response = guy.completion_stream(connector, print_token).await;
+        print!("\n\n");
+        response.map(|_| ())
+    } else {
+        let response = guy.completion(connector).await;
+        response.map(|response| print_message(&response.choices[0].message, guy.history.len() - 1))
+    };
+    let result = report_moderation(guy, result, flags);
+    if result.is_ok() {
+        report_compactions(guy, compactions);
+    }
+    warn_budget(guy);
+    result
+}
+
+/// Let the guy retrieve passages from its indexed knowledge (if any).
+fn attach_knowledge(guy: &mut Guy, handle: &GuyHandle, connector: &OpenAIConnector) -> IaResult<()> {
+    if guy.knowledge.is_none() {
+        return Ok(());
+    }
+    let index = handle.knowledge_index()?;
+    if index.is_empty() {
+        print_warning!("The guy's knowledge is not indexed, run `ia guy index` first");
+        return Ok(());
+    }
+    guy.knowledge_base = Some(KnowledgeBase::new(index, connector.clone()));
+    Ok(())
+}
+
+/// Moderate the guy's messages when it has a guard.
+fn attach_guard(guy: &mut Guy, connector: &OpenAIConnector) {
+    if guy.guard.is_some() {
+        guy.moderator = Some(Moderator::new(connector.clone()));
+    }
+}
+
+/// Warn about the messages flagged since `before`, a blocked user message is removed from the
+/// history. Errors other than moderation ones are returned.
+fn report_moderation(guy: &mut Guy, result: Result<(), guy::error::GuyError>, before: usize) -> IaResult<()> {
+    for flag in guy.moderation_flags.iter().skip(before) {
+        print_warning!("{:?} message flagged by moderation ({}): {:?}", flag.role, flag.categories.join(", "), flag.policy);
+    }
+    match result {
+        Err(guy::error::GuyError::ModerationBlocked(flag)) => {
+            print_warning!("{:?} message blocked by moderation ({})", flag.role, flag.categories.join(", "));
+            if let (ChatCompletionRole::User, Some(index)) = (&flag.role, flag.index) {
+                guy.history.remove(index);
             }
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Warn (and return `true`) when the guy's prompt does not fit in the model's context window
+/// and will not be compacted.
+fn warn_context_overflow(guy: &Guy, connector: &OpenAIConnector) -> bool {
+    if guy.compaction.is_some() {
+        return false;
+    }
+    let context = guy.context_usage(connector);
+    match context.context_window {
+        Some(context_window) if context.overflows() => {
+            print_warning!(
+                "The prompt does not fit in the context window ({} tokens for a {} tokens window), remove some messages first",
+                context.tokens,
+                context_window
+            );
+            true
         }
+        _ => false,
+    }
+}
+
+fn report_compactions(guy: &Guy, before: usize) {
+    for record in guy.compactions.iter().skip(before) {
+        print_warning!("History compacted: {} messages archived", record.messages.len());
     }
 }
 
+fn warn_budget(guy: &Guy) {
+    if let (BudgetStatus::SoftExceeded, Some(budget)) = (guy.budget_status(), guy.budget.soft) {
+        print_warning!("Soft budget exceeded: ${:.4} spent of ${:.4}", guy.usage.total_cost(), budget);
+    }
+}
+
+/// Let the user answer the function calls which have no rust handler.
+fn register_prompt_handlers(guy: &mut Guy) {
+    for name in unhandled_functions(guy) {
+        let prompt_name = name.clone();
+        guy.register_function(name, move |arguments| {
+            let name = prompt_name.clone();
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    Text::new(&format!("{}({}) =", name.yellow(), arguments))
+                        .with_help_message("Function result sent back to the guy")
+                        .prompt()
+                })
+                .await
+                .map_err(|e| guy::error::GuyError::Function(e.to_string()))?
+                .map(serde_json::Value::String)
+                .map_err(|e| guy::error::GuyError::Function(e.to_string()))
+            }
+        });
+    }
+}
+
+/// Without a user to answer them, the guy's functions send an error back to the model.
+fn register_unavailable_handlers(guy: &mut Guy) {
+    for name in unhandled_functions(guy) {
+        let error = format!("`{}` cannot be called in non-interactive mode", name);
+        guy.register_function(name, move |_| {
+            let error = error.clone();
+            async move { Ok(serde_json::json!({ "error": error })) }
+        });
+    }
+}
+
+/// The guy's functions without a registered handler.
+fn unhandled_functions(guy: &Guy) -> Vec<String> {
+    guy.functions
+        .iter()
+        .map(|function| function.name.clone())
+        .filter(|name| !guy.handlers.contains(name))
+        .collect()
+}
+
 use colored::Colorize;
+use std::io::Write;
 
-fn print_message(message: &ChatCompletionMessage, index: usize) {
-    let role_colored = match message.role {
-        ChatCompletionRole::User => format!("{:?}", message.role).green(),
-        ChatCompletionRole::System => format!("{:?}", message.role).magenta(),
-        ChatCompletionRole::Assistant => format!("{:?}", message.role).blue(),
-        ChatCompletionRole::Function => format!("{:?}", message.role).yellow(),
+fn print_token(token: &str) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(token.as_bytes());
+    let _ = stdout.flush();
+}
+
+fn print_header(role: &ChatCompletionRole, index: usize) {
+    let role_colored = match role {
+        ChatCompletionRole::User => format!("{:?}", role).green(),
+        ChatCompletionRole::System => format!("{:?}", role).magenta(),
+        ChatCompletionRole::Assistant => format!("{:?}", role).blue(),
+        ChatCompletionRole::Function => format!("{:?}", role).yellow(),
     };
     println!(
         "{} ({})",
         role_colored,
         index,
     );
-    termimad::print_text(&message.content);
+}
+
+fn print_message(message: &ChatCompletionMessage, index: usize) {
+    print_header(&message.role, index);
+    if let Some(name) = &message.name {
+        println!("{}", name.yellow());
+    }
+    termimad::print_text(&message.content.text());
+    for image in message.content.images() {
+        match image.is_inline() {
+            true => println!("{}", "[inline image]".yellow()),
+            false => println!("{}", format!("[image: {}]", image.url).yellow()),
+        }
+    }
+    if let Some(call) = &message.function_call {
+        println!("{}({})", call.name.yellow(), call.arguments);
+    }
     print!("\n");    
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+    fn completion(message: serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "gpt-4",
+            "choices": [{"index": 0, "message": message, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_non_interactive_function_call() {
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .respond_with(completion(serde_json::json!({
+                "role": "assistant",
+                "content": null,
+                "function_call": {"name": "check_availability", "arguments": "{\"people\": 2}"}
+            })))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::body_string_contains("non-interactive mode"))
+            .respond_with(completion(serde_json::json!({"role": "assistant", "content": "I cannot check it."})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let directory = TempDir::new().unwrap();
+        let store = Store::create(&directory.path().join("store")).unwrap();
+        let handle = store.get_guy_handle("code_doc").await.unwrap();
+        crate::commands::apply::apply(handle.clone(), false, Some("../../data/guys/code_doc.yaml"), false)
+            .await
+            .unwrap();
+        let profile = ChatGptProfile {
+            api_endpoint: format!("{}/v1/chat/completions", server.uri()),
+            api_key_name: None,
+            ..Default::default()
+        };
+        let connector = OpenAIConnector::from_profile(&KeyChain::new(), profile).unwrap();
+
+        ask_non_interactive(
+            connector,
+            handle.clone(),
+            PricingTable::default(),
+            ChatCompletionParameters::default(),
+            ChatCompletionRole::User,
+            Some(MessageContent::from("Is a room free next week?".to_string())),
+            None,
+            true,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let guy = handle.get_guy().unwrap();
+        let result = &guy.history[guy.history.len() - 2];
+        assert_eq!(result.role, ChatCompletionRole::Function);
+        assert!(result.content.text().contains("non-interactive mode"));
+        assert_eq!(guy.history.last().unwrap().content.text(), "I cannot check it.");
+    }
+}
@@ -0,0 +1,37 @@
+use crate::prelude::*;
+
+/// Split the guy's knowledge sources into passages and (re)build their index.
+pub async fn index(
+    handle: GuyHandle,
+    profile: ChatGptProfile,
+    pricing: PricingTable,
+    request_log: Option<PathBuf>,
+) -> IaResult<()> {
+    let mut guy = handle.get_guy()?;
+    let Some(knowledge) = guy.knowledge.clone() else {
+        return Err(IaError::Message(
+            "The guy has no knowledge, add a `knowledge` section to its template".to_string(),
+        ));
+    };
+    let connector = super::openai_connector(profile, request_log.as_deref())?;
+
+    let base = KnowledgeBase::new(handle.knowledge_index()?, connector);
+    let report = base.rebuild(&knowledge).await?;
+    for path in report.skipped.iter() {
+        print_warning!("Skipped {:?} (not UTF-8 text)", path);
+    }
+    if report.sources.is_empty() {
+        print_warning!("No file matches the guy's knowledge");
+    }
+    guy.pricing = pricing;
+    if report.chunks > 0 {
+        guy.usage.record(report.model, report.usage, &guy.pricing);
+    }
+    handle.store_guy(guy)?;
+    print_success!(
+        "{} passages indexed from {} files",
+        report.chunks,
+        report.sources.len()
+    );
+    Ok(())
+}
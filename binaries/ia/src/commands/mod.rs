@@ -1,2 +1,24 @@
 pub mod ask;
-pub mod apply;
\ No newline at end of file
+pub mod apply;
+pub mod index;
+pub mod usage;
+
+use crate::prelude::*;
+
+/// The OpenAI connector of the commands, replaying or recording a cassette when `API_CASSETTE`
+/// is set, logging its requests to `request_log` and caching the deterministic ones when
+/// `API_CACHE_DIR` is set.
+pub fn openai_connector(profile: ChatGptProfile, request_log: Option<&Path>) -> IaResult<OpenAIConnector> {
+    let keychain = KeyChain::load()?;
+    let mut connector = OpenAIConnector::from_profile(&keychain, profile)?;
+    if let Some(cassette) = Cassette::from_env()? {
+        connector = connector.with_cassette(cassette);
+    }
+    if let Some(path) = request_log {
+        connector = connector.with_middleware(JsonlLogger::open(path)?);
+    }
+    if let Some(cache) = ResponseCache::from_env()? {
+        connector = connector.with_middleware(cache);
+    }
+    Ok(connector)
+}
\ No newline at end of file
@@ -0,0 +1,67 @@
+use crate::prelude::*;
+use colored::Colorize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+/// Print the tokens and spend of `guy` (or of every guy) per guy, per model and per day.
+pub async fn usage(store: &Store, guy: Option<&str>) -> IaResult<()> {
+    let names = store.guy_names()?;
+    let names = match guy {
+        Some(guy) if !names.iter().any(|name| name == guy) => {
+            return Err(IaError::Message(format!("Guy `{}` does not exist", guy)));
+        }
+        Some(guy) => vec![guy.to_string()],
+        None => names,
+    };
+
+    let mut per_guy = BTreeMap::new();
+    let mut per_model = BTreeMap::<String, UsageSummary>::new();
+    let mut per_day = BTreeMap::<String, UsageSummary>::new();
+    let mut total = UsageSummary::default();
+    for name in names {
+        let guy = store.get_guy_handle(&name).await?.get_guy()?;
+        for record in guy.usage.records() {
+            per_model.entry(record.model.clone()).or_default().push(record);
+            per_day
+                .entry(record.date.date_naive().to_string())
+                .or_default()
+                .push(record);
+            total.push(record);
+        }
+        if let BudgetStatus::SoftExceeded | BudgetStatus::HardExceeded = guy.budget_status() {
+            print_warning!("Guy `{}` is over budget", name);
+        }
+        per_guy.insert(name, guy.usage.total());
+    }
+
+    print_section("Guy", &per_guy);
+    print_section("Model", &per_model);
+    print_section("Day", &per_day);
+    println!("{}", "Total".bold());
+    print_summary("", &total);
+    Ok(())
+}
+
+fn print_section<K: Display>(title: &str, summaries: &BTreeMap<K, UsageSummary>) {
+    println!("{}", title.bold());
+    for (key, summary) in summaries.iter() {
+        print_summary(key, summary);
+    }
+    println!();
+}
+
+fn print_summary(key: impl Display, summary: &UsageSummary) {
+    let unpriced = match summary.unpriced_calls {
+        0 => String::new(),
+        calls => format!(" ({} unpriced)", calls).yellow().to_string(),
+    };
+    println!(
+        "  {:<24} {:>6} calls {:>10} prompt {:>10} completion {:>10}{}",
+        key.to_string(),
+        summary.calls,
+        summary.usage.prompt_tokens,
+        summary.usage.completion_tokens,
+        format!("${:.4}", summary.cost),
+        unpriced,
+    );
+}
@@ -22,6 +22,16 @@ pub enum IaError {
     NotAlive,
     #[error("Yaml: {}", _0)]
     Yaml(#[from] serde_yaml::Error),
+    #[error("Json: {}", _0)]
+    Json(#[from] serde_json::Error),
+    #[error("Vector index: {}", _0)]
+    Index(#[from] vector_index::IndexError),
+    #[error("Keychain: {}", _0)]
+    Key(#[from] api_connector::keyring::KeyError),
+    #[error("Cassette: {}", _0)]
+    Cassette(#[from] api_connector::cassette::CassetteError),
+    #[error("Middleware: {}", _0)]
+    Middleware(#[from] api_connector::middleware::MiddlewareError),
 }
 
 pub type IaResult<T> = std::result::Result<T, IaError>;
@@ -38,6 +38,27 @@ macro_rules! print_success {
 struct Cli {
     #[arg(short, long, help = "The store's path", default_value = ".iarc")]
     store: PathBuf,
+    #[arg(
+        short,
+        long,
+        env = "IA_CHAT_PROFILE",
+        help = "The chat profile to use (YAML file)",
+        long_help = "A YAML chat profile used to target any OpenAI compatible server:\n  api_endpoint: http://localhost:8080/v1/chat/completions\n  model: mistral-7b-instruct\n  api_key_name: null"
+    )]
+    profile: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "IA_PRICING",
+        help = "Model prices overriding the default ones (YAML file)",
+        long_help = "A YAML map of model prices in USD per 1K tokens, models are matched by prefix:\n  gpt-4:\n    prompt: 0.03\n    completion: 0.06"
+    )]
+    pricing: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "IA_REQUEST_LOG",
+        help = "Append every API request to this file (JSON lines)"
+    )]
+    request_log: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -53,6 +74,11 @@ enum Commands {
         #[command(subcommand)]
         command: GuysCommands,
     },
+    #[command(about = "Report tokens and spend per guy, per model and per day")]
+    Usage {
+        #[arg(short, long, help = "Only report this guy's usage")]
+        name: Option<String>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -89,6 +115,10 @@ pub enum GuysCommands {
         #[arg(short, long, help = "The output format")]
         output: Option<GuyGetOutputFormat>,
     },
+    #[command(about = "Print the messages removed from the guy's history by compactions")]
+    Archive {},
+    #[command(about = "Index the guy's knowledge (the files of its template's `knowledge` section)")]
+    Index {},
     #[command(about = "Perform a chat completion with a guy`")]
     Ask {
         #[arg(
@@ -106,6 +136,31 @@ pub enum GuysCommands {
             help = "The message to be appended to history"
         )]
         message: Option<String>,
+        #[arg(
+            long,
+            long_help = "An audio file (mp3, wav, m4a...) transcribed and appended to the guy's history as a user message, before the message if any.",
+            help = "Append the transcript of an audio file to history"
+        )]
+        audio: Option<PathBuf>,
+        #[arg(
+            long,
+            requires = "audio",
+            help = "The language of the audio (ISO-639-1), detected when not provided"
+        )]
+        language: Option<String>,
+        #[arg(
+            long,
+            long_help = "An image file (png, jpg, webp or gif) attached to the message, can be repeated. The model must support vision.",
+            help = "Attach an image to the message"
+        )]
+        image: Vec<PathBuf>,
+        #[arg(
+            long,
+            value_enum,
+            requires = "image",
+            help = "The resolution the model sees the images at"
+        )]
+        detail: Option<GuyAskImageDetail>,
         #[arg(
             short,
             long,
@@ -123,15 +178,63 @@ pub enum GuysCommands {
             conflicts_with = "interactive"
         )]
         completion: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Wait for the whole response instead of printing tokens as they arrive"
+        )]
+        no_stream: bool,
+        #[command(flatten)]
+        parameters: Box<CompletionParameters>,
     },
 }
 
+/// Completion options saved in the guy when provided.
+#[derive(clap::Args)]
+pub struct CompletionParameters {
+    #[arg(long, help = "The model used by the guy")]
+    model: Option<String>,
+    #[arg(long, help = "Sampling temperature (0 to 2)")]
+    temperature: Option<f64>,
+    #[arg(long, help = "Nucleus sampling probability mass (0 to 1)")]
+    top_p: Option<f64>,
+    #[arg(long, help = "Maximum number of tokens to generate")]
+    max_tokens: Option<u64>,
+    #[arg(long, help = "Penalize tokens already present (-2 to 2)")]
+    presence_penalty: Option<f64>,
+    #[arg(long, help = "Penalize frequent tokens (-2 to 2)")]
+    frequency_penalty: Option<f64>,
+    #[arg(long, value_parser = parse_logit_bias, help = "Bias a token's logit, `<token id>=<bias>` (repeatable)")]
+    logit_bias: Vec<(String, f64)>,
+    #[arg(long, help = "End-user identifier sent to the API")]
+    user: Option<String>,
+    #[arg(long, help = "Seed for (mostly) deterministic sampling")]
+    seed: Option<u64>,
+    #[arg(long, help = "Sequence where the generation stops (repeatable)")]
+    stop: Vec<String>,
+    #[arg(long, value_enum, help = "The output format")]
+    response_format: Option<ResponseFormat>,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 pub enum GuyGetOutputFormat {
     Yaml,
     Json,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum ResponseFormat {
+    Text,
+    Json,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum GuyAskImageDetail {
+    Auto,
+    Low,
+    High,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 pub enum GuyAskRole {
     System,
@@ -152,6 +255,14 @@ async fn main() {
 
 async fn hanlde_command(cli: Cli) -> Result<(), anyhow::Error> {
     let store_directory = cli.store;
+    let profile: ChatGptProfile = match &cli.profile {
+        Some(path) => serde_yaml::from_str(&tokio::fs::read_to_string(path).await?)?,
+        None => ChatGptProfile::default(),
+    };
+    let mut pricing = PricingTable::default();
+    if let Some(path) = &cli.pricing {
+        pricing.extend(PricingTable::from_yaml_file(path)?);
+    }
 
     match &cli.command {
         Commands::Init {} => {
@@ -164,6 +275,10 @@ async fn hanlde_command(cli: Cli) -> Result<(), anyhow::Error> {
             let _store = Store::create(&store_directory)?;
             println!("Store created in {:?}", store_directory);
         }
+        Commands::Usage { name } => {
+            let store = Store::open(&store_directory)?;
+            commands::usage::usage(&store, name.as_deref()).await?;
+        }
         Commands::Guy { name, command } => {
             let store = Store::open(&store_directory)?;
             let name = name.clone().unwrap_or_else(|| {
@@ -205,7 +320,8 @@ async fn hanlde_command(cli: Cli) -> Result<(), anyhow::Error> {
                         }
                     }
 
-                    let edited_guy = edited_guy.unwrap();
+                    let mut edited_guy = edited_guy.unwrap();
+                    edited_guy.usage = guy.usage.clone();
 
                     if edited_guy == guy {
                         print_warning!("No changes detected");
@@ -227,11 +343,25 @@ async fn hanlde_command(cli: Cli) -> Result<(), anyhow::Error> {
                         }
                     }
                 }
+                GuysCommands::Archive {} => {
+                    let handle = store.get_guy_handle(&name).await?;
+                    println!("{}", serde_yaml::to_string(&handle.archive()?)?);
+                }
+                GuysCommands::Index {} => {
+                    let handle = store.get_guy_handle(&name).await?;
+                    commands::index::index(handle, profile, pricing, cli.request_log.clone()).await?;
+                }
                 GuysCommands::Ask {
                     role,
                     message,
+                    audio,
+                    language,
+                    image,
+                    detail,
                     interactive,
                     completion,
+                    no_stream,
+                    parameters,
                 } => {
                     let handle = store.get_guy_handle(&name).await?;
                     let message = if let Some(message) = message {
@@ -247,8 +377,34 @@ async fn hanlde_command(cli: Cli) -> Result<(), anyhow::Error> {
                     } else {
                         None
                     };
-                    commands::ask::ask(handle, (*role).into(), message, *interactive, *completion)
-                        .await?;
+                    let audio = audio.clone().map(|path| {
+                        let options = TranscriptionOptions {
+                            language: language.clone(),
+                            ..Default::default()
+                        };
+                        (path, options)
+                    });
+                    let mut images = Vec::with_capacity(image.len());
+                    for path in image.iter() {
+                        let mut image = ImageUrl::from_file(path).await?;
+                        image.detail = detail.map(Into::into);
+                        images.push(image);
+                    }
+                    commands::ask::ask(
+                        handle,
+                        profile,
+                        pricing,
+                        cli.request_log.clone(),
+                        (*role).into(),
+                        message,
+                        audio,
+                        images,
+                        *interactive,
+                        *completion,
+                        !*no_stream,
+                        parameters.to_parameters(),
+                    )
+                    .await?;
                 }
             }
         }
@@ -276,6 +432,50 @@ async fn ask_for_editing(init: String, editor: Option<String>) -> Result<String,
     Ok(edited_content)
 }
 
+fn parse_logit_bias(value: &str) -> Result<(String, f64), String> {
+    let (token, bias) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<token id>=<bias>`, got `{}`", value))?;
+    let bias = bias.parse().map_err(|e| format!("invalid bias `{}`: {}", bias, e))?;
+    Ok((token.to_string(), bias))
+}
+
+impl CompletionParameters {
+    fn to_parameters(&self) -> ChatCompletionParameters {
+        ChatCompletionParameters {
+            model: self.model.clone(),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            n: None,
+            stop: match self.stop.len() {
+                0 => None,
+                1 => Some(self.stop[0].clone().into()),
+                _ => Some(self.stop.clone().into()),
+            },
+            max_tokens: self.max_tokens,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            logit_bias: (!self.logit_bias.is_empty()).then(|| self.logit_bias.iter().cloned().collect()),
+            user: self.user.clone(),
+            seed: self.seed,
+            response_format: self.response_format.map(|format| match format {
+                ResponseFormat::Text => ChatCompletionResponseFormat::Text,
+                ResponseFormat::Json => ChatCompletionResponseFormat::JsonObject,
+            }),
+        }
+    }
+}
+
+impl From<GuyAskImageDetail> for ImageDetail {
+    fn from(detail: GuyAskImageDetail) -> Self {
+        match detail {
+            GuyAskImageDetail::Auto => Self::Auto,
+            GuyAskImageDetail::Low => Self::Low,
+            GuyAskImageDetail::High => Self::High,
+        }
+    }
+}
+
 impl Into<ChatCompletionRole> for GuyAskRole {
     fn into(self) -> ChatCompletionRole {
         match self {
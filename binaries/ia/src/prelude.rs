@@ -5,9 +5,14 @@ pub use tempfile::{NamedTempFile, TempDir};
 pub use guy::prelude::*;
 pub use inquire::Text;
 pub use api_connector::{
+    audio::TranscriptionOptions,
+    cache::ResponseCache,
+    cassette::Cassette,
     keyring::KeyChain,
+    middleware::JsonlLogger,
     openai::*,
 };
+pub use vector_index::VectorIndex;
 
 
 pub use crate::error::*;
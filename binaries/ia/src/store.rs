@@ -1,6 +1,11 @@
 use crate::prelude::*;
 
 pub const TREE_SETTINGS: &str = "____settings";
+const ARCHIVE_PREFIX: &str = "archive/";
+/// Trees holding the guys' knowledge indexes, suffixed by the guy's name.
+const KNOWLEDGE_TREE_PREFIX: &str = "____knowledge/";
+/// Trees of the database which are not guys.
+const RESERVED_TREES: [&str; 2] = [TREE_SETTINGS, "__sled__default"];
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub struct Store {
@@ -12,6 +17,7 @@ pub struct Store {
 pub struct GuyHandle {
     alive: Arc<AtomicBool>,
     tree: sled::Tree,
+    knowledge: sled::Tree,
     guy: Arc<RwLock<Guy>>,
 }
 
@@ -64,13 +70,27 @@ impl Store {
             hash_map::Entry::Occupied(e) => Ok(e.get().clone()),
             hash_map::Entry::Vacant(e) => {
                 let tree = self.db.open_tree(name)?;
-                let handle = GuyHandle::load_or_create(tree).await?;
+                let knowledge = self.db.open_tree(knowledge_tree_name(name))?;
+                let handle = GuyHandle::load_or_create(tree, knowledge).await?;
                 e.insert(handle.clone());
                 Ok(handle)
             }
         }
     }
 
+    /// Names of the guys in the store.
+    pub fn guy_names(&self) -> IaResult<Vec<String>> {
+        let mut names = Vec::new();
+        for name in self.db.tree_names() {
+            let name = String::from_utf8(name.to_vec())?;
+            if !RESERVED_TREES.contains(&name.as_str()) && !name.starts_with(KNOWLEDGE_TREE_PREFIX) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
     pub async fn delete_guy(&self, name: &str) -> IaResult<Guy> {
         let mut opened_guys = self
             .opened_guys
@@ -82,23 +102,28 @@ impl Store {
         handle.alive.store(false, std::sync::atomic::Ordering::SeqCst);
         let guy = handle.get_guy()?;
         self.db.drop_tree(name)?;
+        self.db.drop_tree(knowledge_tree_name(name))?;
         Ok(guy)
     }
 }
 
 impl GuyHandle {
-    pub async fn load_or_create(tree: sled::Tree) -> IaResult<Self> {
-        let guy: Guy = if let Some(bytes) = tree.get("template")? {
-            bincode::deserialize(&bytes).unwrap()
+    pub async fn load_or_create(tree: sled::Tree, knowledge: sled::Tree) -> IaResult<Self> {
+        let mut guy: Guy = if let Some(bytes) = tree.get("template")? {
+            decode_guy(&bytes)?
         } else {
             Guy::new()
         };
+        if let Some(bytes) = tree.get("usage")? {
+            guy.usage = serde_json::from_slice(&bytes)?;
+        }
 
-        let guy_encoded: Vec<u8> = bincode::serialize(&guy)?;
+        let guy_encoded: Vec<u8> = serde_json::to_vec(&guy)?;
         tree.insert("template", &guy_encoded[..])?;
         Ok(Self {
             alive: Arc::new(AtomicBool::new(true)),
             tree,
+            knowledge,
             guy: Arc::new(RwLock::new(guy)),
         })
     }
@@ -114,8 +139,14 @@ impl GuyHandle {
 
     pub fn store_guy(&self, guy: Guy) -> IaResult<()> {
         if self.alive.load(std::sync::atomic::Ordering::SeqCst) {
-            let guy_encoded: Vec<u8> = bincode::serialize(&guy)?;
+            let guy_encoded: Vec<u8> = serde_json::to_vec(&guy)?;
             self.tree.insert("template", &guy_encoded[..])?;
+            let usage_encoded: Vec<u8> = serde_json::to_vec(&guy.usage)?;
+            self.tree.insert("usage", &usage_encoded[..])?;
+            for record in guy.compactions.iter() {
+                let record_encoded: Vec<u8> = serde_json::to_vec(record)?;
+                self.tree.insert(archive_key(record), &record_encoded[..])?;
+            }
             let mut guy_lock = self.guy.write().unwrap();
             let guy_lock_ref: &mut Guy = &mut guy_lock;
             let _ = std::mem::replace(guy_lock_ref, guy);
@@ -125,3 +156,118 @@ impl GuyHandle {
         }
     }
 }
+
+impl GuyHandle {
+    /// The messages removed from the guy's history by compactions, oldest first.
+    pub fn archive(&self) -> IaResult<Vec<CompactionRecord>> {
+        self.tree
+            .scan_prefix(ARCHIVE_PREFIX)
+            .values()
+            .map(|bytes| Ok(serde_json::from_slice(&bytes?)?))
+            .collect()
+    }
+}
+
+impl GuyHandle {
+    /// The index of the guy's knowledge passages, see `ia guy index`.
+    pub fn knowledge_index(&self) -> IaResult<VectorIndex> {
+        Ok(VectorIndex::open(self.knowledge.clone(), KNOWLEDGE_METRIC)?)
+    }
+}
+
+fn knowledge_tree_name(name: &str) -> String {
+    format!("{}{}", KNOWLEDGE_TREE_PREFIX, name)
+}
+
+/// Records are keyed by date so storing the same guy again does not duplicate them.
+fn archive_key(record: &CompactionRecord) -> String {
+    format!("{}{:020}", ARCHIVE_PREFIX, record.date.timestamp_nanos_opt().unwrap_or_default())
+}
+
+/// Guys are stored as JSON so new (defaulted) fields do not invalidate existing stores,
+/// guys written by older versions (bincode) are still readable.
+fn decode_guy(bytes: &[u8]) -> IaResult<Guy> {
+    match serde_json::from_slice(bytes) {
+        Ok(guy) => Ok(guy),
+        Err(json_error) => bincode::deserialize::<legacy::Guy>(bytes)
+            .map(Into::into)
+            .map_err(|_| json_error.into()),
+    }
+}
+
+mod legacy {
+    use crate::prelude::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Guy {
+        description: Option<String>,
+        history: Vec<Message>,
+        functions: Vec<Function>,
+    }
+
+    #[derive(Deserialize)]
+    struct Message {
+        role: ChatCompletionRole,
+        content: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Function {
+        name: String,
+        description: String,
+        parameters: Parameters,
+        required: Vec<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Parameters {
+        _kind: String,
+        properties: HashMap<String, Property>,
+    }
+
+    #[derive(Deserialize)]
+    struct Property {
+        kind: String,
+        description: String,
+    }
+
+    impl Property {
+        fn into_property(self) -> Option<ChatCompletionFunctionProperty> {
+            let kind = serde_json::from_value(serde_json::Value::String(self.kind)).ok()?;
+            Some(ChatCompletionFunctionProperty {
+                description: Some(self.description),
+                ..ChatCompletionFunctionProperty::new(kind)
+            })
+        }
+    }
+
+    impl From<Guy> for guy::Guy {
+        fn from(legacy: Guy) -> Self {
+            let mut guy = guy::Guy::new();
+            guy.description = legacy.description;
+            for message in legacy.history {
+                guy.push_message(message.content, message.role);
+            }
+            guy.functions = legacy
+                .functions
+                .into_iter()
+                .map(|function| ChatCompletionFunction {
+                    name: function.name,
+                    description: function.description,
+                    parameters: ChatCompletionFunctionParameters {
+                        kind: JsonSchemaType::Object,
+                        properties: function
+                            .parameters
+                            .properties
+                            .into_iter()
+                            .filter_map(|(name, property)| Some((name, property.into_property()?)))
+                            .collect(),
+                        required: function.required,
+                    },
+                })
+                .collect();
+            guy
+        }
+    }
+}
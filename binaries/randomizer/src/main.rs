@@ -1,29 +1,141 @@
+use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::Arc;
+use api_connector::automatic1111::*;
+use api_connector::image_generator::*;
 use api_connector::stable_diffusion::*;
+use api_connector::text_to_image::TextToImageProfile;
 use api_connector::openai::*;
 use api_connector::keyring::*;
-use clap::Parser;
+use api_connector::cache::ResponseCache;
+use api_connector::cassette::Cassette;
+use api_connector::webhook::WebhookListener;
+use clap::{ArgEnum, Parser};
 use prompt_generator::PromptGenerator;
 
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum Backend {
+    /// The hosted stablediffusionapi.com API
+    StableDiffusionApi,
+    /// A self-hosted Automatic1111 server started with `--api`
+    Automatic1111,
+}
+
 #[derive(Parser, Debug)]
 struct Args {
     data_path: String,
     template_path: String,
+    #[clap(short, long, default_value = ".", help = "Where the images are saved")]
+    output: String,
+    #[clap(short, long, help = "The generation profile (YAML file, see data/profiles/stable_diffusion)")]
+    profile: Option<String>,
+    #[clap(short, long, arg_enum, env = "IMAGE_BACKEND", default_value = "stable-diffusion-api", help = "The image generation backend")]
+    backend: Backend,
+    #[clap(short, long, env = "IMAGE_ENDPOINT", help = "The txt2img endpoint of the automatic1111 backend")]
+    endpoint: Option<String>,
+    #[clap(long, env = "WEBHOOK_ADDRESS", help = "Receive the stable-diffusion-api results on this address instead of polling")]
+    webhook_address: Option<SocketAddr>,
+    #[clap(long, env = "WEBHOOK_URL", requires = "webhook-address", help = "The public URL of the webhook listener")]
+    webhook_url: Option<String>,
+    #[clap(long, env = "IMAGE_SEED", help = "A fixed seed, the results are cached when API_CACHE_DIR is set")]
+    seed: Option<u64>,
+    #[clap(long, help = "Ask a vision model to review the generated images with this prompt")]
+    review: Option<String>,
+    #[clap(long, env = "REVIEW_MODEL", default_value = "gpt-4o", help = "The model reviewing the images")]
+    review_model: String,
 }
 
 static NEGATIVE_PROMPT: &str = "(title), (text), ((((underage)))), ((((child)))), (((kid))), (((preteen))), ((((frame)))), ((((border)))), (((((background))))), ((tiling)), poorly drawn hands, poorly drawn feet, poorly drawn face, out of frame, extra limbs, deformed, body out of frame, bad anatomy, watermark, signature, cut off, low contrast, underexposed, overexposed, bad art, beginner, amateur, distorted face, blurry, draft, grainy";
 
+async fn run<G: ImageGenerator>(generator: &G, request: &ImageRequest, output: &Path) -> Vec<Image> {
+    let images = generator.generate(request).await.unwrap();
+    for path in save_images(&images, output, "randomizer").await.unwrap() {
+        println!("{}", path.display());
+    }
+    images
+}
+
+async fn review(keys: &KeyChain, model: &str, prompt: &str, images: &[Image]) {
+    let connector = OpenAIConnector::new(keys).unwrap();
+    let mut content = MessageContent::from(prompt);
+    images.iter().for_each(|image| content.push_image(ImageUrl::from_image(image)));
+    let messages = [ChatCompletionMessage::new(ChatCompletionRole::User, content)];
+    let request = ChatCompletionRequest {
+        messages: &messages,
+        parameters: ChatCompletionParameters {
+            model: Some(model.to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let response = connector.chat_completion_request(request).await.unwrap();
+    println!("{}", response.choices[0].message.content);
+}
+
 #[tokio::main]
 async fn main() {
     let _ = dotenv::dotenv().ok();
     
-    let keys = KeyChain::from_env();
+    let keys = KeyChain::load().unwrap();
 
     let args = Args::parse();
     let generator = PromptGenerator::new(Path::new(&args.template_path), Path::new(&args.data_path)).unwrap();
 
     let prompt = generator.randomize();
     println!("{}", &prompt.render);
-    let response = StableDiffusionConnector::new(&keys).generate_image(prompt.render, Some(String::from(NEGATIVE_PROMPT)), None).await.unwrap();
-    dbg!(response);
+    let profile = args.profile.as_ref().map(|path| {
+        let profile: TextToImageProfile = serde_yaml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        if let Err(e) = profile.validate() {
+            eprintln!("Invalid profile `{}`: {}", path, e);
+            std::process::exit(1);
+        }
+        profile
+    });
+    let cassette = Cassette::from_env().unwrap();
+    let cache = ResponseCache::from_env().unwrap();
+    let request = ImageRequest {
+        prompt: prompt.render,
+        negative_prompt: Some(String::from(NEGATIVE_PROMPT)),
+        seed: args.seed,
+    };
+    let output = Path::new(&args.output);
+    let images = match args.backend {
+        Backend::StableDiffusionApi => {
+            let mut connector = StableDiffusionConnector::new(&keys).unwrap();
+            if let Some(profile) = profile {
+                connector = connector.with_profile(profile);
+            }
+            if let Some(cassette) = cassette {
+                connector = connector.with_cassette(cassette);
+            }
+            if let Some(cache) = cache {
+                connector = connector.with_middleware(cache);
+            }
+            if let Some(address) = args.webhook_address {
+                let mut listener = WebhookListener::bind(address).unwrap();
+                if let Some(url) = args.webhook_url {
+                    listener = listener.with_public_url(url);
+                }
+                connector = connector.with_webhook(Arc::new(listener));
+            }
+            run(&connector, &request, output).await
+        }
+        Backend::Automatic1111 => {
+            let endpoint = args.endpoint.as_deref().unwrap_or(DEFAULT_AUTOMATIC1111_ENDPOINT);
+            let mut connector = Automatic1111Connector::new(endpoint);
+            if let Some(profile) = profile {
+                connector = connector.with_profile(profile);
+            }
+            if let Some(cassette) = cassette {
+                connector = connector.with_cassette(cassette);
+            }
+            if let Some(cache) = cache {
+                connector = connector.with_middleware(cache);
+            }
+            run(&connector, &request, output).await
+        }
+    };
+    if let Some(prompt) = &args.review {
+        review(&keys, &args.review_model, prompt, &images).await;
+    }
 }
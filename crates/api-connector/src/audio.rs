@@ -0,0 +1,151 @@
+use crate::prelude::*;
+use std::path::Path;
+
+/// Size limit of the files sent to the transcription endpoint.
+pub const MAX_AUDIO_FILE_SIZE: u64 = 25 * 1024 * 1024;
+pub const DEFAULT_VOICE: &str = "alloy";
+
+/// Format of a transcript.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TranscriptionFormat {
+    #[default]
+    Json,
+    /// JSON with the language, the duration and the segments.
+    VerboseJson,
+    Text,
+    Srt,
+    Vtt,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct TranscriptionOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// ISO-639-1 language of the audio, detected when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Text guiding the transcript's style (or the transcript of the previous segment).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub response_format: TranscriptionFormat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Transcription {
+    /// The transcript, or the subtitles for the `srt` and `vtt` formats.
+    pub text: String,
+    /// Only returned with [`TranscriptionFormat::VerboseJson`].
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Seconds of audio, only returned with [`TranscriptionFormat::VerboseJson`].
+    #[serde(default)]
+    pub duration: Option<f64>,
+}
+
+/// Encoding of the generated speech.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeechFormat {
+    #[default]
+    Mp3,
+    Opus,
+    Aac,
+    Flac,
+    Wav,
+    /// Raw 24kHz 16-bit signed little-endian samples.
+    Pcm,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct SpeechOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// See [`DEFAULT_VOICE`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<String>,
+    /// Follows the extension of the written file when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<SpeechFormat>,
+    /// From `0.25` to `4.0`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SpeechRequest<'a> {
+    pub model: &'a str,
+    pub input: &'a str,
+    pub voice: &'a str,
+    pub response_format: SpeechFormat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+}
+
+/// The form fields of a transcription request (but the file), also used as cassette key.
+#[derive(Debug, Serialize)]
+pub struct TranscriptionRequest<'a> {
+    pub file: &'a str,
+    pub model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<&'a str>,
+    pub response_format: TranscriptionFormat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+}
+
+impl TranscriptionFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::VerboseJson => "verbose_json",
+            Self::Text => "text",
+            Self::Srt => "srt",
+            Self::Vtt => "vtt",
+        }
+    }
+
+    /// Whether the transcript is returned as JSON rather than as plain text.
+    pub fn is_json(&self) -> bool {
+        matches!(self, Self::Json | Self::VerboseJson)
+    }
+}
+
+impl SpeechFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "mp3" => Some(Self::Mp3),
+            "opus" | "ogg" => Some(Self::Opus),
+            "aac" => Some(Self::Aac),
+            "flac" => Some(Self::Flac),
+            "wav" => Some(Self::Wav),
+            "pcm" => Some(Self::Pcm),
+            _ => None,
+        }
+    }
+}
+
+impl TranscriptionRequest<'_> {
+    /// The text fields of the multipart form.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("model", self.model.to_string()),
+            ("response_format", self.response_format.as_str().to_string()),
+        ];
+        if let Some(language) = self.language {
+            fields.push(("language", language.to_string()));
+        }
+        if let Some(prompt) = self.prompt {
+            fields.push(("prompt", prompt.to_string()));
+        }
+        if let Some(temperature) = self.temperature {
+            fields.push(("temperature", temperature.to_string()));
+        }
+        fields
+    }
+}
@@ -0,0 +1,343 @@
+use crate::{
+    cassette::{Cassette, CassetteError},
+    image_generator::*,
+    middleware::{Middleware, MiddlewareError, Middlewares},
+    prelude::*,
+    retry::{RetryPolicy, Retryable},
+    text_to_image::*,
+};
+use base64::prelude::*;
+use reqwest::StatusCode;
+use std::time::Duration;
+use thiserror::Error;
+
+pub const DEFAULT_AUTOMATIC1111_ENDPOINT: &str = "http://127.0.0.1:7860/sdapi/v1/txt2img";
+
+#[derive(Debug, Error)]
+pub enum Automatic1111Error {
+    #[error("JSON error: {}", _0)]
+    JsonError(#[from] serde_json::Error),
+    #[error("reqwest error: {}", _0)]
+    HttpClientError(#[from] reqwest::Error),
+    #[error("cassette error: {}", _0)]
+    Cassette(#[from] CassetteError),
+    #[error("invalid base64 image: {}", _0)]
+    Base64(#[from] base64::DecodeError),
+    #[error("invalid profile: {}", _0)]
+    InvalidProfile(#[from] ProfileError),
+    #[error("request failed: {} (http status: {})", message, status)]
+    RequestFailed { message: String, status: StatusCode },
+    #[error("server error: {} (http status: {})", message, status)]
+    ServerError { message: String, status: StatusCode },
+    #[error("middleware error: {}", _0)]
+    Middleware(String),
+}
+
+impl From<MiddlewareError> for Automatic1111Error {
+    fn from(error: MiddlewareError) -> Self {
+        match error {
+            MiddlewareError::HttpClientError(e) => Self::HttpClientError(e),
+            MiddlewareError::Cassette(e) => Self::Cassette(e),
+            error => Self::Middleware(error.to_string()),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Automatic1111Error>;
+
+/// A self-hosted Automatic1111 (or compatible, e.g. SD.Next or a ComfyUI bridge) server started
+/// with `--api`, images are returned inline as base64.
+///
+/// The generation settings come from a [`TextToImageProfile`], its `api_endpoint` is ignored.
+pub struct Automatic1111Connector {
+    endpoint: String,
+    profile: TextToImageProfile,
+    client: Client,
+    credentials: Option<(String, String)>,
+    retry_policy: RetryPolicy,
+    cassette: Option<Cassette>,
+    middlewares: Middlewares,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Automatic1111Request {
+    pub prompt: String,
+    pub negative_prompt: String,
+    /// `-1` for a random seed.
+    pub seed: i64,
+    pub steps: u32,
+    pub cfg_scale: f64,
+    pub width: u32,
+    pub height: u32,
+    pub batch_size: u32,
+    pub n_iter: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampler_name: Option<Sampler>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduler: Option<&'static str>,
+    pub enable_hr: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hr_scale: Option<f64>,
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    pub override_settings: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Automatic1111Response {
+    /// Base64 encoded PNG images.
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub parameters: serde_json::Value,
+    /// JSON encoded generation details (seeds...).
+    #[serde(default)]
+    pub info: String,
+}
+
+impl Automatic1111Connector {
+    /// `endpoint` is the `txt2img` URL, see [`DEFAULT_AUTOMATIC1111_ENDPOINT`].
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            profile: TextToImageProfile::default(),
+            client: Client::new(),
+            credentials: None,
+            retry_policy: RetryPolicy::default(),
+            cassette: None,
+            middlewares: Middlewares::default(),
+        }
+    }
+
+    pub fn with_profile(mut self, profile: TextToImageProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Credentials of a server started with `--api-auth`.
+    pub fn with_basic_auth(mut self, username: String, password: String) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Record the exchanges to (or replay them from) `cassette`.
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Run the HTTP exchanges through `middleware`, after the ones already registered.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    pub fn profile(&self) -> &TextToImageProfile {
+        &self.profile
+    }
+
+    /// Check the profile, including the settings Automatic1111 has no equivalent for.
+    pub fn validate(&self) -> std::result::Result<(), ProfileError> {
+        let profile = &self.profile;
+        profile.validate()?;
+        let unsupported = [
+            ("safety_checker", profile.safety_checker),
+            ("enhance_prompt", profile.enhance_prompt),
+            ("multi_lingual", profile.multi_lingual),
+            ("panorama", profile.panorama),
+            ("self_attention", profile.self_attention),
+            ("scheduler", profile.sampler.is_none() && profile.scheduler.is_some_and(|scheduler| scheduler.sampler().is_none())),
+        ];
+        match unsupported.into_iter().find(|(_, enabled)| *enabled) {
+            Some((field, _)) => Err(ProfileError::Unsupported { field, backend: "Automatic1111" }),
+            None => Ok(()),
+        }
+    }
+
+    /// Map the profile to the request, LoRAs are referenced in the prompt (`<lora:name:strength>`)
+    /// as well as the textual inversion embeddings (by name). Without sampler, the one equivalent
+    /// to the scheduler is used.
+    pub fn request(&self, request: &ImageRequest) -> Automatic1111Request {
+        let profile = &self.profile;
+        let mut prompt = request.prompt.clone();
+        for lora in profile.loras.iter() {
+            prompt.push_str(&format!(" <lora:{}:{}>", lora.model, lora.strength));
+        }
+        for embedding in profile.embeddings.iter() {
+            prompt.push_str(&format!(" {}", embedding));
+        }
+        let mut override_settings = serde_json::Map::new();
+        if let Some(clip_skip) = profile.clip_skip {
+            override_settings.insert("CLIP_stop_at_last_layers".to_string(), clip_skip.into());
+        }
+        if let Some(model) = &profile.model_id {
+            override_settings.insert("sd_model_checkpoint".to_string(), model.clone().into());
+        }
+        if let Some(vae) = &profile.vae {
+            override_settings.insert("sd_vae".to_string(), vae.clone().into());
+        }
+        Automatic1111Request {
+            prompt,
+            negative_prompt: request.negative_prompt.clone().unwrap_or_default(),
+            seed: request.seed.map_or(-1, |seed| seed as i64),
+            steps: profile.num_inference_steps,
+            cfg_scale: profile.guidance_scale,
+            width: profile.width,
+            height: profile.height,
+            batch_size: profile.samples,
+            n_iter: 1,
+            sampler_name: profile.sampler.or_else(|| profile.scheduler.and_then(|scheduler| scheduler.sampler())),
+            scheduler: profile.use_karras_sigmas.then_some("Karras"),
+            enable_hr: profile.upscale,
+            hr_scale: profile.upscale.then_some(2.0),
+            override_settings,
+        }
+    }
+
+    /// Generate the images of `request`, decoded from the response.
+    pub async fn txt2img(&self, request: &ImageRequest) -> Result<Vec<Image>> {
+        self.validate()?;
+        let request = self.request(request);
+        let response: Automatic1111Response = self.send(&request).await?.json().await?;
+        response
+            .images
+            .iter()
+            .map(|image| {
+                // Some forks prefix the images with a data URI header.
+                let data = image.split_once("base64,").map_or(image.as_str(), |(_, data)| data);
+                Ok(Image::new(BASE64_STANDARD.decode(data)?, "image/png"))
+            })
+            .collect()
+    }
+
+    async fn send<T: Serialize>(&self, request: &T) -> Result<reqwest::Response> {
+        self.retry_policy
+            .run(|| async {
+                let mut builder = self.client.post(&self.endpoint).json(request);
+                if let Some((username, password)) = &self.credentials {
+                    builder = builder.basic_auth(username, Some(password));
+                }
+                let response = self
+                    .middlewares
+                    .send("automatic1111", &self.client, self.cassette.as_ref(), builder, request)
+                    .await?;
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(response);
+                }
+                let message = response.text().await.unwrap_or_default();
+                Err(match status.is_server_error() {
+                    true => Automatic1111Error::ServerError { message, status },
+                    false => Automatic1111Error::RequestFailed { message, status },
+                })
+            })
+            .await
+    }
+}
+
+impl ImageGenerator for Automatic1111Connector {
+    type Error = Automatic1111Error;
+
+    async fn generate<'a>(&'a self, request: &'a ImageRequest) -> Result<Vec<Image>> {
+        self.txt2img(request).await
+    }
+}
+
+impl Retryable for Automatic1111Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::ServerError { .. } => true,
+            Self::HttpClientError(e) => e.is_timeout() || e.is_connect(),
+            _ => false,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_txt2img() {
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/sdapi/v1/txt2img"))
+            .and(matchers::header_exists("authorization"))
+            .and(matchers::body_partial_json(serde_json::json!({
+                "prompt": "an elf <lora:arcane:0.5> elf-style",
+                "negative_prompt": "blurry",
+                "seed": -1,
+                "steps": 30,
+                "width": 512,
+                "height": 768,
+                "batch_size": 2,
+                "sampler_name": "Euler a",
+                "override_settings": {"CLIP_stop_at_last_layers": 2}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "images": [BASE64_STANDARD.encode(b"first"), format!("data:image/png;base64,{}", BASE64_STANDARD.encode(b"second"))],
+                "parameters": {},
+                "info": "{}"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let connector = Automatic1111Connector::new(format!("{}/sdapi/v1/txt2img", server.uri()))
+            .with_basic_auth("user".to_string(), "secret".to_string())
+            .with_profile(TextToImageProfile {
+                width: 512,
+                height: 768,
+                samples: 2,
+                scheduler: Some(Scheduler::EulerAncestralDiscrete),
+                clip_skip: Some(2),
+                loras: vec![Lora {
+                    model: "arcane".to_string(),
+                    strength: 0.5,
+                }],
+                embeddings: vec!["elf-style".to_string()],
+                ..Default::default()
+            });
+        let request = ImageRequest {
+            prompt: "an elf".to_string(),
+            negative_prompt: Some("blurry".to_string()),
+            seed: None,
+        };
+        let images = connector.generate(&request).await.unwrap();
+        assert_eq!(images, [Image::new(b"first".to_vec(), "image/png"), Image::new(b"second".to_vec(), "image/png")]);
+
+        let directory = tempfile::tempdir().unwrap();
+        let paths = save_images(&images, directory.path(), "elf").await.unwrap();
+        assert_eq!(paths[1], directory.path().join("elf-1.png"));
+        assert_eq!(std::fs::read(&paths[1]).unwrap(), b"second");
+
+        // The settings without equivalent are rejected before sending anything.
+        for profile in [
+            TextToImageProfile {
+                panorama: true,
+                ..Default::default()
+            },
+            TextToImageProfile {
+                scheduler: Some(Scheduler::Ddpm),
+                ..Default::default()
+            },
+        ] {
+            let connector = Automatic1111Connector::new(format!("{}/sdapi/v1/txt2img", server.uri())).with_profile(profile);
+            assert!(matches!(connector.generate(&request).await, Err(Automatic1111Error::InvalidProfile(ProfileError::Unsupported { .. }))));
+        }
+        let profile = TextToImageProfile {
+            scheduler: Some(Scheduler::Ddpm),
+            sampler: Some(Sampler::DpmPlusPlus2MKarras),
+            ..Default::default()
+        };
+        assert!(Automatic1111Connector::new(DEFAULT_AUTOMATIC1111_ENDPOINT).with_profile(profile).validate().is_ok());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::chat::{ChatCompletionRequest, ChatCompletionResponse};
+use crate::image_generator::{Image, ImageGenerator, ImageRequest};
+use crate::openai::{OpenAIConnector, OpenAIError};
+use crate::tokenizer::Tokenizer;
+use futures_util::StreamExt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A client-side token-bucket limiter: up to `capacity` units at once, refilled continuously.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    /// Units per second.
+    refill_rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+/// What a batch does once an item failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BatchPolicy {
+    /// Run every item.
+    #[default]
+    Continue,
+    /// Do not start the remaining items, they are reported as [`BatchResult::Skipped`].
+    StopOnError,
+}
+
+/// Runs a list of requests with bounded concurrency within requests-per-minute and
+/// tokens-per-minute budgets.
+#[derive(Debug)]
+pub struct BatchExecutor {
+    concurrency: usize,
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+    policy: BatchPolicy,
+}
+
+#[derive(Debug)]
+pub enum BatchResult<R, E> {
+    Done(R),
+    Failed(E),
+    /// Not started because an earlier item failed (see [`BatchPolicy::StopOnError`]).
+    Skipped,
+}
+
+/// The results of a batch, in the order of its items.
+#[derive(Debug)]
+pub struct BatchReport<R, E> {
+    pub results: Vec<BatchResult<R, E>>,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u64, refill_rate: f64) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_rate,
+            state: Mutex::new((capacity as f64, Instant::now())),
+        }
+    }
+
+    /// `limit` units per minute, all of them available at once.
+    pub fn per_minute(limit: u64) -> Self {
+        Self::new(limit, limit as f64 / 60.0)
+    }
+
+    /// Take `amount` units (at most the capacity), or return how long to wait before retrying.
+    pub fn try_acquire(&self, amount: u64) -> Result<(), Duration> {
+        let amount = (amount as f64).min(self.capacity);
+        let mut state = self.state.lock().unwrap();
+        let (available, refilled) = &mut *state;
+        let now = Instant::now();
+        *available = (*available + now.duration_since(*refilled).as_secs_f64() * self.refill_rate).min(self.capacity);
+        *refilled = now;
+        if *available >= amount {
+            *available -= amount;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((amount - *available) / self.refill_rate))
+        }
+    }
+
+    /// Wait until `amount` units are available and take them.
+    pub async fn acquire(&self, amount: u64) {
+        while let Err(delay) = self.try_acquire(amount) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+impl Default for BatchExecutor {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+impl BatchExecutor {
+    /// Run at most `concurrency` items at once.
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+            requests: None,
+            tokens: None,
+            policy: BatchPolicy::default(),
+        }
+    }
+
+    /// A `limit` of `0` means no budget.
+    pub fn with_requests_per_minute(mut self, limit: u64) -> Self {
+        self.requests = (limit > 0).then(|| TokenBucket::per_minute(limit));
+        self
+    }
+
+    /// Budget of the tokens reported by the `cost` of the items, none when `limit` is `0`.
+    pub fn with_tokens_per_minute(mut self, limit: u64) -> Self {
+        self.tokens = (limit > 0).then(|| TokenBucket::per_minute(limit));
+        self
+    }
+
+    pub fn with_policy(mut self, policy: BatchPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Run `job` on every item, `cost` estimates the tokens an item uses.
+    pub async fn run<T, R, E, C, F, Fut>(&self, items: Vec<T>, cost: C, job: F) -> BatchReport<R, E>
+    where
+        C: Fn(&T) -> u64,
+        F: Fn(T) -> Fut,
+        Fut: Future<Output = Result<R, E>>,
+    {
+        let stopped = AtomicBool::new(false);
+        let mut results = items.iter().map(|_| BatchResult::Skipped).collect::<Vec<_>>();
+        let mut finished = futures_util::stream::iter(items.into_iter().enumerate())
+            .map(|(index, item)| {
+                let tokens = cost(&item);
+                let (job, stopped) = (&job, &stopped);
+                async move {
+                    if let Some(requests) = &self.requests {
+                        requests.acquire(1).await;
+                    }
+                    if let Some(bucket) = &self.tokens {
+                        bucket.acquire(tokens).await;
+                    }
+                    if stopped.load(Ordering::Relaxed) {
+                        return (index, BatchResult::Skipped);
+                    }
+                    match job(item).await {
+                        Ok(result) => (index, BatchResult::Done(result)),
+                        Err(error) => {
+                            if self.policy == BatchPolicy::StopOnError {
+                                stopped.store(true, Ordering::Relaxed);
+                            }
+                            (index, BatchResult::Failed(error))
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(self.concurrency);
+        while let Some((index, result)) = finished.next().await {
+            results[index] = result;
+        }
+        BatchReport { results }
+    }
+
+    /// Complete every request, their cost is the prompt's tokens plus `max_tokens`.
+    pub async fn chat_completions(
+        &self,
+        connector: &OpenAIConnector,
+        requests: Vec<ChatCompletionRequest<'_>>,
+    ) -> BatchReport<ChatCompletionResponse, OpenAIError> {
+        let cost = |request: &ChatCompletionRequest<'_>| {
+            let model = request.parameters.model.as_deref().unwrap_or(&connector.profile().model);
+            let prompt = Tokenizer::for_model(model).count_request(request) as u64;
+            prompt + request.parameters.max_tokens.unwrap_or_default()
+        };
+        self.run(requests, cost, |request| connector.chat_completion_request(request)).await
+    }
+
+    /// Generate the images of every request, only the requests budget applies.
+    pub async fn generate_images<G: ImageGenerator>(
+        &self,
+        generator: &G,
+        requests: &[ImageRequest],
+    ) -> BatchReport<Vec<Image>, G::Error> {
+        self.run(requests.iter().collect(), |_| 0, |request| generator.generate(request)).await
+    }
+}
+
+impl<R, E> BatchResult<R, E> {
+    pub fn ok(&self) -> Option<&R> {
+        match self {
+            Self::Done(result) => Some(result),
+            _ => None,
+        }
+    }
+
+    pub fn err(&self) -> Option<&E> {
+        match self {
+            Self::Failed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl<R, E> BatchReport<R, E> {
+    /// The results of the succeeded items with their index.
+    pub fn successes(&self) -> impl Iterator<Item = (usize, &R)> {
+        self.results.iter().enumerate().filter_map(|(index, result)| Some((index, result.ok()?)))
+    }
+
+    /// The errors of the failed items with their index.
+    pub fn errors(&self) -> impl Iterator<Item = (usize, &E)> {
+        self.results.iter().enumerate().filter_map(|(index, result)| Some((index, result.err()?)))
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.results.iter().filter(|result| matches!(result, BatchResult::Skipped)).count()
+    }
+
+    /// Whether every item succeeded.
+    pub fn is_success(&self) -> bool {
+        self.results.iter().all(|result| matches!(result, BatchResult::Done(_)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_batch() {
+        let items = (0..10).collect::<Vec<u32>>();
+        let job = |item: u32| async move {
+            tokio::time::sleep(Duration::from_millis(10 - item as u64)).await;
+            match item % 4 {
+                3 => Err(format!("item {} failed", item)),
+                _ => Ok(item * 2),
+            }
+        };
+
+        let report = BatchExecutor::new(4).run(items.clone(), |_| 1, job).await;
+        let successes = report.successes().map(|(index, result)| (index, *result)).collect::<Vec<_>>();
+        assert_eq!(successes, [(0, 0), (1, 2), (2, 4), (4, 8), (5, 10), (6, 12), (8, 16), (9, 18)]);
+        assert_eq!(report.errors().map(|(index, _)| index).collect::<Vec<_>>(), [3, 7]);
+
+        let report = BatchExecutor::new(1)
+            .with_policy(BatchPolicy::StopOnError)
+            .run(items.clone(), |_| 1, job)
+            .await;
+        assert_eq!(report.successes().count(), 3);
+        assert_eq!(report.skipped(), 6);
+        assert!(!report.is_success());
+
+        // A zero limit is no budget.
+        let report = BatchExecutor::new(2)
+            .with_requests_per_minute(0)
+            .with_tokens_per_minute(0)
+            .run(items, |_| 100, job)
+            .await;
+        assert_eq!(report.successes().count(), 8);
+
+        // 60 tokens per minute: the bucket starts full then refills one token per second.
+        let bucket = TokenBucket::per_minute(60);
+        assert!(bucket.try_acquire(60).is_ok());
+        let delay = bucket.try_acquire(2).unwrap_err();
+        assert!(delay > Duration::from_millis(1900) && delay <= Duration::from_secs(2));
+    }
+}
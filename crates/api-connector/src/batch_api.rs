@@ -0,0 +1,174 @@
+use crate::chat::{ChatCompletionRequest, ChatCompletionResponse};
+use crate::prelude::*;
+use thiserror::Error;
+
+/// The only completion window offered by the Batch API.
+pub const COMPLETION_WINDOW: &str = "24h";
+
+/// The chat completion endpoint as named in batch files and jobs, whatever the API base URL.
+pub const CHAT_COMPLETIONS_ENDPOINT: &str = "/v1/chat/completions";
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Validating,
+    Failed,
+    InProgress,
+    Finalizing,
+    Completed,
+    Expired,
+    Cancelling,
+    Cancelled,
+}
+
+/// An asynchronous batch job of the Batch API.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BatchJob {
+    pub id: String,
+    pub endpoint: String,
+    pub status: BatchStatus,
+    pub input_file_id: String,
+    /// Set once the job produced results.
+    #[serde(default)]
+    pub output_file_id: Option<String>,
+    /// Set when some requests failed.
+    #[serde(default)]
+    pub error_file_id: Option<String>,
+    /// Seconds since the UNIX epoch.
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub request_counts: Option<BatchRequestCounts>,
+    /// Validation errors of a `failed` job.
+    #[serde(default)]
+    pub errors: Option<serde_json::Value>,
+    #[serde(default)]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BatchRequestCounts {
+    pub total: u64,
+    pub completed: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateBatchRequest<'a> {
+    pub input_file_id: &'a str,
+    pub endpoint: &'a str,
+    pub completion_window: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<&'a HashMap<String, String>>,
+}
+
+/// A line of a batch input file.
+#[derive(Debug, Serialize)]
+pub struct BatchRequestLine<'a, T> {
+    pub custom_id: &'a str,
+    pub method: &'a str,
+    /// Path of the endpoint, e.g. `/v1/chat/completions`.
+    pub url: &'a str,
+    pub body: &'a T,
+}
+
+/// A line of a batch output (or error) file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchResponseLine {
+    pub custom_id: String,
+    #[serde(default)]
+    pub response: Option<BatchResponse>,
+    #[serde(default)]
+    pub error: Option<BatchLineError>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchResponse {
+    pub status_code: u16,
+    pub body: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchLineError {
+    #[serde(default)]
+    pub code: Option<String>,
+    pub message: String,
+}
+
+/// Why a request of a batch has no response.
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum BatchItemError {
+    #[error("request failed ({:?}): {}", code, message)]
+    Failed { code: Option<String>, message: String },
+    #[error("request failed: {} (http status: {})", message, status)]
+    Status { status: u16, message: String },
+    #[error("invalid response: {}", _0)]
+    InvalidResponse(String),
+    #[error("no result in the batch output")]
+    Missing,
+}
+
+impl BatchStatus {
+    /// Whether the job will not change anymore.
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Failed | Self::Completed | Self::Expired | Self::Cancelled)
+    }
+}
+
+/// Build the JSONL input file of `requests`, each one identified by its `custom_id` and sent
+/// to the endpoint at `url` (a path).
+pub fn build_batch_file<'a, T: Serialize + 'a>(
+    url: &str,
+    requests: impl IntoIterator<Item = (&'a str, &'a T)>,
+) -> serde_json::Result<String> {
+    let mut file = String::new();
+    for (custom_id, body) in requests {
+        let line = BatchRequestLine {
+            custom_id,
+            method: "POST",
+            url,
+            body,
+        };
+        file.push_str(&serde_json::to_string(&line)?);
+        file.push('\n');
+    }
+    Ok(file)
+}
+
+/// Build the JSONL input file of chat completion `requests`.
+pub fn build_chat_batch_file(url: &str, requests: &[(String, ChatCompletionRequest<'_>)]) -> serde_json::Result<String> {
+    build_batch_file(url, requests.iter().map(|(custom_id, request)| (custom_id.as_str(), request)))
+}
+
+/// Parse an output (or error) file, the results are keyed by `custom_id`.
+pub fn parse_batch_results<R: serde::de::DeserializeOwned>(
+    jsonl: &str,
+) -> serde_json::Result<HashMap<String, std::result::Result<R, BatchItemError>>> {
+    let mut results = HashMap::new();
+    for line in jsonl.lines().filter(|line| !line.trim().is_empty()) {
+        let line: BatchResponseLine = serde_json::from_str(line)?;
+        let result = match (line.response, line.error) {
+            (_, Some(error)) => Err(BatchItemError::Failed {
+                code: error.code,
+                message: error.message,
+            }),
+            (Some(response), None) if (200..300).contains(&response.status_code) => {
+                serde_json::from_value(response.body).map_err(|e| BatchItemError::InvalidResponse(e.to_string()))
+            }
+            (Some(response), None) => Err(BatchItemError::Status {
+                status: response.status_code,
+                message: response.body["error"]["message"].as_str().unwrap_or_default().to_string(),
+            }),
+            (None, None) => Err(BatchItemError::Missing),
+        };
+        results.insert(line.custom_id, result);
+    }
+    Ok(results)
+}
+
+/// [`parse_batch_results`] for chat completions.
+pub fn parse_chat_batch_results(
+    jsonl: &str,
+) -> serde_json::Result<HashMap<String, std::result::Result<ChatCompletionResponse, BatchItemError>>> {
+    parse_batch_results(jsonl)
+}
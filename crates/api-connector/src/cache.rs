@@ -0,0 +1,420 @@
+use crate::cassette::{fnv1a, normalize};
+use crate::middleware::{Exchange, Middleware, MiddlewareError, MiddlewareFuture, Next, Result};
+use crate::prelude::*;
+use base64::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Response header telling whether a response was served by the cache (`hit`) or stored in it
+/// (`miss`), read by [`crate::middleware::JsonlLogger`].
+pub const CACHE_HEADER: &str = "x-entropy-cache";
+
+/// On-disk cache of the successful responses, keyed by a hash of the method, the URL and the
+/// normalized JSON body (keys sorted, [`ResponseCache::ignored_fields`] removed).
+///
+/// Only deterministic requests are cached unless [`ResponseCache::with_any_request`]: the ones
+/// with a fixed `seed` or a `temperature` of `0`, and never streamed ones. Multipart requests
+/// are never cached, their uploaded file is not part of the body seen by the middlewares, nor
+/// `GET` requests (status polls). Only final successes are stored: a 2xx response whose JSON
+/// `status`, when it has one, is `success` (see [`is_final`]). Register it after a
+/// [`crate::middleware::JsonlLogger`] to log the hits and misses. Clones share the same state.
+#[derive(Clone, Debug)]
+pub struct ResponseCache {
+    directory: PathBuf,
+    ttl: Option<Duration>,
+    max_size: Option<u64>,
+    bypass: bool,
+    any_request: bool,
+    /// Body fields left out of the key (secrets, generated ids).
+    pub ignored_fields: Vec<String>,
+    state: Arc<Mutex<CacheState>>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Size and last access (milliseconds since the UNIX epoch) by entry file name.
+    entries: HashMap<String, (u64, u64)>,
+    hits: u64,
+    misses: u64,
+}
+
+/// A cached response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub method: String,
+    pub url: String,
+    pub request: serde_json::Value,
+    /// Seconds since the UNIX epoch.
+    pub created: u64,
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// Base64 encoded.
+    pub body: String,
+}
+
+impl ResponseCache {
+    /// Open (or create) the cache stored in `directory`.
+    pub fn open(directory: impl Into<PathBuf>) -> Result<Self> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        let mut entries = HashMap::new();
+        for entry in std::fs::read_dir(&directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if metadata.is_file() && name.ends_with(".json") {
+                let accessed = metadata.modified().map(unix_millis).unwrap_or_default();
+                entries.insert(name, (metadata.len(), accessed));
+            }
+        }
+        Ok(Self {
+            directory,
+            ttl: None,
+            max_size: None,
+            bypass: false,
+            any_request: false,
+            ignored_fields: vec!["key".to_string(), "track_id".to_string(), "webhook".to_string()],
+            state: Arc::new(Mutex::new(CacheState {
+                entries,
+                ..Default::default()
+            })),
+        })
+    }
+
+    /// The cache configured by `API_CACHE_DIR` (enables it), `API_CACHE_TTL` (seconds),
+    /// `API_CACHE_MAX_SIZE` (bytes) and `API_CACHE_BYPASS`, `None` when `API_CACHE_DIR` is not set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(directory) = std::env::var("API_CACHE_DIR") else {
+            return Ok(None);
+        };
+        let number = |name: &str| -> Result<Option<u64>> {
+            match std::env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| MiddlewareError::InvalidSetting(format!("{}={}", name, value))),
+                Err(_) => Ok(None),
+            }
+        };
+        let bypass = std::env::var("API_CACHE_BYPASS").is_ok_and(|value| !value.is_empty() && value != "0");
+        let mut cache = Self::open(directory)?.with_bypass(bypass);
+        if let Some(ttl) = number("API_CACHE_TTL")? {
+            cache = cache.with_ttl(Duration::from_secs(ttl));
+        }
+        if let Some(max_size) = number("API_CACHE_MAX_SIZE")? {
+            cache = cache.with_max_size(max_size);
+        }
+        Ok(Some(cache))
+    }
+
+    /// Entries older than `ttl` are not served (and are removed).
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Evict the least recently used entries once the cache is larger than `max_size` bytes.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Never serve cached responses, the fresh ones still replace them.
+    pub fn with_bypass(mut self, bypass: bool) -> Self {
+        self.bypass = bypass;
+        self
+    }
+
+    /// Also cache the requests which are not deterministic.
+    pub fn with_any_request(mut self, any_request: bool) -> Self {
+        self.any_request = any_request;
+        self
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.state.lock().unwrap().hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.state.lock().unwrap().misses
+    }
+
+    /// Bytes used by the entries.
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().entries.values().map(|(size, _)| size).sum()
+    }
+
+    fn file_name(&self, method: &str, url: &str, request: &serde_json::Value) -> String {
+        format!("{:016x}.json", fnv1a(format!("{} {} {}", method, url, request).as_bytes()))
+    }
+
+    async fn lookup(&self, name: &str, method: &str, url: &str, request: &serde_json::Value) -> Result<Option<CacheEntry>> {
+        if !self.state.lock().unwrap().entries.contains_key(name) {
+            return Ok(None);
+        }
+        let path = self.directory.join(name);
+        let entry = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice::<CacheEntry>(&bytes).ok(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let expired = |entry: &CacheEntry| {
+            self.ttl
+                .is_some_and(|ttl| unix_millis(SystemTime::now()) / 1000 >= entry.created + ttl.as_secs())
+        };
+        match entry {
+            // Another request with the same file name (a hash collision) is a miss.
+            Some(entry) if entry.method != method || entry.url != url || entry.request != *request => Ok(None),
+            Some(entry) if !expired(&entry) => {
+                let now = SystemTime::now();
+                // Only keeps the eviction order across reopenings, a failure is not worth failing the request.
+                let _ = tokio::task::spawn_blocking(move || {
+                    std::fs::File::options().write(true).open(path)?.set_modified(now)
+                })
+                .await;
+                if let Some((_, accessed)) = self.state.lock().unwrap().entries.get_mut(name) {
+                    *accessed = unix_millis(now);
+                }
+                Ok(Some(entry))
+            }
+            _ => {
+                self.remove(name);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn store(&self, name: String, entry: &CacheEntry) -> Result<()> {
+        let bytes = serde_json::to_vec(entry).map_err(std::io::Error::from)?;
+        tokio::fs::write(self.directory.join(&name), &bytes).await?;
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .insert(name, (bytes.len() as u64, unix_millis(SystemTime::now())));
+        self.evict();
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) {
+        self.state.lock().unwrap().entries.remove(name);
+        let _ = std::fs::remove_file(self.directory.join(name));
+    }
+
+    /// Remove the least recently used entries until the cache fits in `max_size`.
+    fn evict(&self) {
+        let Some(max_size) = self.max_size else {
+            return;
+        };
+        loop {
+            let oldest = {
+                let state = self.state.lock().unwrap();
+                if state.entries.values().map(|(size, _)| size).sum::<u64>() <= max_size {
+                    return;
+                }
+                state
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, (_, accessed))| *accessed)
+                    .map(|(name, _)| name.clone())
+            };
+            match oldest {
+                Some(name) => self.remove(&name),
+                None => return,
+            }
+        }
+    }
+}
+
+/// Whether the same request is expected to get the same answer: a fixed seed (`-1` is random)
+/// or a temperature of `0`, and not streamed.
+pub fn is_deterministic(body: &serde_json::Value) -> bool {
+    if body.get("stream").and_then(|stream| stream.as_bool()) == Some(true) {
+        return false;
+    }
+    let seeded = body.get("seed").is_some_and(|seed| seed.is_u64());
+    seeded || body.get("temperature").and_then(|temperature| temperature.as_f64()) == Some(0.0)
+}
+
+/// Whether the request body is a multipart form (an upload).
+fn is_multipart(request: &reqwest::Request) -> bool {
+    request
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/"))
+}
+
+/// Whether a successful response body is a final answer, not an error or a pending job reported
+/// with a `status` field (Stable Diffusion's `error` and `processing`, a queued batch).
+pub fn is_final(body: &[u8]) -> bool {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(json) => json.get("status").and_then(|status| status.as_str()).is_none_or(|status| status == "success"),
+        Err(_) => true,
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn into_response(entry: &CacheEntry, body: Vec<u8>, cache: Option<&str>) -> Result<reqwest::Response> {
+    let mut builder = http::Response::builder().status(entry.status);
+    for (name, value) in entry.headers.iter() {
+        if name != "content-length" && name != "transfer-encoding" && name != "content-encoding" {
+            builder = builder.header(name, value);
+        }
+    }
+    if let Some(cache) = cache {
+        builder = builder.header(CACHE_HEADER, cache);
+    }
+    let response = builder
+        .body(body)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok(reqwest::Response::from(response))
+}
+
+impl Middleware for ResponseCache {
+    fn handle<'a>(&'a self, exchange: Exchange, next: Next<'a>) -> MiddlewareFuture<'a> {
+        if exchange.request.method() == reqwest::Method::GET
+            || is_multipart(&exchange.request)
+            || (!self.any_request && !is_deterministic(&exchange.body))
+        {
+            return next.run(exchange);
+        }
+        Box::pin(async move {
+            let method = exchange.request.method().to_string();
+            let url = exchange.request.url().to_string();
+            let request = normalize(exchange.body.clone(), &self.ignored_fields);
+            let name = self.file_name(&method, &url, &request);
+            if !self.bypass {
+                if let Some(entry) = self.lookup(&name, &method, &url, &request).await? {
+                    self.state.lock().unwrap().hits += 1;
+                    let body = BASE64_STANDARD
+                        .decode(&entry.body)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                    return into_response(&entry, body, Some("hit"));
+                }
+            }
+            self.state.lock().unwrap().misses += 1;
+            let response = next.run(exchange).await?;
+            if !response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status().as_u16();
+            let headers = response
+                .headers()
+                .iter()
+                .filter(|(name, _)| name.as_str() != CACHE_HEADER)
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                .collect();
+            let body = response.bytes().await?.to_vec();
+            let entry = CacheEntry {
+                method,
+                url,
+                request,
+                created: unix_millis(SystemTime::now()) / 1000,
+                status,
+                headers,
+                body: BASE64_STANDARD.encode(&body),
+            };
+            if !is_final(&body) {
+                return into_response(&entry, body, None);
+            }
+            self.store(name, &entry).await?;
+            into_response(&entry, body, Some("miss"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Middlewares;
+
+    #[tokio::test]
+    async fn test_response_cache() {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"answer": 42})))
+            .expect(7)
+            .mount(&server)
+            .await;
+        let directory = tempfile::tempdir().unwrap();
+        let cache = ResponseCache::open(directory.path()).unwrap();
+        let mut middlewares = Middlewares::default();
+        middlewares.push(cache.clone());
+        let client = Client::new();
+        let url = format!("{}/v1/chat/completions", server.uri());
+        let send = |middlewares: &Middlewares, body: serde_json::Value| {
+            let builder = client.post(&url).json(&body);
+            let middlewares = middlewares.clone();
+            let client = client.clone();
+            async move {
+                let response = middlewares.send("test", &client, None, builder, &body).await.unwrap();
+                let header = response.headers().get(CACHE_HEADER).map(|value| value.to_str().unwrap().to_string());
+                let json = response.json::<serde_json::Value>().await.unwrap();
+                assert_eq!(json["answer"], 42);
+                header
+            }
+        };
+
+        let request = serde_json::json!({"model": "gpt-4", "temperature": 0.0, "key": "a"});
+        assert_eq!(send(&middlewares, request.clone()).await.as_deref(), Some("miss"));
+        // The ignored fields and the key order are not part of the key.
+        let reordered = serde_json::json!({"key": "b", "temperature": 0.0, "model": "gpt-4"});
+        assert_eq!(send(&middlewares, reordered).await.as_deref(), Some("hit"));
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        // Not deterministic, sent twice.
+        let random = serde_json::json!({"model": "gpt-4", "temperature": 1.0});
+        assert_eq!(send(&middlewares, random.clone()).await, None);
+        assert_eq!(send(&middlewares, random).await, None);
+        // Nor uploads, even with any request cached.
+        let mut any = Middlewares::default();
+        any.push(cache.clone().with_any_request(true));
+        let form = reqwest::multipart::Form::new().text("temperature", "0");
+        let builder = client.post(&url).multipart(form);
+        let response = any.send("test", &client, None, builder, &serde_json::json!({"temperature": "0"})).await.unwrap();
+        assert!(response.headers().get(CACHE_HEADER).is_none());
+
+        // A reopened cache serves the stored entries, the bypassed one refreshes them.
+        let mut bypassed = Middlewares::default();
+        bypassed.push(ResponseCache::open(directory.path()).unwrap().with_bypass(true));
+        assert_eq!(send(&bypassed, request.clone()).await.as_deref(), Some("miss"));
+        let mut reopened = Middlewares::default();
+        reopened.push(ResponseCache::open(directory.path()).unwrap());
+        assert_eq!(send(&reopened, request.clone()).await.as_deref(), Some("hit"));
+        // An entry stored for another request under the same name is not served.
+        for file in std::fs::read_dir(directory.path()).unwrap() {
+            let path = file.unwrap().path();
+            let mut entry: CacheEntry = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            entry.request = serde_json::json!({"model": "gpt-4", "seed": 1});
+            std::fs::write(&path, serde_json::to_vec(&entry).unwrap()).unwrap();
+        }
+        assert_eq!(send(&reopened, request.clone()).await.as_deref(), Some("miss"));
+
+        // Over its size cap, the least recently used entries are evicted.
+        let capped = ResponseCache::open(directory.path()).unwrap().with_max_size(1);
+        let mut middlewares = Middlewares::default();
+        middlewares.push(capped.clone());
+        let seeded = serde_json::json!({"model": "gpt-4", "seed": 7});
+        assert_eq!(send(&middlewares, seeded).await.as_deref(), Some("miss"));
+        assert_eq!(capped.size(), 0);
+
+        assert!(is_final(br#"{"status": "success", "output": []}"#));
+        assert!(is_final(br#"{"choices": []}"#));
+        assert!(!is_final(br#"{"status": "processing", "id": 7}"#));
+        assert!(!is_final(br#"{"status": "error", "message": "out of credits"}"#));
+        assert!(is_deterministic(&serde_json::json!({"seed": 3})));
+        assert!(!is_deterministic(&serde_json::json!({"seed": -1})));
+        assert!(!is_deterministic(&serde_json::json!({"temperature": 0, "stream": true})));
+    }
+}
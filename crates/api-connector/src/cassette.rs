@@ -0,0 +1,259 @@
+use crate::prelude::*;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CassetteError {
+    #[error("IO error: {}", _0)]
+    IoError(#[from] std::io::Error),
+    #[error("JSON error: {}", _0)]
+    JsonError(#[from] serde_json::Error),
+    #[error("reqwest error: {}", _0)]
+    HttpClientError(#[from] reqwest::Error),
+    #[error("no recorded interaction for `{}` (expected fixture: {:?})", endpoint, path)]
+    Miss { endpoint: String, path: PathBuf },
+    #[error("invalid cassette mode `{}` (expected `record` or `replay`)", _0)]
+    InvalidMode(String),
+}
+
+pub type Result<T> = std::result::Result<T, CassetteError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CassetteMode {
+    /// Requests are sent and every exchange is saved to the cassette.
+    Record,
+    /// Requests are never sent, responses are served from the cassette.
+    Replay,
+}
+
+/// Record/replay layer for the connectors' HTTP exchanges.
+///
+/// Every interaction is saved to `<directory>/<endpoint>-<key>.json` where the key is a stable
+/// hash of the HTTP method, the URL path and the normalized JSON body (keys sorted,
+/// [`Cassette::ignored_fields`] removed). The host is not part of the key so recordings can be
+/// replayed against another server.
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    mode: CassetteMode,
+    directory: PathBuf,
+    /// Body fields left out of the key and of the fixtures (secrets, generated ids).
+    pub ignored_fields: Vec<String>,
+}
+
+/// A recorded request/response pair.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub method: String,
+    pub endpoint: String,
+    pub request: serde_json::Value,
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub body: InteractionBody,
+}
+
+/// JSON bodies are kept as JSON so the fixtures stay readable, anything else (SSE streams) as text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InteractionBody {
+    Json(serde_json::Value),
+    Text(String),
+}
+
+impl Cassette {
+    pub fn new(mode: CassetteMode, directory: impl Into<PathBuf>) -> Self {
+        Self {
+            mode,
+            directory: directory.into(),
+            ignored_fields: vec!["key".to_string(), "track_id".to_string(), "webhook".to_string()],
+        }
+    }
+
+    pub fn record(directory: impl Into<PathBuf>) -> Self {
+        Self::new(CassetteMode::Record, directory)
+    }
+
+    pub fn replay(directory: impl Into<PathBuf>) -> Self {
+        Self::new(CassetteMode::Replay, directory)
+    }
+
+    /// Build the cassette configured by `API_CASSETTE` (`record` or `replay`) and
+    /// `API_CASSETTE_DIR` (defaults to `cassettes`), `None` when `API_CASSETTE` is not set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(mode) = std::env::var("API_CASSETTE") else {
+            return Ok(None);
+        };
+        let mode = match mode.as_str() {
+            "record" => CassetteMode::Record,
+            "replay" => CassetteMode::Replay,
+            _ => return Err(CassetteError::InvalidMode(mode)),
+        };
+        let directory = std::env::var("API_CASSETTE_DIR").unwrap_or_else(|_| "cassettes".to_string());
+        Ok(Some(Self::new(mode, directory)))
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Send `builder` (whose JSON body is `body`) or replay the recorded response.
+    pub(crate) async fn send<T: Serialize>(
+        &self,
+        method: &str,
+        url: &str,
+        body: &T,
+        builder: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        let endpoint = url_path(url);
+        let request = self.normalize(serde_json::to_value(body)?);
+        let path = self.fixture_path(method, &endpoint, &request);
+        match self.mode {
+            CassetteMode::Replay => {
+                let interaction: Interaction = match tokio::fs::read(&path).await {
+                    Ok(bytes) => serde_json::from_slice(&bytes)?,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        return Err(CassetteError::Miss { endpoint, path })
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                interaction.into_response()
+            }
+            CassetteMode::Record => {
+                let response = builder.send().await?;
+                let status = response.status().as_u16();
+                let headers = response
+                    .headers()
+                    .iter()
+                    .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                    .collect();
+                let bytes = response.bytes().await?;
+                let text = String::from_utf8_lossy(&bytes).into_owned();
+                let body = match serde_json::from_str(&text) {
+                    Ok(json) => InteractionBody::Json(json),
+                    Err(_) => InteractionBody::Text(text),
+                };
+                let interaction = Interaction {
+                    method: method.to_string(),
+                    endpoint,
+                    request,
+                    status,
+                    headers,
+                    body,
+                };
+                tokio::fs::create_dir_all(&self.directory).await?;
+                tokio::fs::write(&path, serde_json::to_vec_pretty(&interaction)?).await?;
+                interaction.into_response()
+            }
+        }
+    }
+
+    /// Remove the ignored fields and sort the object keys.
+    fn normalize(&self, value: serde_json::Value) -> serde_json::Value {
+        normalize(value, &self.ignored_fields)
+    }
+
+    fn fixture_path(&self, method: &str, endpoint: &str, request: &serde_json::Value) -> PathBuf {
+        let key = format!("{} {} {}", method, endpoint, request);
+        let name = endpoint
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>()
+            .join("_");
+        self.directory.join(format!("{}-{:016x}.json", name, fnv1a(key.as_bytes())))
+    }
+}
+
+impl Interaction {
+    fn into_response(self) -> Result<reqwest::Response> {
+        let body = match self.body {
+            InteractionBody::Json(json) => serde_json::to_string(&json)?,
+            InteractionBody::Text(text) => text,
+        };
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in self.headers.iter() {
+            // The body is re-encoded, its original framing does not apply anymore.
+            if name != "content-length" && name != "transfer-encoding" && name != "content-encoding" {
+                builder = builder.header(name, value);
+            }
+        }
+        let response = builder.body(body).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(response.into())
+    }
+}
+
+fn url_path(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| url.to_string())
+}
+
+/// Remove the `ignored_fields` and sort the object keys.
+pub(crate) fn normalize(value: serde_json::Value, ignored_fields: &[String]) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        Value::Object(map) => {
+            let mut entries = map
+                .into_iter()
+                .filter(|(name, _)| !ignored_fields.contains(name))
+                .map(|(name, value)| (name, normalize(value, ignored_fields)))
+                .collect::<Vec<_>>();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(entries.into_iter().collect())
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(|value| normalize(value, ignored_fields)).collect()),
+        value => value,
+    }
+}
+
+/// FNV-1a, used because the keys must stay stable across rust versions and platforms.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"answer": 42})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let directory = tempfile::tempdir().unwrap();
+        let url = format!("{}/v1/chat/completions", server.uri());
+        let client = Client::new();
+
+        let body = serde_json::json!({"b": 1, "a": [{"y": 1, "x": 2}], "key": "secret"});
+        let recorded = Cassette::record(directory.path())
+            .send("POST", &url, &body, client.post(&url).json(&body))
+            .await
+            .unwrap();
+        assert_eq!(recorded.json::<serde_json::Value>().await.unwrap()["answer"], 42);
+
+        // Same request with other keys order and another secret: served from the cassette.
+        let body = serde_json::json!({"a": [{"x": 2, "y": 1}], "b": 1, "key": "other"});
+        let cassette = Cassette::replay(directory.path());
+        let replayed = cassette
+            .send("POST", "http://localhost:1/v1/chat/completions", &body, client.post(&url))
+            .await
+            .unwrap();
+        assert_eq!(replayed.status(), 200);
+        assert_eq!(replayed.json::<serde_json::Value>().await.unwrap()["answer"], 42);
+
+        let body = serde_json::json!({"b": 2});
+        let miss = cassette.send("POST", &url, &body, client.post(&url)).await;
+        assert!(matches!(miss, Err(CassetteError::Miss { .. })));
+    }
+}
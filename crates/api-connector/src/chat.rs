@@ -0,0 +1,984 @@
+use crate::image_generator::{extension_mime, Image};
+use crate::prelude::*;
+use base64::prelude::*;
+use futures_util::Stream;
+use std::borrow::Cow;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use thiserror::Error;
+
+/// Stream of chunks returned by [`ChatProvider::chat_completion_stream`].
+pub type ChatCompletionStream<E> = Pin<Box<dyn Stream<Item = std::result::Result<ChatCompletionChunk, E>> + Send>>;
+
+/// A chat completion backend speaking the (OpenAI) chat completion model defined in this module.
+///
+/// [`crate::openai::OpenAIConnector`] implements it for the OpenAI API and every compatible
+/// server (llama.cpp server, vLLM, LocalAI, [...]).
+pub trait ChatProvider: Send + Sync {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// The model used when the request does not name one.
+    fn default_model(&self) -> &str;
+
+    /// The number of tokens `model` can handle, `None` when unknown.
+    fn context_window(&self, model: &str) -> Option<usize> {
+        crate::tokenizer::context_window(model)
+    }
+
+    fn chat_completion<'a>(
+        &'a self,
+        request: ChatCompletionRequest<'a>,
+    ) -> impl Future<Output = std::result::Result<ChatCompletionResponse, Self::Error>> + Send + 'a;
+
+    /// Perform a streamed chat completion, chunks are yielded as soon as the server sends them.
+    fn chat_completion_stream<'a>(
+        &'a self,
+        request: ChatCompletionRequest<'a>,
+    ) -> impl Future<Output = std::result::Result<ChatCompletionStream<Self::Error>, Self::Error>> + Send + 'a;
+}
+
+#[derive(Clone, Serialize, Debug, Default)]
+pub struct ChatCompletionRequest<'a> {
+    pub messages: &'a [ChatCompletionMessage],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub functions: Option<&'a [ChatCompletionFunction]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<ChatCompletionFunctionCallMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<ChatCompletionStreamOptions>,
+    #[serde(flatten)]
+    pub parameters: ChatCompletionParameters,
+}
+
+/// The model and sampling options of a request, unset options are left to the server.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ChatCompletionParameters {
+    /// Filled with the provider's default model when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<ChatCompletionStop>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    /// Bias (from -100 to 100) added to the logits of the given token ids.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<String, f64>>,
+    /// End-user identifier, used by OpenAI to monitor abuse.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ChatCompletionResponseFormat>,
+}
+
+/// Up to 4 sequences where the generation stops.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ChatCompletionStop {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatCompletionResponseFormat {
+    Text,
+    /// JSON mode: the model only outputs valid JSON objects.
+    JsonObject,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatCompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChatCompletionChoice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct ChatCompletionStreamOptions {
+    /// Ask for a last chunk (with no choices) holding the [`Usage`] of the whole completion.
+    pub include_usage: bool,
+}
+
+/// Tokens consumed by a completion.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+ 
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatCompletionChoice {
+    pub message: ChatCompletionMessage,
+    pub index: u64,
+    pub finish_reason: String,
+}
+
+/// A streamed fragment of a chat completion (`object: chat.completion.chunk`).
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChatCompletionChunkChoice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatCompletionChunkChoice {
+    pub delta: ChatCompletionDelta,
+    pub index: u64,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct ChatCompletionDelta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<ChatCompletionRole>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<ChatCompletionFunctionCallDelta>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct ChatCompletionFunctionCallDelta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+/// Puts streamed chunks back together into a regular [`ChatCompletionResponse`].
+#[derive(Clone, Debug, Default)]
+pub struct ChatCompletionAccumulator {
+    id: String,
+    object: String,
+    created: u64,
+    model: String,
+    choices: Vec<(ChatCompletionMessage, Option<String>)>,
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChatCompletionMessage {
+    pub role: ChatCompletionRole,
+    /// Empty when the assistant answered with a `function_call` (`null` on the wire).
+    #[serde(default, deserialize_with = "deserialize_null_default")]
+    pub content: MessageContent,
+    /// Name of the function whose result is carried by a `Function` role message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<ChatCompletionFunctionCall>,
+}
+
+/// The content of a message: plain text (a string on the wire) or parts mixing text and images
+/// for vision models.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// An image given to the model, by URL or inlined as a `data:` URL.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ImageDetail>,
+}
+
+/// Resolution the model sees an image at, `low` costs a fixed amount of tokens.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageDetail {
+    #[default]
+    Auto,
+    Low,
+    High,
+}
+
+/// A function call requested by the model, `arguments` is a JSON encoded object.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChatCompletionFunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// Controls how the model responds to function calls (`function_call` request parameter).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatCompletionFunctionCallMode {
+    /// The model does not call any function.
+    None,
+    /// The model picks between an answer and a function call (default when functions are sent).
+    Auto,
+    /// Forces the model to call the given function.
+    Function(String),
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChatCompletionFunction {
+    pub name: String,
+    pub description: String,
+    pub parameters: ChatCompletionFunctionParameters,
+}
+
+/// JSON-Schema of the arguments object of a function.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChatCompletionFunctionParameters {
+    #[serde(rename = "type")]
+    pub kind: JsonSchemaType,
+    #[serde(default)]
+    pub properties: HashMap<String, ChatCompletionFunctionProperty>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<String>,
+}
+
+/// The subset of JSON-Schema supported to describe a function argument.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChatCompletionFunctionProperty {
+    #[serde(rename = "type")]
+    pub kind: JsonSchemaType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, rename = "enum", skip_serializing_if = "Option::is_none")]
+    pub enum_values: Option<Vec<serde_json::Value>>,
+    /// See [`JSON_SCHEMA_FORMATS`], only for `string`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Schema of the elements, required for (and only for) `array`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<ChatCompletionFunctionProperty>>,
+    /// Fields of a nested `object`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub properties: Option<HashMap<String, ChatCompletionFunctionProperty>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<serde_json::Number>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<serde_json::Number>,
+    #[serde(default, rename = "minLength", skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<u64>,
+    #[serde(default, rename = "maxLength", skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<u64>,
+    #[serde(default, rename = "minItems", skip_serializing_if = "Option::is_none")]
+    pub min_items: Option<u64>,
+    #[serde(default, rename = "maxItems", skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonSchemaType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Array,
+    Object,
+    Null,
+}
+
+/// Values accepted for [`ChatCompletionFunctionProperty::format`].
+pub const JSON_SCHEMA_FORMATS: &[&str] = &[
+    "date", "date-time", "time", "duration", "email", "hostname", "ipv4", "ipv6", "uri", "uuid",
+];
+
+/// A function schema that does not describe what it claims to (`path` points to the faulty node).
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[error("invalid schema at `{}`: {}", path, reason)]
+pub struct FunctionSchemaError {
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatCompletionRole {
+    System,
+    User,
+    Assistant,
+    Function,
+}
+
+impl ChatCompletionRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::User => "user",
+            Self::Assistant => "assistant",
+            Self::Function => "function",
+        }
+    }
+}
+
+impl Default for MessageContent {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+impl MessageContent {
+    /// The text of the content, the text parts are joined by new lines.
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            Self::Text(text) => Cow::Borrowed(text),
+            Self::Parts(parts) => {
+                let mut texts = parts.iter().filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                });
+                match (texts.next(), texts.next()) {
+                    (None, _) => Cow::Borrowed(""),
+                    (Some(text), None) => Cow::Borrowed(text),
+                    (Some(first), Some(second)) => {
+                        Cow::Owned([first, second].into_iter().chain(texts).collect::<Vec<_>>().join("\n"))
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn images(&self) -> impl Iterator<Item = &ImageUrl> {
+        let parts = match self {
+            Self::Text(_) => &[][..],
+            Self::Parts(parts) => parts.as_slice(),
+        };
+        parts.iter().filter_map(|part| match part {
+            ContentPart::ImageUrl { image_url } => Some(image_url),
+            ContentPart::Text { .. } => None,
+        })
+    }
+
+    /// Whether there is neither text nor image.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Text(text) => text.is_empty(),
+            Self::Parts(parts) => parts.iter().all(|part| matches!(part, ContentPart::Text { text } if text.is_empty())),
+        }
+    }
+
+    /// Append `text` to the (last) text.
+    pub fn push_str(&mut self, text: &str) {
+        match self {
+            Self::Text(content) => content.push_str(text),
+            Self::Parts(parts) => match parts.last_mut() {
+                Some(ContentPart::Text { text: content }) => content.push_str(text),
+                _ => parts.push(ContentPart::Text { text: text.to_string() }),
+            },
+        }
+    }
+
+    /// Attach an image, the content becomes a list of parts.
+    pub fn push_image(&mut self, image_url: ImageUrl) {
+        if let Self::Text(text) = self {
+            let text = std::mem::take(text);
+            let parts = match text.is_empty() {
+                true => Vec::new(),
+                false => vec![ContentPart::Text { text }],
+            };
+            *self = Self::Parts(parts);
+        }
+        if let Self::Parts(parts) = self {
+            parts.push(ContentPart::ImageUrl { image_url });
+        }
+    }
+}
+
+impl std::fmt::Display for MessageContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text())
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for MessageContent {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl PartialEq<str> for MessageContent {
+    fn eq(&self, other: &str) -> bool {
+        self.images().next().is_none() && self.text() == other
+    }
+}
+
+impl PartialEq<&str> for MessageContent {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl PartialEq<String> for MessageContent {
+    fn eq(&self, other: &String) -> bool {
+        self == other.as_str()
+    }
+}
+
+impl ImageUrl {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            detail: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: ImageDetail) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    /// Inline `image` as a `data:` URL.
+    pub fn from_image(image: &Image) -> Self {
+        Self::new(format!("data:{};base64,{}", image.mime, BASE64_STANDARD.encode(&image.data)))
+    }
+
+    /// Inline the image file at `path`, its type is guessed from its extension.
+    pub async fn from_file(path: &Path) -> std::io::Result<Self> {
+        let mime = path
+            .extension()
+            .and_then(|extension| extension_mime(&extension.to_string_lossy()))
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("not an image: {:?}", path)))?;
+        Ok(Self::from_image(&Image::new(tokio::fs::read(path).await?, mime)))
+    }
+
+    /// Whether the image is inlined rather than fetched by the provider.
+    pub fn is_inline(&self) -> bool {
+        self.url.starts_with("data:")
+    }
+}
+
+impl ChatCompletionMessage {
+    pub fn new(role: ChatCompletionRole, content: impl Into<MessageContent>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            name: None,
+            function_call: None,
+        }
+    }
+
+    /// Build the `Function` role message holding the result of the function `name`.
+    pub fn function_result(name: String, content: String) -> Self {
+        Self {
+            name: Some(name),
+            ..Self::new(ChatCompletionRole::Function, content)
+        }
+    }
+}
+
+impl ChatCompletionFunctionCall {
+    /// Decode the JSON encoded arguments.
+    pub fn parse_arguments(&self) -> serde_json::Result<serde_json::Value> {
+        if self.arguments.trim().is_empty() {
+            return Ok(serde_json::Value::Object(Default::default()));
+        }
+        serde_json::from_str(&self.arguments)
+    }
+}
+
+impl Serialize for ChatCompletionFunctionCallMode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Self::None => serializer.serialize_str("none"),
+            Self::Auto => serializer.serialize_str("auto"),
+            Self::Function(name) => {
+                use serde::ser::SerializeMap;
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("name", name)?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl ChatCompletionFunction {
+    /// Check that the schema is consistent (types, constraints, required fields, defaults).
+    pub fn validate(&self) -> std::result::Result<(), FunctionSchemaError> {
+        let path = format!("{}.parameters", self.name);
+        if self.parameters.kind != JsonSchemaType::Object {
+            return Err(FunctionSchemaError::new(&path, "the parameters must be of type `object`"));
+        }
+        validate_properties(&path, &self.parameters.properties, &self.parameters.required)
+    }
+}
+
+impl FunctionSchemaError {
+    fn new(path: &str, reason: impl Into<String>) -> Self {
+        Self {
+            path: path.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl JsonSchemaType {
+    /// Whether `value` is an instance of this type.
+    pub fn accepts(&self, value: &serde_json::Value) -> bool {
+        use serde_json::Value;
+        match (self, value) {
+            (Self::String, Value::String(_)) => true,
+            (Self::Number, Value::Number(_)) => true,
+            (Self::Integer, Value::Number(n)) => n.is_i64() || n.is_u64(),
+            (Self::Boolean, Value::Bool(_)) => true,
+            (Self::Array, Value::Array(_)) => true,
+            (Self::Object, Value::Object(_)) => true,
+            (Self::Null, Value::Null) => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for JsonSchemaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Integer => "integer",
+            Self::Boolean => "boolean",
+            Self::Array => "array",
+            Self::Object => "object",
+            Self::Null => "null",
+        })
+    }
+}
+
+fn validate_properties(
+    path: &str,
+    properties: &HashMap<String, ChatCompletionFunctionProperty>,
+    required: &[String],
+) -> std::result::Result<(), FunctionSchemaError> {
+    if let Some(missing) = required.iter().find(|name| !properties.contains_key(*name)) {
+        return Err(FunctionSchemaError::new(path, format!("required property `{}` is not declared", missing)));
+    }
+    for (name, property) in properties {
+        property.validate(&format!("{}.{}", path, name))?;
+    }
+    Ok(())
+}
+
+impl ChatCompletionFunctionProperty {
+    /// A property of the given type without any constraint.
+    pub fn new(kind: JsonSchemaType) -> Self {
+        Self {
+            kind,
+            description: None,
+            enum_values: None,
+            format: None,
+            items: None,
+            properties: None,
+            required: Vec::new(),
+            minimum: None,
+            maximum: None,
+            min_length: None,
+            max_length: None,
+            min_items: None,
+            max_items: None,
+            default: None,
+        }
+    }
+
+    fn validate(&self, path: &str) -> std::result::Result<(), FunctionSchemaError> {
+        use JsonSchemaType::*;
+        let only_for = |field: &str, set: bool, kinds: &[JsonSchemaType]| {
+            if set && !kinds.contains(&self.kind) {
+                Err(FunctionSchemaError::new(
+                    path,
+                    format!("`{}` is not allowed for type `{}`", field, self.kind),
+                ))
+            } else {
+                Ok(())
+            }
+        };
+        only_for("format", self.format.is_some(), &[String])?;
+        only_for("items", self.items.is_some(), &[Array])?;
+        only_for("properties", self.properties.is_some(), &[Object])?;
+        only_for("required", !self.required.is_empty(), &[Object])?;
+        only_for("minimum", self.minimum.is_some(), &[Number, Integer])?;
+        only_for("maximum", self.maximum.is_some(), &[Number, Integer])?;
+        only_for("minLength", self.min_length.is_some(), &[String])?;
+        only_for("maxLength", self.max_length.is_some(), &[String])?;
+        only_for("minItems", self.min_items.is_some(), &[Array])?;
+        only_for("maxItems", self.max_items.is_some(), &[Array])?;
+
+        if let Some(format) = &self.format {
+            if !JSON_SCHEMA_FORMATS.contains(&format.as_str()) {
+                return Err(FunctionSchemaError::new(
+                    path,
+                    format!("unknown format `{}` (expected one of: {})", format, JSON_SCHEMA_FORMATS.join(", ")),
+                ));
+            }
+        }
+        let ordered = |min: Option<f64>, max: Option<f64>, min_name: &str, max_name: &str| match (min, max) {
+            (Some(min), Some(max)) if min > max => Err(FunctionSchemaError::new(
+                path,
+                format!("{} is greater than {}", min_name, max_name),
+            )),
+            _ => Ok(()),
+        };
+        ordered(
+            self.minimum.as_ref().and_then(|n| n.as_f64()),
+            self.maximum.as_ref().and_then(|n| n.as_f64()),
+            "minimum",
+            "maximum",
+        )?;
+        ordered(self.min_length.map(|n| n as f64), self.max_length.map(|n| n as f64), "minLength", "maxLength")?;
+        ordered(self.min_items.map(|n| n as f64), self.max_items.map(|n| n as f64), "minItems", "maxItems")?;
+
+        if let Some(values) = &self.enum_values {
+            if values.is_empty() {
+                return Err(FunctionSchemaError::new(path, "`enum` must not be empty"));
+            }
+            if let Some(value) = values.iter().find(|value| !self.kind.accepts(value)) {
+                return Err(FunctionSchemaError::new(
+                    path,
+                    format!("enum value `{}` is not of type `{}`", value, self.kind),
+                ));
+            }
+        }
+        if let Some(default) = &self.default {
+            if !self.kind.accepts(default) {
+                return Err(FunctionSchemaError::new(
+                    path,
+                    format!("default value `{}` is not of type `{}`", default, self.kind),
+                ));
+            }
+            if self.enum_values.as_ref().is_some_and(|values| !values.contains(default)) {
+                return Err(FunctionSchemaError::new(path, format!("default value `{}` is not in `enum`", default)));
+            }
+        }
+
+        match self.kind {
+            Array => match &self.items {
+                Some(items) => items.validate(&format!("{}[]", path))?,
+                None => return Err(FunctionSchemaError::new(path, "`items` is required for type `array`")),
+            },
+            Object => validate_properties(path, self.properties.as_ref().unwrap_or(&HashMap::new()), &self.required)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn deserialize_null_default<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+
+impl ChatCompletionParameters {
+    /// Override the options set in `other`.
+    pub fn merge(&mut self, other: ChatCompletionParameters) {
+        macro_rules! merge {
+            ($($field:ident),*) => {
+                $(if other.$field.is_some() {
+                    self.$field = other.$field;
+                })*
+            };
+        }
+        merge!(
+            model,
+            temperature,
+            top_p,
+            n,
+            stop,
+            max_tokens,
+            presence_penalty,
+            frequency_penalty,
+            logit_bias,
+            user,
+            seed,
+            response_format
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl From<String> for ChatCompletionStop {
+    fn from(stop: String) -> Self {
+        Self::One(stop)
+    }
+}
+
+impl From<Vec<String>> for ChatCompletionStop {
+    fn from(stop: Vec<String>) -> Self {
+        Self::Many(stop)
+    }
+}
+
+impl ChatCompletionAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Merge a chunk into the accumulated response.
+    pub fn push(&mut self, chunk: &ChatCompletionChunk) {
+        if self.id.is_empty() {
+            self.id = chunk.id.clone();
+            self.created = chunk.created;
+            self.model = chunk.model.clone();
+            self.object = "chat.completion".to_string();
+        }
+        if chunk.usage.is_some() {
+            self.usage = chunk.usage;
+        }
+        for choice in chunk.choices.iter() {
+            let index = choice.index as usize;
+            while self.choices.len() <= index {
+                self.choices.push((
+                    ChatCompletionMessage::new(ChatCompletionRole::Assistant, String::new()),
+                    None,
+                ));
+            }
+            let (message, finish_reason) = &mut self.choices[index];
+            if let Some(role) = &choice.delta.role {
+                message.role = role.clone();
+            }
+            if let Some(content) = &choice.delta.content {
+                message.content.push_str(content);
+            }
+            if let Some(delta) = &choice.delta.function_call {
+                let call = message.function_call.get_or_insert_with(|| ChatCompletionFunctionCall {
+                    name: String::new(),
+                    arguments: String::new(),
+                });
+                if let Some(name) = &delta.name {
+                    call.name.push_str(name);
+                }
+                if let Some(arguments) = &delta.arguments {
+                    call.arguments.push_str(arguments);
+                }
+            }
+            if choice.finish_reason.is_some() {
+                finish_reason.clone_from(&choice.finish_reason);
+            }
+        }
+    }
+
+    /// Content received so far for the first choice.
+    pub fn content(&self) -> Cow<'_, str> {
+        self.choices.first().map(|(message, _)| message.content.text()).unwrap_or_default()
+    }
+
+    pub fn finish(self) -> ChatCompletionResponse {
+        ChatCompletionResponse {
+            id: self.id,
+            object: self.object,
+            created: self.created,
+            model: self.model,
+            choices: self
+                .choices
+                .into_iter()
+                .enumerate()
+                .map(|(index, (message, finish_reason))| ChatCompletionChoice {
+                    message,
+                    index: index as u64,
+                    finish_reason: finish_reason.unwrap_or_default(),
+                })
+                .collect(),
+            usage: self.usage,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accumulate_chunks() {
+        let chunks = [
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1,"model":"gpt-4","choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1,"model":"gpt-4","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1,"model":"gpt-4","choices":[{"index":0,"delta":{"content":" world"},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1,"model":"gpt-4","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1,"model":"gpt-4","choices":[],"usage":{"prompt_tokens":9,"completion_tokens":2,"total_tokens":11}}"#,
+        ];
+        let mut accumulator = ChatCompletionAccumulator::new();
+        for chunk in chunks {
+            accumulator.push(&serde_json::from_str(chunk).unwrap());
+        }
+        let response = accumulator.finish();
+        assert_eq!(response.id, "chatcmpl-1");
+        assert_eq!(response.choices[0].message.content, "Hello world");
+        assert_eq!(response.choices[0].message.role, ChatCompletionRole::Assistant);
+        assert_eq!(response.choices[0].finish_reason, "stop");
+        assert_eq!(response.usage.map(|usage| usage.total_tokens), Some(11));
+    }
+
+    #[test]
+    fn test_request_parameters() {
+        let mut parameters: ChatCompletionParameters =
+            serde_json::from_value(serde_json::json!({"model": "gpt-4", "stop": "\n", "response_format": {"type": "json_object"}}))
+                .unwrap();
+        parameters.merge(ChatCompletionParameters {
+            temperature: Some(0.5),
+            stop: Some(vec!["a".to_string(), "b".to_string()].into()),
+            ..Default::default()
+        });
+        let request = ChatCompletionRequest {
+            parameters,
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            serde_json::json!({
+                "messages": [],
+                "model": "gpt-4",
+                "temperature": 0.5,
+                "stop": ["a", "b"],
+                "response_format": {"type": "json_object"}
+            })
+        );
+    }
+
+    #[test]
+    fn test_function_call_message() {
+        let message: ChatCompletionMessage = serde_json::from_str(
+            r#"{"role":"assistant","content":null,"function_call":{"name":"check_availability","arguments":"{\"people\": 2}"}}"#,
+        )
+        .unwrap();
+        assert_eq!(message.content, "");
+        let call = message.function_call.unwrap();
+        assert_eq!(call.name, "check_availability");
+        assert_eq!(call.parse_arguments().unwrap()["people"], 2);
+
+        let result = ChatCompletionMessage::function_result("check_availability".to_string(), "true".to_string());
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({"role": "function", "content": "true", "name": "check_availability"})
+        );
+        assert_eq!(
+            serde_json::to_value(ChatCompletionFunctionCallMode::Function("f".to_string())).unwrap(),
+            serde_json::json!({"name": "f"})
+        );
+    }
+
+    #[test]
+    fn test_function_schema() {
+        let schema = serde_json::json!({
+            "name": "book",
+            "description": "Book a room",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "arrival_date": {"type": "string", "format": "date"},
+                    "people": {"type": "integer", "minimum": 1, "maximum": 8, "default": 2},
+                    "options": {"type": "array", "items": {"type": "string", "enum": ["breakfast", "parking"]}},
+                    "contact": {
+                        "type": "object",
+                        "properties": {"email": {"type": "string", "format": "email"}},
+                        "required": ["email"]
+                    }
+                },
+                "required": ["arrival_date", "people"]
+            }
+        });
+        let function: ChatCompletionFunction = serde_json::from_value(schema.clone()).unwrap();
+        function.validate().unwrap();
+        assert_eq!(serde_json::to_value(&function).unwrap(), schema);
+
+        let mut invalid = function.clone();
+        invalid.parameters.properties.get_mut("people").unwrap().minimum = Some(10.into());
+        assert_eq!(
+            invalid.validate().unwrap_err(),
+            FunctionSchemaError::new("book.parameters.people", "minimum is greater than maximum")
+        );
+        let mut invalid = function.clone();
+        invalid.parameters.properties.get_mut("options").unwrap().items = None;
+        assert_eq!(
+            invalid.validate().unwrap_err().reason,
+            "`items` is required for type `array`"
+        );
+        let mut invalid = function;
+        invalid.parameters.required.push("checkout_date".to_string());
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_multimodal_content() {
+        // Stored messages with a plain string content are still read.
+        let message: ChatCompletionMessage = serde_yaml::from_str("role: user\ncontent: Rate this card\n").unwrap();
+        assert_eq!(message.content, MessageContent::Text("Rate this card".to_string()));
+
+        let mut content = message.content;
+        content.push_image(ImageUrl::from_image(&Image::new(b"png".to_vec(), "image/png")).with_detail(ImageDetail::Low));
+        content.push_image(ImageUrl::new("https://example.com/card.webp"));
+        let json = serde_json::to_value(ChatCompletionMessage::new(ChatCompletionRole::User, content.clone())).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"role": "user", "content": [
+                {"type": "text", "text": "Rate this card"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,cG5n", "detail": "low"}},
+                {"type": "image_url", "image_url": {"url": "https://example.com/card.webp"}}
+            ]})
+        );
+        let message: ChatCompletionMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(message.content, content);
+        assert_eq!(content.text(), "Rate this card");
+        assert_eq!(content.images().filter(|image| image.is_inline()).count(), 1);
+        assert_ne!(content, "Rate this card");
+        assert!(!MessageContent::Parts(vec![ContentPart::ImageUrl {
+            image_url: ImageUrl::new("https://example.com/card.webp")
+        }])
+        .is_empty());
+    }
+}
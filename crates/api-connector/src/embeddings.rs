@@ -0,0 +1,144 @@
+use crate::cassette::fnv1a;
+use crate::chat::Usage;
+use crate::prelude::*;
+use std::future::Future;
+
+/// Number of inputs sent in a single request when [`EmbeddingOptions::batch_size`] is not set.
+pub const DEFAULT_EMBEDDING_BATCH_SIZE: usize = 256;
+
+/// A text embedding backend.
+///
+/// [`crate::openai::OpenAIConnector`] implements it for the OpenAI API (`/v1/embeddings`) and
+/// every compatible server.
+pub trait EmbeddingProvider: Send + Sync {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// The model used when the options do not name one.
+    fn default_embedding_model(&self) -> &str;
+
+    /// Embed every input, vectors are returned in the order of `input`.
+    fn embed<'a>(
+        &'a self,
+        input: &'a [String],
+        options: &'a EmbeddingOptions,
+    ) -> impl Future<Output = std::result::Result<Embeddings, Self::Error>> + Send + 'a;
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EmbeddingOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Size of the returned vectors, only supported by the `text-embedding-3` models.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Inputs sent per request, see [`DEFAULT_EMBEDDING_BATCH_SIZE`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_size: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EmbeddingRequest<'a> {
+    pub model: &'a str,
+    pub input: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<&'a str>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EmbeddingResponse {
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EmbeddingData {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+/// The vectors of a whole (batched) embedding call.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Embeddings {
+    pub model: String,
+    pub vectors: Vec<Vec<f32>>,
+    pub usage: Usage,
+}
+
+impl Embeddings {
+    /// Append a batch response, its vectors are put back in the order of the batch input.
+    pub fn push(&mut self, mut response: EmbeddingResponse) {
+        response.data.sort_by_key(|data| data.index);
+        self.model = response.model;
+        self.vectors.extend(response.data.into_iter().map(|data| data.embedding));
+        if let Some(usage) = response.usage {
+            self.usage += usage;
+        }
+    }
+}
+
+/// Deterministic embeddings computed locally by hashing words (feature hashing): texts sharing
+/// words get similar vectors. No server is needed, it stands in for a real model in tests and
+/// offline runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashingEmbedder {
+    dimensions: usize,
+}
+
+impl HashingEmbedder {
+    pub const MODEL: &'static str = "hashing";
+
+    pub fn new(dimensions: usize) -> Self {
+        Self {
+            dimensions: dimensions.max(1),
+        }
+    }
+
+    /// The (normalized) vector of `text`, of `dimensions` values.
+    pub fn embed_text(&self, text: &str, dimensions: usize) -> Vec<f32> {
+        let dimensions = dimensions.max(1);
+        let mut vector = vec![0.0f32; dimensions];
+        for word in text.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+            let hash = fnv1a(word.to_lowercase().as_bytes());
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(hash % dimensions as u64) as usize] += sign;
+        }
+        let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|value| *value /= norm);
+        }
+        vector
+    }
+}
+
+impl Default for HashingEmbedder {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+impl EmbeddingProvider for HashingEmbedder {
+    type Error = std::convert::Infallible;
+
+    fn default_embedding_model(&self) -> &str {
+        Self::MODEL
+    }
+
+    async fn embed<'a>(
+        &'a self,
+        input: &'a [String],
+        options: &'a EmbeddingOptions,
+    ) -> std::result::Result<Embeddings, Self::Error> {
+        let dimensions = options.dimensions.map_or(self.dimensions, |dimensions| dimensions as usize);
+        Ok(Embeddings {
+            model: Self::MODEL.to_string(),
+            vectors: input.iter().map(|text| self.embed_text(text, dimensions)).collect(),
+            usage: Usage::default(),
+        })
+    }
+}
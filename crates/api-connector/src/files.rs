@@ -0,0 +1,48 @@
+use crate::prelude::*;
+
+/// Use of an uploaded file.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilePurpose {
+    /// Input of a batch job.
+    Batch,
+    /// Output or error file of a batch job.
+    BatchOutput,
+    #[serde(rename = "fine-tune")]
+    FineTune,
+    Assistants,
+    Vision,
+    #[serde(other)]
+    Other,
+}
+
+/// A file stored by the Files API.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FileObject {
+    pub id: String,
+    #[serde(default)]
+    pub bytes: u64,
+    /// Seconds since the UNIX epoch.
+    #[serde(default)]
+    pub created_at: u64,
+    pub filename: String,
+    pub purpose: FilePurpose,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FileList {
+    pub data: Vec<FileObject>,
+}
+
+impl FilePurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Batch => "batch",
+            Self::BatchOutput => "batch_output",
+            Self::FineTune => "fine-tune",
+            Self::Assistants => "assistants",
+            Self::Vision => "vision",
+            Self::Other => "other",
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+/// A text to image backend.
+///
+/// Implemented by [`crate::stable_diffusion::StableDiffusionConnector`] (hosted API, images are
+/// polled and downloaded) and [`crate::automatic1111::Automatic1111Connector`] (self-hosted,
+/// images are returned inline).
+pub trait ImageGenerator: Send + Sync {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Generate the images of `request` with the backend's profile, once they are ready.
+    fn generate<'a>(
+        &'a self,
+        request: &'a ImageRequest,
+    ) -> impl Future<Output = std::result::Result<Vec<Image>, Self::Error>> + Send + 'a;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageRequest {
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    pub seed: Option<u64>,
+}
+
+/// An encoded image.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Image {
+    pub data: Vec<u8>,
+    pub mime: String,
+}
+
+impl Image {
+    pub fn new(data: Vec<u8>, mime: impl Into<String>) -> Self {
+        Self {
+            data,
+            mime: mime.into(),
+        }
+    }
+
+    /// File extension of the image (`bin` for unknown types).
+    pub fn extension(&self) -> &'static str {
+        mime_extension(&self.mime).unwrap_or("bin")
+    }
+}
+
+impl std::fmt::Debug for Image {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Image")
+            .field("mime", &self.mime)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+/// Save `images` in `directory` (created if needed) as `<name>-<index>.<extension>`, returns
+/// the written files.
+pub async fn save_images(images: &[Image], directory: &Path, name: &str) -> std::io::Result<Vec<PathBuf>> {
+    tokio::fs::create_dir_all(directory).await?;
+    let mut paths = Vec::with_capacity(images.len());
+    for (index, image) in images.iter().enumerate() {
+        let path = directory.join(format!("{}-{}.{}", name, index, image.extension()));
+        tokio::fs::write(&path, &image.data).await?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// File extension of an image content type (parameters such as `; charset` are ignored).
+pub fn mime_extension(mime: &str) -> Option<&'static str> {
+    let mime = mime.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    match mime.as_str() {
+        "image/png" => Some("png"),
+        "image/jpeg" | "image/jpg" => Some("jpg"),
+        "image/webp" => Some("webp"),
+        "image/gif" => Some("gif"),
+        "image/bmp" => Some("bmp"),
+        "image/tiff" => Some("tiff"),
+        _ => None,
+    }
+}
+
+/// Content type of an image file extension.
+pub fn extension_mime(extension: &str) -> Option<&'static str> {
+    match extension.to_ascii_lowercase().as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "webp" => Some("image/webp"),
+        "gif" => Some("image/gif"),
+        "bmp" => Some("image/bmp"),
+        "tif" | "tiff" => Some("image/tiff"),
+        _ => None,
+    }
+}
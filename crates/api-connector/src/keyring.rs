@@ -1,25 +1,463 @@
 use std::env;
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use thiserror::Error;
 use crate::prelude::*;
 
+/// Profile of the environment keys, and fallback of the other profiles.
+pub const DEFAULT_PROFILE: &str = "default";
+/// Environment variable selecting the key profile.
+pub const PROFILE_ENV: &str = "ENTROPY_KEY_PROFILE";
+/// Environment variable holding the password of the encrypted key file.
+pub const PASSWORD_ENV: &str = "ENTROPY_KEYS_PASSWORD";
+/// How long a rate limited key is left aside when the API gives no delay.
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
+
+const ENCRYPTED_HEADER: &[u8] = b"entropy-keys-v1\n";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("API key not found in keychain: `{}` (profile `{}`)", name, profile)]
+    NotFound { name: String, profile: String },
+    #[error("IO error: {}", _0)]
+    Io(#[from] std::io::Error),
+    #[error("YAML error: {}", _0)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("{} is encrypted, set `{}` to unlock it", _0.display(), PASSWORD_ENV)]
+    PasswordRequired(PathBuf),
+    #[error("cannot decrypt the key file (wrong password or corrupted file)")]
+    Decryption,
+    #[error("invalid key file: {}", _0)]
+    InvalidKeyFile(String),
+}
+
+pub type Result<T> = std::result::Result<T, KeyError>;
+
+/// API keys by profile and provider (`OPENAI`, `STABLE_DIFFUSION`...), layered from the
+/// environment, `~/.config/entropy/keys.yaml` and the encrypted `~/.config/entropy/keys.enc`
+/// (see [`KeyChain::load`]).
+///
+/// A provider can have several keys: they are used one after the other ([`Rotation`]), rate
+/// limited keys being left aside until their cooldown is over. Clones share the keys.
+#[derive(Clone)]
 pub struct KeyChain {
-    keys: Arc<RwLock<HashMap<String, String>>>,
+    keys: Arc<Mutex<HashMap<String, HashMap<String, ProviderKeys>>>>,
+    profile: String,
+    rotation: Rotation,
+}
+
+/// Which key of a provider is used for the next request.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rotation {
+    /// The first available key, the next ones are only used while it is rate limited.
+    #[default]
+    Failover,
+    /// Every available key in turn.
+    RoundRobin,
+}
+
+/// A key, with the organization it is billed to. Written as a plain string in key files when
+/// there is no organization.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(from = "ApiKeyEntry")]
+pub struct ApiKey {
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ApiKeyEntry {
+    Key(String),
+    Full { key: String, organization: Option<String> },
+}
+
+/// The keys of a provider, a single key or a list of keys in key files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(from = "OneOrMany")]
+pub struct ApiKeys(pub Vec<ApiKey>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(ApiKey),
+    Many(Vec<ApiKey>),
+}
+
+/// Content of `keys.yaml` (and of the encrypted key file):
+///
+/// ```yaml
+/// profile: work
+/// rotation: round-robin
+/// profiles:
+///   default:
+///     STABLE_DIFFUSION: sd-...
+///   work:
+///     OPENAI:
+///       - key: sk-...
+///         organization: org-...
+///       - sk-...
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct KeyFile {
+    /// The profile used when [`PROFILE_ENV`] is not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<Rotation>,
+    pub profiles: HashMap<String, HashMap<String, ApiKeys>>,
+}
+
+struct ProviderKeys {
+    keys: Vec<ApiKey>,
+    cooldowns: Vec<Option<Instant>>,
+    next: usize,
 }
 
 impl KeyChain {
+    pub fn new() -> Self {
+        Self {
+            keys: Arc::new(Mutex::new(HashMap::new())),
+            profile: DEFAULT_PROFILE.to_string(),
+            rotation: Rotation::default(),
+        }
+    }
+
+    /// The `<NAME>_API_KEY` (one key) and `<NAME>_API_KEYS` (comma separated keys) variables.
     pub fn from_env() -> Self {
-        let keys = env::vars()
-            .into_iter()
-            .filter(|(name, _)| name.ends_with("_API_KEY"))
-            .map(|(name, value)| {
-                let name = name.trim_end_matches("_API_KEY");
-                (name.to_string(), value)
-            })
-            .collect::<HashMap<String, String>>();
-        Self { keys: Arc::new(RwLock::new(keys)) }
+        let keychain = Self::new();
+        keychain.merge(env_keys());
+        keychain
+    }
+
+    /// The keys of the default configuration directory, see [`KeyChain::load_from`].
+    pub fn load() -> Result<Self> {
+        match config_dir() {
+            Some(directory) => Self::load_from(&directory),
+            None => Ok(Self::from_env().with_profile_from_env()),
+        }
+    }
+
+    /// Layer the keys of `directory/keys.enc` (decrypted with [`PASSWORD_ENV`]), then
+    /// `directory/keys.yaml`, then the environment: a provider's keys are taken from the last
+    /// layer defining them. Missing files are skipped.
+    pub fn load_from(directory: &Path) -> Result<Self> {
+        let mut keychain = Self::new();
+        let encrypted = directory.join("keys.enc");
+        if encrypted.exists() {
+            let password = env::var(PASSWORD_ENV).map_err(|_| KeyError::PasswordRequired(encrypted.clone()))?;
+            keychain = keychain.with_key_file(KeyFile::read_encrypted(&encrypted, &password)?);
+        }
+        let plain = directory.join("keys.yaml");
+        if plain.exists() {
+            keychain = keychain.with_key_file(KeyFile::read(&plain)?);
+        }
+        keychain.merge(env_keys());
+        Ok(keychain.with_profile_from_env())
+    }
+
+    /// Add the keys of `file`, its profile and rotation replace the current ones.
+    pub fn with_key_file(mut self, file: KeyFile) -> Self {
+        if let Some(profile) = &file.profile {
+            self.profile = profile.clone();
+        }
+        if let Some(rotation) = file.rotation {
+            self.rotation = rotation;
+        }
+        self.merge(file);
+        self
+    }
+
+    /// Use the keys of `profile`, falling back to the [`DEFAULT_PROFILE`] ones.
+    pub fn with_profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = profile.into();
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    fn with_profile_from_env(self) -> Self {
+        match env::var(PROFILE_ENV) {
+            Ok(profile) if !profile.is_empty() => self.with_profile(profile),
+            _ => self,
+        }
+    }
+
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// Add the keys of `file`, replacing the providers it defines.
+    pub fn merge(&self, file: KeyFile) {
+        let mut keys = self.keys.lock().unwrap();
+        for (profile, providers) in file.profiles {
+            let profile = keys.entry(profile).or_default();
+            for (name, ApiKeys(api_keys)) in providers {
+                profile.insert(name, ProviderKeys::new(api_keys));
+            }
+        }
+    }
+
+    /// The key to use for the next request to `name`, following the rotation.
+    pub fn api_key(&self, name: &str) -> Result<ApiKey> {
+        let rotation = self.rotation;
+        self.with_provider(name, |provider| provider.select(rotation))
+    }
+
+    /// Every key of `name`.
+    pub fn api_keys(&self, name: &str) -> Result<Vec<ApiKey>> {
+        self.with_provider(name, |provider| provider.keys.clone())
+    }
+
+    /// Leave `key` aside for `retry_after` (or [`DEFAULT_COOLDOWN`]).
+    pub fn report_rate_limited(&self, name: &str, key: &ApiKey, retry_after: Option<Duration>) {
+        let until = Instant::now() + retry_after.unwrap_or(DEFAULT_COOLDOWN);
+        let _ = self.with_provider(name, |provider| {
+            if let Some(index) = provider.keys.iter().position(|k| k == key) {
+                provider.cooldowns[index] = Some(until);
+            }
+        });
     }
 
     pub fn get_api_key(&self, name: &str) -> Option<String> {
-        self.keys.read().unwrap().get(name).cloned()
+        self.api_key(name).ok().map(|api_key| api_key.key)
+    }
+
+    /// Set the only key of `name` in the current profile.
+    pub fn set_api_key(&self, name: &str, key: String) {
+        let api_key = ApiKey { key, organization: None };
+        self.keys
+            .lock()
+            .unwrap()
+            .entry(self.profile.clone())
+            .or_default()
+            .insert(name.to_string(), ProviderKeys::new(vec![api_key]));
+    }
+
+    fn with_provider<T>(&self, name: &str, f: impl FnOnce(&mut ProviderKeys) -> T) -> Result<T> {
+        let mut keys = self.keys.lock().unwrap();
+        let has_keys = |profile: &str, keys: &HashMap<String, HashMap<String, ProviderKeys>>| {
+            keys.get(profile)
+                .and_then(|providers| providers.get(name))
+                .is_some_and(|provider| !provider.keys.is_empty())
+        };
+        let profile = match has_keys(&self.profile, &keys) {
+            true => self.profile.as_str(),
+            false => DEFAULT_PROFILE,
+        };
+        match keys.get_mut(profile).and_then(|providers| providers.get_mut(name)) {
+            Some(provider) if !provider.keys.is_empty() => Ok(f(provider)),
+            _ => Err(KeyError::NotFound {
+                name: name.to_string(),
+                profile: self.profile.clone(),
+            }),
+        }
+    }
+}
+
+impl Default for KeyChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for KeyChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyChain")
+            .field("profile", &self.profile)
+            .field("rotation", &self.rotation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProviderKeys {
+    fn new(keys: Vec<ApiKey>) -> Self {
+        Self {
+            cooldowns: vec![None; keys.len()],
+            keys,
+            next: 0,
+        }
+    }
+
+    /// The next key not rate limited, or the one available first when all are.
+    fn select(&mut self, rotation: Rotation) -> ApiKey {
+        let now = Instant::now();
+        let start = match rotation {
+            Rotation::Failover => 0,
+            Rotation::RoundRobin => self.next,
+        };
+        let count = self.keys.len();
+        let index = (0..count)
+            .map(|offset| (start + offset) % count)
+            .find(|index| self.cooldowns[*index].is_none_or(|until| until <= now))
+            .unwrap_or_else(|| {
+                (0..count)
+                    .min_by_key(|index| self.cooldowns[*index])
+                    .unwrap_or_default()
+            });
+        self.next = (index + 1) % count;
+        self.keys[index].clone()
+    }
+}
+
+impl From<ApiKeyEntry> for ApiKey {
+    fn from(entry: ApiKeyEntry) -> Self {
+        match entry {
+            ApiKeyEntry::Key(key) => Self { key, organization: None },
+            ApiKeyEntry::Full { key, organization } => Self { key, organization },
+        }
+    }
+}
+
+impl From<OneOrMany> for ApiKeys {
+    fn from(keys: OneOrMany) -> Self {
+        match keys {
+            OneOrMany::One(key) => Self(vec![key]),
+            OneOrMany::Many(keys) => Self(keys),
+        }
+    }
+}
+
+impl KeyFile {
+    pub fn read(path: &Path) -> Result<Self> {
+        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Read a file written by [`KeyFile::write_encrypted`].
+    pub fn read_encrypted(path: &Path, password: &str) -> Result<Self> {
+        Self::decrypt(&std::fs::read(path)?, password)
+    }
+
+    pub fn write_encrypted(&self, path: &Path, password: &str) -> Result<()> {
+        Ok(std::fs::write(path, self.encrypt(password)?)?)
+    }
+
+    /// The YAML file encrypted with ChaCha20-Poly1305, the key is derived from `password`
+    /// with Argon2id.
+    pub fn encrypt(&self, password: &str) -> Result<Vec<u8>> {
+        let salt = rand::random::<[u8; SALT_LEN]>();
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let cipher = ChaCha20Poly1305::new(&derive_key(password, &salt)?);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), serde_yaml::to_string(self)?.as_bytes())
+            .map_err(|_| KeyError::InvalidKeyFile("encryption failed".to_string()))?;
+        Ok([ENCRYPTED_HEADER, &salt, &nonce, &ciphertext].concat())
+    }
+
+    pub fn decrypt(data: &[u8], password: &str) -> Result<Self> {
+        let data = data
+            .strip_prefix(ENCRYPTED_HEADER)
+            .filter(|data| data.len() > SALT_LEN + NONCE_LEN)
+            .ok_or_else(|| KeyError::InvalidKeyFile("not an encrypted key file".to_string()))?;
+        let (salt, data) = data.split_at(SALT_LEN);
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let cipher = ChaCha20Poly1305::new(&derive_key(password, salt)?);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| KeyError::Decryption)?;
+        Ok(serde_yaml::from_slice(&plaintext)?)
+    }
+}
+
+fn derive_key(password: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| KeyError::InvalidKeyFile(e.to_string()))?;
+    Ok(key)
+}
+
+/// `$XDG_CONFIG_HOME/entropy`, or `~/.config/entropy`.
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|directory| !directory.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|directory| directory.join("entropy"))
+}
+
+fn env_keys() -> KeyFile {
+    let mut providers = HashMap::new();
+    for (name, value) in env::vars() {
+        let (name, keys): (&str, Vec<String>) = if let Some(name) = name.strip_suffix("_API_KEYS") {
+            (name, value.split(',').map(str::trim).filter(|key| !key.is_empty()).map(String::from).collect())
+        } else if let Some(name) = name.strip_suffix("_API_KEY") {
+            (name, vec![value])
+        } else {
+            continue;
+        };
+        let keys = keys.into_iter().map(|key| ApiKey { key, organization: None });
+        providers.entry(name.to_string()).or_insert_with(|| ApiKeys(Vec::new())).0.extend(keys);
+    }
+    KeyFile {
+        profiles: HashMap::from([(DEFAULT_PROFILE.to_string(), providers)]),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles_and_rotation() {
+        let file: KeyFile = serde_yaml::from_str(
+            "profile: work\nprofiles:\n  default:\n    STABLE_DIFFUSION: sd-1\n    OPENAI: sk-personal\n  work:\n    OPENAI:\n      - key: sk-a\n        organization: org-1\n      - sk-b\n",
+        )
+        .unwrap();
+        let keychain = KeyChain::new().with_key_file(file.clone());
+        assert_eq!(keychain.profile(), "work");
+        assert_eq!(keychain.get_api_key("STABLE_DIFFUSION").as_deref(), Some("sd-1"));
+        let first = keychain.api_key("OPENAI").unwrap();
+        assert_eq!(first.organization.as_deref(), Some("org-1"));
+        assert_eq!(keychain.api_key("OPENAI").unwrap(), first);
+
+        keychain.report_rate_limited("OPENAI", &first, Some(Duration::from_secs(60)));
+        assert_eq!(keychain.get_api_key("OPENAI").as_deref(), Some("sk-b"));
+        let second = keychain.api_key("OPENAI").unwrap();
+        keychain.report_rate_limited("OPENAI", &second, Some(Duration::from_secs(30)));
+        assert_eq!(keychain.get_api_key("OPENAI").as_deref(), Some("sk-b"));
+
+        let keychain = KeyChain::new().with_key_file(file).with_rotation(Rotation::RoundRobin);
+        let keys = (0..3).map(|_| keychain.get_api_key("OPENAI").unwrap()).collect::<Vec<_>>();
+        assert_eq!(keys, ["sk-a", "sk-b", "sk-a"]);
+        let personal = keychain.clone().with_profile(DEFAULT_PROFILE);
+        assert_eq!(personal.get_api_key("OPENAI").as_deref(), Some("sk-personal"));
+        assert!(matches!(
+            keychain.api_key("HUGGING_FACE"),
+            Err(KeyError::NotFound { name, profile }) if name == "HUGGING_FACE" && profile == "work"
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_key_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("keys.enc");
+        let file = KeyFile {
+            rotation: Some(Rotation::RoundRobin),
+            profiles: HashMap::from([(
+                DEFAULT_PROFILE.to_string(),
+                HashMap::from([("OPENAI".to_string(), ApiKeys(vec![ApiKey { key: "sk-secret".to_string(), organization: None }]))]),
+            )]),
+            ..Default::default()
+        };
+        file.write_encrypted(&path, "correct horse").unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&data).contains("sk-secret"));
+        assert_eq!(KeyFile::read_encrypted(&path, "correct horse").unwrap(), file);
+        assert!(matches!(KeyFile::read_encrypted(&path, "wrong"), Err(KeyError::Decryption)));
+        assert!(matches!(KeyFile::decrypt(b"sk-secret", "correct horse"), Err(KeyError::InvalidKeyFile(_))));
     }
 }
@@ -1,5 +1,22 @@
 pub mod prelude;
+pub mod audio;
+pub mod automatic1111;
+pub mod batch;
+pub mod batch_api;
+pub mod cache;
+pub mod cassette;
+pub mod chat;
+pub mod embeddings;
 pub mod error;
+pub mod files;
+pub mod image_generator;
 pub mod keyring;
+pub mod middleware;
+pub mod moderation;
 pub mod stable_diffusion;
 pub mod openai;
+pub mod retry;
+pub mod sse;
+pub mod text_to_image;
+pub mod tokenizer;
+pub mod webhook;
@@ -0,0 +1,352 @@
+use crate::cache::CACHE_HEADER;
+use crate::cassette::{Cassette, CassetteError};
+use crate::prelude::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MiddlewareError {
+    #[error("IO error: {}", _0)]
+    IoError(#[from] std::io::Error),
+    #[error("reqwest error: {}", _0)]
+    HttpClientError(#[from] reqwest::Error),
+    #[error("cassette error: {}", _0)]
+    Cassette(#[from] CassetteError),
+    #[error("invalid header: {}", _0)]
+    InvalidHeader(String),
+    #[error("invalid setting: {}", _0)]
+    InvalidSetting(String),
+    #[error("{}", _0)]
+    Custom(Box<dyn std::error::Error + Send + Sync>),
+}
+
+pub type Result<T> = std::result::Result<T, MiddlewareError>;
+
+pub type MiddlewareFuture<'a> = Pin<Box<dyn Future<Output = Result<reqwest::Response>> + Send + 'a>>;
+
+/// A layer wrapped around the HTTP exchanges of a connector, it may change the request, inspect
+/// the response or answer without calling `next`.
+///
+/// Layers run once per attempt, inside the connector's retry loop and outside its cassette.
+pub trait Middleware: Send + Sync {
+    fn handle<'a>(&'a self, exchange: Exchange, next: Next<'a>) -> MiddlewareFuture<'a>;
+}
+
+/// A request on its way to the server.
+#[derive(Debug)]
+pub struct Exchange {
+    /// `openai`, `stable_diffusion` or `automatic1111`.
+    pub connector: &'static str,
+    pub request: reqwest::Request,
+    /// JSON view of the request body (the form fields of a multipart request), `Null` when it
+    /// has none.
+    pub body: serde_json::Value,
+}
+
+/// The rest of the chain, ending with the transport.
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    client: &'a Client,
+    cassette: Option<&'a Cassette>,
+}
+
+/// The middlewares of a connector, the first one registered is the outermost.
+#[derive(Clone, Default)]
+pub struct Middlewares(Vec<Arc<dyn Middleware>>);
+
+impl<'a> Next<'a> {
+    pub fn run(self, exchange: Exchange) -> MiddlewareFuture<'a> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => middleware.handle(exchange, Next { middlewares, ..self }),
+            None => Box::pin(async move {
+                let method = exchange.request.method().to_string();
+                let url = exchange.request.url().to_string();
+                let builder = reqwest::RequestBuilder::from_parts(self.client.clone(), exchange.request);
+                Ok(match self.cassette {
+                    Some(cassette) => cassette.send(&method, &url, &exchange.body, builder).await?,
+                    None => builder.send().await?,
+                })
+            }),
+        }
+    }
+}
+
+impl Middlewares {
+    pub fn push(&mut self, middleware: impl Middleware + 'static) {
+        self.0.push(Arc::new(middleware));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Send `builder` through the chain, then through `cassette` (if any).
+    pub(crate) async fn send<T: Serialize>(
+        &self,
+        connector: &'static str,
+        client: &Client,
+        cassette: Option<&Cassette>,
+        builder: reqwest::RequestBuilder,
+        body: &T,
+    ) -> Result<reqwest::Response> {
+        let exchange = Exchange {
+            connector,
+            request: builder.build()?,
+            body: serde_json::to_value(body).unwrap_or_default(),
+        };
+        let next = Next {
+            middlewares: &self.0,
+            client,
+            cassette,
+        };
+        next.run(exchange).await
+    }
+}
+
+impl std::fmt::Debug for Middlewares {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Middlewares({})", self.0.len())
+    }
+}
+
+/// Append a JSON line per exchange to a file: time, connector, method, URL, request body,
+/// status (or error) and duration. Headers are never logged.
+pub struct JsonlLogger {
+    file: Mutex<File>,
+    redacted_fields: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Milliseconds since the UNIX epoch.
+    pub time: u64,
+    pub connector: String,
+    pub method: String,
+    pub url: String,
+    pub request: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// `hit` or `miss` when the exchange went through a [`crate::cache::ResponseCache`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl JsonlLogger {
+    /// Append to `path`, the API key sent in the Stable Diffusion bodies (`key`) is redacted.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            redacted_fields: vec!["key".to_string()],
+        })
+    }
+
+    /// Also redact the body fields named `field` (e.g. `messages`).
+    pub fn with_redacted_field(mut self, field: impl Into<String>) -> Self {
+        self.redacted_fields.push(field.into());
+        self
+    }
+
+    fn redact(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (name, value) in map.iter_mut() {
+                    if self.redacted_fields.contains(name) {
+                        *value = serde_json::Value::String("[redacted]".to_string());
+                    } else {
+                        self.redact(value);
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(|value| self.redact(value)),
+            _ => {}
+        }
+    }
+
+    fn write(&self, entry: &LogEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry).map_err(std::io::Error::from)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+impl Middleware for JsonlLogger {
+    fn handle<'a>(&'a self, exchange: Exchange, next: Next<'a>) -> MiddlewareFuture<'a> {
+        Box::pin(async move {
+            let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            let method = exchange.request.method().to_string();
+            let url = exchange.request.url().to_string();
+            let mut request = exchange.body.clone();
+            self.redact(&mut request);
+            let connector = exchange.connector.to_string();
+            let start = Instant::now();
+            let result = next.run(exchange).await;
+            let entry = LogEntry {
+                time,
+                connector,
+                method,
+                url,
+                request,
+                status: result.as_ref().ok().map(|response| response.status().as_u16()),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                cache: result
+                    .as_ref()
+                    .ok()
+                    .and_then(|response| response.headers().get(CACHE_HEADER)?.to_str().ok())
+                    .map(str::to_string),
+                duration_ms: start.elapsed().as_millis() as u64,
+            };
+            self.write(&entry)?;
+            result
+        })
+    }
+}
+
+/// Record how long each exchange took, until the response headers are received (a streamed
+/// body is not waited for). Clones share the records.
+#[derive(Clone, Debug, Default)]
+pub struct TimingLayer {
+    timings: Arc<Mutex<Vec<Timing>>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timing {
+    pub connector: &'static str,
+    pub url: String,
+    /// `None` when no response was received.
+    pub status: Option<u16>,
+    pub duration: Duration,
+}
+
+impl TimingLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn timings(&self) -> Vec<Timing> {
+        self.timings.lock().unwrap().clone()
+    }
+
+    pub fn total(&self) -> Duration {
+        self.timings.lock().unwrap().iter().map(|timing| timing.duration).sum()
+    }
+
+    pub fn clear(&self) {
+        self.timings.lock().unwrap().clear();
+    }
+}
+
+impl Middleware for TimingLayer {
+    fn handle<'a>(&'a self, exchange: Exchange, next: Next<'a>) -> MiddlewareFuture<'a> {
+        Box::pin(async move {
+            let connector = exchange.connector;
+            let url = exchange.request.url().to_string();
+            let start = Instant::now();
+            let result = next.run(exchange).await;
+            self.timings.lock().unwrap().push(Timing {
+                connector,
+                url,
+                status: result.as_ref().ok().map(|response| response.status().as_u16()),
+                duration: start.elapsed(),
+            });
+            result
+        })
+    }
+}
+
+/// Set headers on every request (e.g. `OpenAI-Organization` or a proxy's token), replacing the
+/// ones set by the connector.
+#[derive(Clone, Debug, Default)]
+pub struct HeadersLayer {
+    headers: HeaderMap,
+}
+
+impl HeadersLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<Self> {
+        let name = HeaderName::try_from(name).map_err(|e| MiddlewareError::InvalidHeader(format!("{}: {}", name, e)))?;
+        let value = HeaderValue::try_from(value).map_err(|e| MiddlewareError::InvalidHeader(format!("{}: {}", name, e)))?;
+        self.headers.insert(name, value);
+        Ok(self)
+    }
+}
+
+impl Middleware for HeadersLayer {
+    fn handle<'a>(&'a self, mut exchange: Exchange, next: Next<'a>) -> MiddlewareFuture<'a> {
+        for (name, value) in self.headers.iter() {
+            exchange.request.headers_mut().insert(name, value.clone());
+        }
+        next.run(exchange)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers the `/canned` requests without reaching the server.
+    struct Canned;
+
+    impl Middleware for Canned {
+        fn handle<'a>(&'a self, exchange: Exchange, next: Next<'a>) -> MiddlewareFuture<'a> {
+            if exchange.request.url().path() != "/canned" {
+                return next.run(exchange);
+            }
+            Box::pin(async move { Ok(reqwest::Response::from(http::Response::new("canned"))) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middlewares() {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::header("x-team", "entropy"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("served"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let directory = tempfile::tempdir().unwrap();
+        let log = directory.path().join("requests.jsonl");
+        let timing = TimingLayer::new();
+        let mut middlewares = Middlewares::default();
+        middlewares.push(JsonlLogger::open(&log).unwrap());
+        middlewares.push(timing.clone());
+        middlewares.push(HeadersLayer::new().with_header("X-Team", "entropy").unwrap());
+        middlewares.push(Canned);
+        let client = Client::new();
+
+        for (path, expected) in [("served", "served"), ("canned", "canned")] {
+            let builder = client.post(format!("{}/{}", server.uri(), path));
+            let body = serde_json::json!({"key": "secret", "prompt": "a cat"});
+            let response = middlewares.send("test", &client, None, builder, &body).await.unwrap();
+            assert_eq!(response.text().await.unwrap(), expected);
+        }
+
+        let entries = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<LogEntry>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].status, Some(200));
+        assert_eq!(entries[0].request, serde_json::json!({"key": "[redacted]", "prompt": "a cat"}));
+        assert_eq!(timing.timings().len(), 2);
+        assert!(HeadersLayer::new().with_header("bad header", "value").is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use crate::prelude::*;
+use std::collections::BTreeMap;
+use std::future::Future;
+
+/// A content moderation backend.
+///
+/// [`crate::openai::OpenAIConnector`] implements it for the OpenAI API (`/v1/moderations`).
+pub trait ModerationProvider: Send + Sync {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Classify every input, results are returned in the order of `input`.
+    fn moderate<'a>(
+        &'a self,
+        input: &'a [String],
+    ) -> impl Future<Output = std::result::Result<Vec<ModerationResult>, Self::Error>> + Send + 'a;
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModerationRequest<'a> {
+    pub input: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<&'a str>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModerationResponse {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub model: String,
+    pub results: Vec<ModerationResult>,
+}
+
+/// Categories (`hate`, `self-harm/intent`...) of an input.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    #[serde(default)]
+    pub categories: BTreeMap<String, bool>,
+    /// From `0.0` to `1.0`.
+    #[serde(default)]
+    pub category_scores: BTreeMap<String, f64>,
+}
+
+impl ModerationResult {
+    /// The categories flagged by the API, plus the ones scored at least `threshold`.
+    pub fn flagged_categories(&self, threshold: Option<f64>) -> Vec<&str> {
+        let mut categories = self
+            .categories
+            .iter()
+            .filter(|(_, flagged)| **flagged)
+            .map(|(category, _)| category.as_str())
+            .collect::<Vec<_>>();
+        if let Some(threshold) = threshold {
+            for (category, score) in self.category_scores.iter() {
+                if *score >= threshold && !categories.contains(&category.as_str()) {
+                    categories.push(category);
+                }
+            }
+            categories.sort_unstable();
+        }
+        categories
+    }
+}
@@ -1,7 +1,26 @@
-use crate::{keyring::KeyChain, prelude::*};
-use reqwest::StatusCode;
+use crate::{
+    audio::*,
+    batch_api::*,
+    cassette::{Cassette, CassetteError},
+    embeddings::*,
+    files::*,
+    keyring::{KeyChain, KeyError},
+    middleware::{Middleware, MiddlewareError, Middlewares},
+    moderation::*,
+    prelude::*,
+    retry::{RateLimitInfo, RateLimitState, RetryPolicy, Retryable},
+    sse::SseDecoder,
+    stable_diffusion::PollOptions,
+};
+use futures_util::{Stream, StreamExt};
+use reqwest::{header::HeaderMap, Method, StatusCode};
+use std::path::Path;
+use std::pin::Pin;
+use std::time::Duration;
 use thiserror::Error;
 
+pub use crate::chat::*;
+
 #[derive(Debug, Error)]
 pub enum OpenAIError {
     #[error("IO error: {}", _0)]
@@ -10,12 +29,53 @@ pub enum OpenAIError {
     JsonError(#[from] serde_json::Error),
     #[error("reqwest error: {}", _0)]
     HttpClientError(#[from] reqwest::Error),
+    #[error("cassette error: {}", _0)]
+    Cassette(#[from] CassetteError),
     #[error("completion failed ({:?}): {} (http status: {})", code, message, status)]
     CompletionFailed {
         message: String,
         code: Option<String>,
         status: StatusCode,
     },
+    #[error("authentication failed: {} (http status: {})", message, status)]
+    Unauthorized { message: String, status: StatusCode },
+    #[error("quota exceeded: {}", message)]
+    QuotaExceeded { message: String },
+    #[error("context length exceeded: {}", message)]
+    ContextLengthExceeded { message: String },
+    #[error("rate limited: {}", message)]
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("server error: {} (http status: {})", message, status)]
+    ServerError {
+        message: String,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    #[error("invalid response: {}", _0)]
+    InvalidResponse(String),
+    #[error("keychain error: {}", _0)]
+    Key(#[from] KeyError),
+    #[error("audio file too large: {} bytes (max {})", size, max)]
+    AudioTooLarge { size: u64, max: u64 },
+    #[error("batch {} still running after {:?}", id, elapsed)]
+    BatchTimeout { id: String, elapsed: Duration },
+    #[error("middleware error: {}", _0)]
+    Middleware(String),
+    #[error("invalid endpoint: {}", _0)]
+    InvalidEndpoint(String),
+}
+
+impl From<MiddlewareError> for OpenAIError {
+    fn from(error: MiddlewareError) -> Self {
+        match error {
+            MiddlewareError::HttpClientError(e) => Self::HttpClientError(e),
+            MiddlewareError::Cassette(e) => Self::Cassette(e),
+            error => Self::Middleware(error.to_string()),
+        }
+    }
 }
 
 pub (crate) type Result<T> = std::result::Result<T, OpenAIError>;
@@ -24,44 +84,55 @@ pub (crate) type Result<T> = std::result::Result<T, OpenAIError>;
 pub struct OpenAIConnector {
     profile: ChatGptProfile,
     client: Client,
-    api_key: String,
+    keychain: KeyChain,
+    retry_policy: RetryPolicy,
+    rate_limit: RateLimitState,
+    cassette: Option<Cassette>,
+    middlewares: Middlewares,
 }
-#[derive(Clone, Debug, Serialize)]
+
+/// Where and how to reach an OpenAI compatible chat completion API.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChatGptProfile {
-    #[serde(skip)]
+    #[serde(default = "default_api_endpoint")]
     pub api_endpoint: String,
-}
-
-#[derive(Clone, Serialize, Debug)]
-pub struct ChatCompletionRequest<'a> {
-    pub model: String,
-    pub messages: &'a [ChatCompletionMessage],
-    // todo: functions
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub functions: Option<&'a [ChatCompletionFunction]>,
-    // pub function_call: Option<serde_json::Value>,
-    pub temperature: Option<f64>,
-    pub top_p: Option<f64>,
-    pub n: Option<u64>,
-    pub stream: Option<bool>,
-    pub stop: Option<String>,
-    pub max_tokens: Option<u64>,
-    // pub presence_penalty: Option<f64>,
-    // pub frequency_penalty: Option<f64>,
-    // pub logit_bias: Option<serde_json::Value>,
-    // pub user: String,
-}
-
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ChatCompletionResponse {
-    pub id: String,
-    pub object: String,
-    pub created: u64,
+    /// The model used when the request does not name one.
+    #[serde(default = "default_model")]
     pub model: String,
-    pub choices: Vec<ChatCompletionChoice>,
-    pub usage: serde_json::Value,
+    /// Name of the keychain entry holding the API key, `None` for servers without authentication.
+    #[serde(default = "default_api_key_name")]
+    pub api_key_name: Option<String>,
+    /// Overrides the known context window of `model` (needed for local models).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<usize>,
+    /// Derived from `api_endpoint` (`.../chat/completions` becomes `.../embeddings`) when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embeddings_endpoint: Option<String>,
+    /// The embedding model used when the options do not name one.
+    #[serde(default = "default_embedding_model")]
+    pub embedding_model: String,
+    /// Derived from `api_endpoint` (`.../chat/completions` becomes `.../moderations`) when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moderations_endpoint: Option<String>,
+    /// Base of the `transcriptions` and `speech` endpoints, derived from `api_endpoint`
+    /// (`.../chat/completions` becomes `.../audio`) when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_endpoint: Option<String>,
+    #[serde(default = "default_transcription_model")]
+    pub transcription_model: String,
+    #[serde(default = "default_speech_model")]
+    pub speech_model: String,
+    /// Derived from `api_endpoint` (`.../chat/completions` becomes `.../files`) when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files_endpoint: Option<String>,
+    /// Derived from `api_endpoint` (`.../chat/completions` becomes `.../batches`) when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batches_endpoint: Option<String>,
+    /// Ask for the usage at the end of streamed completions (`stream_options`), some compatible
+    /// servers reject it. Only on for `api.openai.com` when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_usage: Option<bool>,
 }
- 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ChatCompletionError {
     pub error: ChatCompletionErrorContent,
@@ -70,112 +141,1006 @@ pub struct ChatCompletionError {
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct  ChatCompletionErrorContent {
     pub message: String,
-    pub code: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default, rename = "type")]
+    pub kind: Option<String>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ChatCompletionChoice {
-    pub message: ChatCompletionMessage,
-    pub index: u64,
-    pub finish_reason: String,
-}
+impl OpenAIConnector {
+    pub fn new(keychain: &KeyChain) -> std::result::Result<Self, KeyError> {
+        Self::from_profile(keychain, ChatGptProfile::default())
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct ChatCompletionMessage {
-    pub role: ChatCompletionRole,
-    pub content: String,
-}
+    /// Fails when the keychain has no key named by the profile.
+    pub fn from_profile(keychain: &KeyChain, profile: ChatGptProfile) -> std::result::Result<Self, KeyError> {
+        if let Some(name) = &profile.api_key_name {
+            keychain.api_keys(name)?;
+        }
+        Ok(Self {
+            profile,
+            client: Client::new(),
+            keychain: keychain.clone(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: RateLimitState::default(),
+            cassette: None,
+            middlewares: Middlewares::default(),
+        })
+    }
 
+    pub fn with_profile(mut self, profile: ChatGptProfile) -> Self {
+        self.profile = profile;
+        self
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct ChatCompletionFunction {
-    pub name: String,
-    pub description: String,
-    pub parameters: ChatCompletionFunctionParameters,
-    pub required: Vec<String>,
-}
+    pub fn profile(&self) -> &ChatGptProfile {
+        &self.profile
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct ChatCompletionFunctionParameters {
-    #[serde(rename = "type")]
-    pub kind: String,
-    pub properties: HashMap<String, ChatCompletionFunctionProperty>,
-}
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct ChatCompletionFunctionProperty {
-    #[serde(rename = "type")]
-    pub kind: String,
-    pub description: String,
-}
+    /// Record the exchanges to (or replay them from) `cassette`.
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
 
+    /// Run the HTTP exchanges through `middleware`, after the ones already registered.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "snake_case")]
-pub enum ChatCompletionRole {
-    System,
-    User,
-    Assistant,
-    Function,
-}
+    /// Rate limits reported by the last response.
+    pub fn rate_limit(&self) -> Option<RateLimitInfo> {
+        self.rate_limit.get()
+    }
 
-impl OpenAIConnector {
-    pub fn new(keychain: &KeyChain) -> Self {
-        Self {
-            profile: ChatGptProfile::default(),
-            client: Client::new(),
-            api_key: keychain
-                .get_api_key("OPENAI")
-                .expect("API key not found in keychain: `OPENAI`"),
+    /// Send the request following the retry policy, non success responses are turned into errors.
+    async fn send<T: Serialize>(&self, url: &str, request: &T) -> Result<reqwest::Response> {
+        self.send_with(Method::POST, url, request, |builder| builder.json(request)).await
+    }
+
+    /// [`OpenAIConnector::send`] with `method` and the body set by `body` (on every attempt),
+    /// `request` only identifies the exchange in cassettes.
+    async fn send_with<T: Serialize>(
+        &self,
+        method: Method,
+        url: &str,
+        request: &T,
+        body: impl Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        self.retry_policy
+            .run(|| async {
+                let respect_rate_limit = self.retry_policy.respect_rate_limit_headers;
+                let api_key = match &self.profile.api_key_name {
+                    Some(name) if respect_rate_limit => Some((name, self.rate_limit.select_key(&self.keychain, name)?)),
+                    Some(name) => Some((name, self.keychain.api_key(name)?)),
+                    None => None,
+                };
+                let key = api_key.as_ref().map_or("", |(_, api_key)| api_key.key.as_str());
+                if let Some(delay) = self.rate_limit.delay(key).filter(|_| respect_rate_limit) {
+                    return Err(OpenAIError::RateLimited {
+                        message: format!("rate limit exhausted, reset in {:?}", delay),
+                        retry_after: Some(delay),
+                    });
+                }
+                let mut builder = self.client.request(method.clone(), url);
+                if let Some((_, api_key)) = &api_key {
+                    builder = builder.bearer_auth(&api_key.key);
+                    if let Some(organization) = &api_key.organization {
+                        builder = builder.header("OpenAI-Organization", organization);
+                    }
+                }
+                let builder = body(builder);
+                let response = self
+                    .middlewares
+                    .send("openai", &self.client, self.cassette.as_ref(), builder, request)
+                    .await?;
+                self.rate_limit.update(key, response.headers());
+                let status = response.status();
+                if status.is_success() {
+                    Ok(response)
+                } else {
+                    let headers = response.headers().clone();
+                    let error = completion_error(status, &headers, response.json::<ChatCompletionError>().await.ok());
+                    if let (OpenAIError::RateLimited { retry_after, .. }, Some((name, api_key))) = (&error, &api_key) {
+                        self.keychain.report_rate_limited(name, api_key, *retry_after);
+                    }
+                    Err(error)
+                }
+            })
+            .await
+    }
+
+    /// Perform a streamed chat completion, chunks are yielded as soon as the server sends them.
+    pub async fn chat_completion_stream<'a>(
+        &self,
+        request: ChatCompletionRequest<'a>,
+    ) -> Result<ChatCompletionStream<OpenAIError>> {
+        let mut request = ChatCompletionRequest {
+            stream: Some(true),
+            stream_options: self
+                .profile
+                .include_stream_usage()
+                .then_some(ChatCompletionStreamOptions { include_usage: true }),
+            ..request
+        };
+        request.parameters.model.get_or_insert_with(|| self.profile.model.clone());
+        let response = self.send(&self.profile.api_endpoint, &request).await?;
+        let status = response.status();
+
+        struct State {
+            bytes: Pin<Box<dyn Stream<Item = reqwest::Result<bytes::Bytes>> + Send>>,
+            decoder: SseDecoder,
+            eof: bool,
+            done: bool,
         }
+
+        let state = State {
+            bytes: Box::pin(response.bytes_stream()),
+            decoder: SseDecoder::new(),
+            eof: false,
+            done: false,
+        };
+        let stream = futures_util::stream::unfold(state, move |mut state| async move {
+            while !state.done {
+                if let Some(data) = state.decoder.next_event() {
+                    if data.trim() == "[DONE]" {
+                        break;
+                    }
+                    let chunk = serde_json::from_str::<ChatCompletionChunk>(&data).map_err(|e| {
+                        match serde_json::from_str::<ChatCompletionError>(&data) {
+                            Ok(error) => completion_error(status, &HeaderMap::new(), Some(error)),
+                            Err(_) => e.into(),
+                        }
+                    });
+                    state.done = chunk.is_err();
+                    return Some((chunk, state));
+                }
+                if state.eof {
+                    break;
+                }
+                match state.bytes.next().await {
+                    Some(Ok(bytes)) => state.decoder.push(&bytes),
+                    Some(Err(e)) => {
+                        state.done = true;
+                        return Some((Err(e.into()), state));
+                    }
+                    None => {
+                        state.decoder.finish();
+                        state.eof = true;
+                    }
+                }
+            }
+            None
+        });
+        Ok(Box::pin(stream))
     }
 
     pub async fn chat_completion_request<'a>(
         &self,
         request: ChatCompletionRequest<'a>,
     ) -> Result<ChatCompletionResponse> {
+        let mut request = request;
+        request.parameters.model.get_or_insert_with(|| self.profile.model.clone());
+        let response = self.send(&self.profile.api_endpoint, &request).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Embed `input`, split in batches of [`EmbeddingOptions::batch_size`] inputs.
+    pub async fn embeddings(&self, input: &[String], options: &EmbeddingOptions) -> Result<Embeddings> {
+        let endpoint = self.profile.endpoint("embeddings")?;
+        let model = options.model.as_deref().unwrap_or(&self.profile.embedding_model);
+        let mut embeddings = Embeddings::default();
+        for batch in input.chunks(options.batch_size.unwrap_or(DEFAULT_EMBEDDING_BATCH_SIZE).max(1)) {
+            let request = EmbeddingRequest {
+                model,
+                input: batch,
+                dimensions: options.dimensions,
+                user: options.user.as_deref(),
+            };
+            let response: EmbeddingResponse = self.send(&endpoint, &request).await?.json().await?;
+            if response.data.len() != batch.len() {
+                return Err(OpenAIError::InvalidResponse(format!(
+                    "{} embeddings returned for {} inputs",
+                    response.data.len(),
+                    batch.len()
+                )));
+            }
+            embeddings.push(response);
+        }
+        Ok(embeddings)
+    }
+}
+
+impl OpenAIConnector {
+    /// Transcribe the audio file at `path` (mp3, mp4, mpeg, m4a, wav, webm...).
+    pub async fn transcribe(&self, path: &Path, options: &TranscriptionOptions) -> Result<Transcription> {
+        let size = tokio::fs::metadata(path).await?.len();
+        if size > MAX_AUDIO_FILE_SIZE {
+            return Err(OpenAIError::AudioTooLarge {
+                size,
+                max: MAX_AUDIO_FILE_SIZE,
+            });
+        }
+        let data = tokio::fs::read(path).await?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "audio".to_string());
+        let request = TranscriptionRequest {
+            file: &file_name,
+            model: options.model.as_deref().unwrap_or(&self.profile.transcription_model),
+            language: options.language.as_deref(),
+            prompt: options.prompt.as_deref(),
+            response_format: options.response_format,
+            temperature: options.temperature,
+        };
+        let fields = request.fields();
+        let endpoint = self.profile.endpoint("audio/transcriptions")?;
         let response = self
-            .client
-            .post(self.profile.api_endpoint.clone())
-            .bearer_auth(&self.api_key)
-            .json(&request)
-            .send()
+            .send_with(Method::POST, &endpoint, &request, |builder| {
+                let file = reqwest::multipart::Part::bytes(data.clone()).file_name(file_name.clone());
+                let form = fields
+                    .iter()
+                    .fold(reqwest::multipart::Form::new(), |form, (name, value)| form.text(*name, value.clone()));
+                builder.multipart(form.part("file", file))
+            })
             .await?;
-        let status = response.status();
-        if status.is_success() {
-            Ok(response.json().await?)
-        } else {
-            let error = response.json::<ChatCompletionError>().await;
-            Err(OpenAIError::CompletionFailed {
-                message: error.as_ref().map(|e| e.error.message.clone()).unwrap_or_else(|_| format!("Non success status code (failed to deserialize response): `{}`", status)),
-                code: error.as_ref().map(|e| e.error.code.clone()).ok(),
-                status,
+        match options.response_format.is_json() {
+            true => Ok(response.json().await?),
+            false => Ok(Transcription {
+                text: response.text().await?,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Read `input` aloud, returns the encoded audio.
+    pub async fn speech(&self, input: &str, options: &SpeechOptions) -> Result<Vec<u8>> {
+        let request = SpeechRequest {
+            model: options.model.as_deref().unwrap_or(&self.profile.speech_model),
+            input,
+            voice: options.voice.as_deref().unwrap_or(DEFAULT_VOICE),
+            response_format: options.format.unwrap_or_default(),
+            speed: options.speed,
+        };
+        let response = self.send(&self.profile.endpoint("audio/speech")?, &request).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// [`OpenAIConnector::speech`] written to `path`, encoded according to its extension when
+    /// the options have no format.
+    pub async fn speech_to_file(&self, input: &str, path: &Path, options: &SpeechOptions) -> Result<()> {
+        let options = SpeechOptions {
+            format: options.format.or_else(|| SpeechFormat::from_path(path)),
+            ..options.clone()
+        };
+        let audio = self.speech(input, &options).await?;
+        Ok(tokio::fs::write(path, audio).await?)
+    }
+
+    /// Upload `data` to the Files API.
+    pub async fn upload_file(&self, file_name: &str, data: Vec<u8>, purpose: FilePurpose) -> Result<FileObject> {
+        let request = serde_json::json!({"file": file_name, "purpose": purpose});
+        let response = self
+            .send_with(Method::POST, &self.profile.endpoint("files")?, &request, |builder| {
+                let file = reqwest::multipart::Part::bytes(data.clone()).file_name(file_name.to_string());
+                let form = reqwest::multipart::Form::new().text("purpose", purpose.as_str());
+                builder.multipart(form.part("file", file))
+            })
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// The uploaded files, only the ones for `purpose` when set.
+    pub async fn list_files(&self, purpose: Option<FilePurpose>) -> Result<Vec<FileObject>> {
+        let response = self
+            .send_with(Method::GET, &self.profile.endpoint("files")?, &purpose, |builder| match purpose {
+                Some(purpose) => builder.query(&[("purpose", purpose.as_str())]),
+                None => builder,
             })
+            .await?;
+        Ok(response.json::<FileList>().await?.data)
+    }
+
+    /// The content of the file `id`.
+    pub async fn download_file(&self, id: &str) -> Result<Vec<u8>> {
+        let url = format!("{}/{}/content", self.profile.endpoint("files")?, id);
+        let response = self.send_with(Method::GET, &url, &(), |builder| builder).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Start a batch job running the requests of the uploaded file `input_file_id` on the chat
+    /// completion endpoint.
+    pub async fn create_batch(&self, input_file_id: &str, metadata: Option<&HashMap<String, String>>) -> Result<BatchJob> {
+        let request = CreateBatchRequest {
+            input_file_id,
+            endpoint: CHAT_COMPLETIONS_ENDPOINT,
+            completion_window: COMPLETION_WINDOW,
+            metadata,
+        };
+        let response = self.send(&self.profile.endpoint("batches")?, &request).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Upload the chat completion `requests` (identified by their `custom_id`) and start their
+    /// batch job, the requests without a model use the profile's one.
+    pub async fn submit_chat_batch(
+        &self,
+        requests: &[(String, ChatCompletionRequest<'_>)],
+        metadata: Option<&HashMap<String, String>>,
+    ) -> Result<BatchJob> {
+        let requests = requests
+            .iter()
+            .map(|(custom_id, request)| {
+                let mut request = ChatCompletionRequest {
+                    stream: None,
+                    stream_options: None,
+                    ..request.clone()
+                };
+                request.parameters.model.get_or_insert_with(|| self.profile.model.clone());
+                (custom_id.clone(), request)
+            })
+            .collect::<Vec<_>>();
+        let file = build_chat_batch_file(CHAT_COMPLETIONS_ENDPOINT, &requests)?;
+        let file = self.upload_file("batch.jsonl", file.into_bytes(), FilePurpose::Batch).await?;
+        self.create_batch(&file.id, metadata).await
+    }
+
+    pub async fn batch(&self, id: &str) -> Result<BatchJob> {
+        let url = format!("{}/{}", self.profile.endpoint("batches")?, id);
+        let response = self.send_with(Method::GET, &url, &(), |builder| builder).await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn cancel_batch(&self, id: &str) -> Result<BatchJob> {
+        let url = format!("{}/{}/cancel", self.profile.endpoint("batches")?, id);
+        let response = self.send_with(Method::POST, &url, &(), |builder| builder).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Poll the batch job `id` until it is completed, failed, expired or cancelled.
+    pub async fn wait_for_batch(&self, id: &str, options: PollOptions) -> Result<BatchJob> {
+        let start = std::time::Instant::now();
+        loop {
+            let job = self.batch(id).await?;
+            if job.status.is_terminal() {
+                return Ok(job);
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= options.timeout {
+                return Err(OpenAIError::BatchTimeout {
+                    id: id.to_string(),
+                    elapsed,
+                });
+            }
+            tokio::time::sleep(options.interval.min(options.timeout - elapsed)).await;
+        }
+    }
+
+    /// The results of a finished chat batch job by `custom_id`, from its output and error files.
+    pub async fn chat_batch_results(
+        &self,
+        job: &BatchJob,
+    ) -> Result<HashMap<String, std::result::Result<ChatCompletionResponse, BatchItemError>>> {
+        let mut results = HashMap::new();
+        for id in job.output_file_id.iter().chain(job.error_file_id.iter()) {
+            let file = self.download_file(id).await?;
+            results.extend(parse_chat_batch_results(&String::from_utf8_lossy(&file))?);
+        }
+        Ok(results)
+    }
+}
+
+impl EmbeddingProvider for OpenAIConnector {
+    type Error = OpenAIError;
+
+    fn default_embedding_model(&self) -> &str {
+        &self.profile.embedding_model
+    }
+
+    async fn embed<'a>(&'a self, input: &'a [String], options: &'a EmbeddingOptions) -> Result<Embeddings> {
+        self.embeddings(input, options).await
+    }
+}
+
+impl ModerationProvider for OpenAIConnector {
+    type Error = OpenAIError;
+
+    async fn moderate<'a>(&'a self, input: &'a [String]) -> Result<Vec<ModerationResult>> {
+        let request = ModerationRequest { input, model: None };
+        let endpoint = self.profile.endpoint("moderations")?;
+        let response: ModerationResponse = self.send(&endpoint, &request).await?.json().await?;
+        if response.results.len() != input.len() {
+            return Err(OpenAIError::InvalidResponse(format!(
+                "{} moderation results returned for {} inputs",
+                response.results.len(),
+                input.len()
+            )));
+        }
+        Ok(response.results)
+    }
+}
+
+impl ChatProvider for OpenAIConnector {
+    type Error = OpenAIError;
+
+    fn default_model(&self) -> &str {
+        &self.profile.model
+    }
+
+    fn context_window(&self, model: &str) -> Option<usize> {
+        match self.profile.context_window {
+            Some(context_window) if model == self.profile.model => Some(context_window),
+            _ => crate::tokenizer::context_window(model),
+        }
+    }
+
+    async fn chat_completion<'a>(&'a self, request: ChatCompletionRequest<'a>) -> Result<ChatCompletionResponse> {
+        self.chat_completion_request(request).await
+    }
+
+    async fn chat_completion_stream<'a>(
+        &'a self,
+        request: ChatCompletionRequest<'a>,
+    ) -> Result<ChatCompletionStream<OpenAIError>> {
+        OpenAIConnector::chat_completion_stream(self, request).await
+    }
+}
+
+/// Classify an error response, see [`Retryable`] for the errors worth retrying.
+fn completion_error(status: StatusCode, headers: &HeaderMap, error: Option<ChatCompletionError>) -> OpenAIError {
+    let message = error.as_ref().map(|e| e.error.message.clone()).unwrap_or_else(|| format!("Non success status code (failed to deserialize response): `{}`", status));
+    let code = error.as_ref().and_then(|e| e.error.code.clone());
+    let kind = error.as_ref().and_then(|e| e.error.kind.clone());
+    let is = |value: &str| code.as_deref() == Some(value) || kind.as_deref() == Some(value);
+    let retry_after = RateLimitInfo::from_headers(headers).delay();
+    if is("insufficient_quota") {
+        OpenAIError::QuotaExceeded { message }
+    } else if is("context_length_exceeded") {
+        OpenAIError::ContextLengthExceeded { message }
+    } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        OpenAIError::Unauthorized { message, status }
+    } else if status == StatusCode::TOO_MANY_REQUESTS {
+        OpenAIError::RateLimited { message, retry_after }
+    } else if status.is_server_error() {
+        OpenAIError::ServerError { message, status, retry_after }
+    } else {
+        OpenAIError::CompletionFailed { message, code, status }
+    }
+}
+
+impl Retryable for OpenAIError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::RateLimited { .. } | Self::ServerError { .. } => true,
+            Self::HttpClientError(e) => e.is_timeout() || e.is_connect(),
+            _ => false,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } | Self::ServerError { retry_after, .. } => *retry_after,
+            _ => None,
         }
     }
 }
 
+fn default_api_endpoint() -> String {
+    "https://api.openai.com/v1/chat/completions".to_string()
+}
+
+fn default_model() -> String {
+    "gpt-4".to_string()
+}
+
+fn default_embedding_model() -> String {
+    "text-embedding-3-small".to_string()
+}
+
+fn default_transcription_model() -> String {
+    "whisper-1".to_string()
+}
+
+fn default_speech_model() -> String {
+    "tts-1".to_string()
+}
+
+fn default_api_key_name() -> Option<String> {
+    Some("OPENAI".to_string())
+}
+
 impl Default for ChatGptProfile {
     fn default() -> Self {
         Self {
-            api_endpoint: "https://api.openai.com/v1/chat/completions".to_string(),
+            api_endpoint: default_api_endpoint(),
+            model: default_model(),
+            api_key_name: default_api_key_name(),
+            context_window: None,
+            embeddings_endpoint: None,
+            embedding_model: default_embedding_model(),
+            moderations_endpoint: None,
+            audio_endpoint: None,
+            transcription_model: default_transcription_model(),
+            speech_model: default_speech_model(),
+            files_endpoint: None,
+            batches_endpoint: None,
+            stream_usage: None,
         }
     }
 }
 
-impl Default for ChatCompletionRequest<'static> {
-    fn default() -> Self {
-        Self {
-            model: "gpt-4".to_string(),
-            messages: &[],
-            functions: None,
-            // function_call: None,
-            temperature: None,
-            top_p: None,
-            n: None,
-            stream: None,
-            stop: None,
-            max_tokens: None,
+impl ChatGptProfile {
+    /// Whether streamed completions ask for their usage, see [`ChatGptProfile::stream_usage`].
+    pub fn include_stream_usage(&self) -> bool {
+        self.stream_usage.unwrap_or_else(|| {
+            reqwest::Url::parse(&self.api_endpoint).is_ok_and(|url| url.host_str() == Some("api.openai.com"))
+        })
+    }
+
+    /// The endpoint at `path` (`embeddings`, `files`, `audio/speech`...): the one configured
+    /// for it, or `api_endpoint` with `chat/completions` replaced by `path`.
+    pub fn endpoint(&self, path: &str) -> Result<String> {
+        let configured = match path.split_once('/') {
+            Some(("audio", name)) => self.audio_endpoint.as_ref().map(|base| format!("{}/{}", base.trim_end_matches('/'), name)),
+            _ => match path {
+                "embeddings" => self.embeddings_endpoint.clone(),
+                "moderations" => self.moderations_endpoint.clone(),
+                "files" => self.files_endpoint.clone(),
+                "batches" => self.batches_endpoint.clone(),
+                _ => None,
+            },
+        };
+        if let Some(endpoint) = configured {
+            return Ok(endpoint);
         }
+        match self.api_endpoint.trim_end_matches('/').strip_suffix("chat/completions") {
+            Some(base) => Ok(format!("{}{}", base, path)),
+            None => Err(OpenAIError::InvalidEndpoint(format!(
+                "cannot derive `{}` from {}, set its endpoint in the profile",
+                path, self.api_endpoint
+            ))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn mock_connector(server: &wiremock::MockServer, max_retries: u32) -> OpenAIConnector {
+        let keychain = KeyChain::new();
+        keychain.set_api_key("OPENAI", "sk-test".to_string());
+        OpenAIConnector::new(&keychain)
+            .unwrap()
+            .with_profile(ChatGptProfile {
+                api_endpoint: format!("{}/v1/chat/completions", server.uri()),
+                ..Default::default()
+            })
+            .with_retry_policy(RetryPolicy {
+                max_retries,
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            })
+    }
+
+    #[tokio::test]
+    async fn test_retry_rate_limited() {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .respond_with(
+                ResponseTemplate::new(429)
+                    .insert_header("retry-after-ms", "10")
+                    .set_body_json(serde_json::json!({"error": {"message": "slow down", "type": "requests", "code": "rate_limit_exceeded"}})),
+            )
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1,
+                "model": "gpt-4",
+                "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}],
+                "usage": {}
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let messages = [ChatCompletionMessage::new(ChatCompletionRole::User, "hello".to_string())];
+        let request = ChatCompletionRequest {
+            messages: &messages,
+            ..Default::default()
+        };
+        let response = mock_connector(&server, 3).chat_completion_request(request.clone()).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "hi");
+
+        let error = mock_connector(&server, 0);
+        server.reset().await;
+        Mock::given(matchers::method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let error = error.chat_completion_request(request.clone()).await.unwrap_err();
+        assert!(matches!(error, OpenAIError::ServerError { .. }));
+
+        // An exhausted limit resetting after `max_elapsed` fails without waiting nor sending.
+        server.reset().await;
+        Mock::given(matchers::method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("x-ratelimit-remaining-tokens", "0")
+                    .insert_header("x-ratelimit-reset-tokens", "6m0s")
+                    .set_body_json(serde_json::json!({
+                        "id": "chatcmpl-1",
+                        "object": "chat.completion",
+                        "created": 1,
+                        "model": "gpt-4",
+                        "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}],
+                        "usage": {}
+                    })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let connector = mock_connector(&server, 3);
+        connector.chat_completion_request(request.clone()).await.unwrap();
+        let error = connector.chat_completion_request(request).await.unwrap_err();
+        assert!(matches!(error, OpenAIError::RateLimited { retry_after: Some(delay), .. } if delay > Duration::from_secs(300)));
+    }
+
+    #[tokio::test]
+    async fn test_fatal_errors_are_not_retried() {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .respond_with(ResponseTemplate::new(429).set_body_json(serde_json::json!({
+                "error": {"message": "You exceeded your current quota", "type": "insufficient_quota", "code": "insufficient_quota"}
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let error = mock_connector(&server, 3)
+            .chat_completion_request(ChatCompletionRequest::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, OpenAIError::QuotaExceeded { .. }));
+        assert!(!error.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_key_failover() {
+        use crate::keyring::*;
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::header("authorization", "Bearer sk-a"))
+            .respond_with(ResponseTemplate::new(429).set_body_json(serde_json::json!({
+                "error": {"message": "slow down", "type": "requests", "code": "rate_limit_exceeded"}
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::header("authorization", "Bearer sk-b"))
+            .and(matchers::header("openai-organization", "org-b"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1,
+                "model": "gpt-4",
+                "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}],
+                "usage": {}
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let keychain = KeyChain::new().with_key_file(
+            serde_yaml::from_str("profiles:\n  default:\n    OPENAI: [sk-a, {key: sk-b, organization: org-b}]\n").unwrap(),
+        );
+        let connector = OpenAIConnector::new(&keychain)
+            .unwrap()
+            .with_profile(ChatGptProfile {
+                api_endpoint: format!("{}/v1/chat/completions", server.uri()),
+                ..Default::default()
+            })
+            .with_retry_policy(RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                ..Default::default()
+            });
+        for _ in 0..2 {
+            let response = connector.chat_completion_request(ChatCompletionRequest::default()).await.unwrap();
+            assert_eq!(response.choices[0].message.content, "hi");
+        }
+
+        assert!(matches!(
+            OpenAIConnector::new(&KeyChain::new()),
+            Err(KeyError::NotFound { name, .. }) if name == "OPENAI"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_key_failover() {
+        use crate::keyring::*;
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let answer = serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "gpt-4",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}],
+            "usage": {}
+        });
+        let server = MockServer::start().await;
+        Mock::given(matchers::header("authorization", "Bearer sk-a"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("x-ratelimit-remaining-requests", "0")
+                    .insert_header("x-ratelimit-reset-requests", "6m0s")
+                    .set_body_json(answer.clone()),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::header("authorization", "Bearer sk-b"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(answer))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        // The exhausted key does not hold up the requests while another one is available.
+        let keychain = KeyChain::new().with_key_file(serde_yaml::from_str("profiles:\n  default:\n    OPENAI: [sk-a, sk-b]\n").unwrap());
+        let connector = OpenAIConnector::new(&keychain).unwrap().with_profile(ChatGptProfile {
+            api_endpoint: format!("{}/v1/chat/completions", server.uri()),
+            ..Default::default()
+        });
+        for _ in 0..3 {
+            connector.chat_completion_request(ChatCompletionRequest::default()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audio() {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/v1/audio/transcriptions"))
+            .and(matchers::header("authorization", "Bearer sk-test"))
+            .and(matchers::body_string_contains("filename=\"meeting.wav\""))
+            .and(matchers::body_string_contains("RIFF-audio"))
+            .and(matchers::body_string_contains("name=\"language\"\r\n\r\nfr"))
+            .and(matchers::body_string_contains("name=\"response_format\"\r\n\r\nsrt"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("1\n00:00:00,000 --> 00:00:01,500\nBonjour\n"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/v1/audio/speech"))
+            .and(matchers::body_json(serde_json::json!({
+                "model": "tts-1",
+                "input": "Bonjour",
+                "voice": "alloy",
+                "response_format": "wav"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"RIFF-speech".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let connector = mock_connector(&server, 0);
+        let directory = tempfile::tempdir().unwrap();
+        let audio = directory.path().join("meeting.wav");
+        std::fs::write(&audio, b"RIFF-audio").unwrap();
+        let options = TranscriptionOptions {
+            language: Some("fr".to_string()),
+            response_format: TranscriptionFormat::Srt,
+            ..Default::default()
+        };
+        let transcription = connector.transcribe(&audio, &options).await.unwrap();
+        assert!(transcription.text.ends_with("Bonjour\n"));
+
+        let speech = directory.path().join("bonjour.wav");
+        connector.speech_to_file("Bonjour", &speech, &SpeechOptions::default()).await.unwrap();
+        assert_eq!(std::fs::read(&speech).unwrap(), b"RIFF-speech");
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let profile = ChatGptProfile {
+            api_endpoint: "http://localhost:8080/v1/chat/completions/".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(profile.endpoint("embeddings").unwrap(), "http://localhost:8080/v1/embeddings");
+        assert_eq!(profile.endpoint("audio/speech").unwrap(), "http://localhost:8080/v1/audio/speech");
+
+        // A custom base URL only derives the configured endpoints.
+        let profile = ChatGptProfile {
+            api_endpoint: "https://proxy.example.com/deployments/gpt-4/complete".to_string(),
+            moderations_endpoint: Some("https://proxy.example.com/moderate".to_string()),
+            audio_endpoint: Some("https://proxy.example.com/audio/".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(profile.endpoint("moderations").unwrap(), "https://proxy.example.com/moderate");
+        assert_eq!(profile.endpoint("audio/transcriptions").unwrap(), "https://proxy.example.com/audio/transcriptions");
+        assert!(matches!(profile.endpoint("embeddings"), Err(OpenAIError::InvalidEndpoint(_))));
+        assert!(matches!(profile.endpoint("batches"), Err(OpenAIError::InvalidEndpoint(_))));
+
+        assert!(!profile.include_stream_usage());
+        assert!(ChatGptProfile::default().include_stream_usage());
+        let profile = ChatGptProfile {
+            stream_usage: Some(true),
+            ..profile
+        };
+        assert!(profile.include_stream_usage());
+    }
+
+    #[tokio::test]
+    async fn test_batched_embeddings() {
+        use wiremock::{matchers, Mock, MockServer, Request, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        // Embeds every input as `[length, index in batch]`, in reverse order.
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/v1/embeddings"))
+            .respond_with(|request: &Request| {
+                let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                let data = body["input"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(index, input)| {
+                        serde_json::json!({"index": index, "embedding": [input.as_str().unwrap().len(), index]})
+                    })
+                    .collect::<Vec<_>>();
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "data": data,
+                    "model": body["model"],
+                    "usage": {"prompt_tokens": 2, "total_tokens": 2}
+                }))
+            })
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let input = ["a", "bb", "ccc"].map(String::from);
+        let options = EmbeddingOptions {
+            batch_size: Some(2),
+            dimensions: Some(2),
+            ..Default::default()
+        };
+        let embeddings = mock_connector(&server, 0).embed(&input, &options).await.unwrap();
+        assert_eq!(embeddings.vectors, [vec![1.0, 0.0], vec![2.0, 1.0], vec![3.0, 0.0]]);
+        assert_eq!(embeddings.model, "text-embedding-3-small");
+        assert_eq!(embeddings.usage.total_tokens, 4);
+    }
+
+    #[tokio::test]
+    async fn test_batch_api() {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let job = |status: &str| {
+            serde_json::json!({
+                "id": "batch_1",
+                "endpoint": "/v1/chat/completions",
+                "status": status,
+                "input_file_id": "file-in",
+                "output_file_id": if status == "completed" { Some("file-out") } else { None },
+                "request_counts": {"total": 2, "completed": 1, "failed": 1}
+            })
+        };
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/openai/v1/files"))
+            .and(matchers::body_string_contains("name=\"purpose\"\r\n\r\nbatch"))
+            .and(matchers::body_string_contains(r#""custom_id":"card-1","method":"POST","url":"/v1/chat/completions""#))
+            .and(matchers::body_string_contains(r#""model":"gpt-4""#))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "file-in", "bytes": 10, "created_at": 1, "filename": "batch.jsonl", "purpose": "batch"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/openai/v1/batches"))
+            .and(matchers::body_json(serde_json::json!({
+                "input_file_id": "file-in",
+                "endpoint": "/v1/chat/completions",
+                "completion_window": "24h"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(job("validating")))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path("/openai/v1/batches/batch_1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(job("in_progress")))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path("/openai/v1/batches/batch_1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(job("completed")))
+            .mount(&server)
+            .await;
+        let output = [
+            serde_json::json!({"id": "r1", "custom_id": "card-1", "response": {"status_code": 200, "body": {
+                "id": "chatcmpl-1", "object": "chat.completion", "created": 1, "model": "gpt-4",
+                "choices": [{"index": 0, "message": {"role": "assistant", "content": "A brave knight"}, "finish_reason": "stop"}]
+            }}}),
+            serde_json::json!({"id": "r2", "custom_id": "card-2", "response": {"status_code": 400, "body": {
+                "error": {"message": "bad request"}
+            }}}),
+        ]
+        .map(|line| line.to_string())
+        .join("\n");
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path("/openai/v1/files/file-out/content"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(output))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path("/openai/v1/files"))
+            .and(matchers::query_param("purpose", "batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"data": [
+                {"id": "file-in", "bytes": 10, "created_at": 1, "filename": "batch.jsonl", "purpose": "batch"}
+            ]})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // Behind a prefixed base URL, the batch still names the endpoint from the API root.
+        let connector = mock_connector(&server, 0).with_profile(ChatGptProfile {
+            api_endpoint: format!("{}/openai/v1/chat/completions", server.uri()),
+            ..Default::default()
+        });
+        let messages = [ChatCompletionMessage::new(ChatCompletionRole::User, "Describe a knight card".to_string())];
+        let requests = ["card-1", "card-2"].map(|custom_id| {
+            let request = ChatCompletionRequest {
+                messages: &messages,
+                ..Default::default()
+            };
+            (custom_id.to_string(), request)
+        });
+        let job = connector.submit_chat_batch(&requests, None).await.unwrap();
+        assert_eq!(job.status, BatchStatus::Validating);
+        let options = PollOptions {
+            interval: Duration::from_millis(1),
+            timeout: Duration::from_secs(5),
+        };
+        let job = connector.wait_for_batch(&job.id, options).await.unwrap();
+        assert_eq!(job.status, BatchStatus::Completed);
+
+        let results = connector.chat_batch_results(&job).await.unwrap();
+        assert_eq!(results["card-1"].as_ref().unwrap().choices[0].message.content, "A brave knight");
+        assert_eq!(
+            results["card-2"].as_ref().unwrap_err(),
+            &BatchItemError::Status {
+                status: 400,
+                message: "bad request".to_string()
+            }
+        );
+        let files = connector.list_files(Some(FilePurpose::Batch)).await.unwrap();
+        assert_eq!(files[0].id, "file-in");
     }
 }
@@ -0,0 +1,279 @@
+use crate::keyring::{ApiKey, KeyChain, KeyError};
+use crate::prelude::*;
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How failed requests are retried: exponential backoff with jitter, bounded by a number of
+/// retries and a total elapsed time.
+///
+/// A delay requested by the server (`Retry-After`, `x-ratelimit-reset-*`) replaces the computed
+/// backoff when [`RetryPolicy::respect_rate_limit_headers`] is set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// Fraction of the backoff that is randomized (`0.0` disables the jitter, `1.0` is full jitter).
+    pub jitter: f64,
+    /// Give up once the next attempt would start after this duration.
+    pub max_elapsed: Option<Duration>,
+    pub respect_rate_limit_headers: bool,
+}
+
+/// Errors that may succeed when the request is sent again.
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+
+    /// The delay the server asked for before the next attempt.
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Rate limit state reported by the server in the response headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitInfo {
+    pub retry_after: Option<Duration>,
+    pub limit_requests: Option<u64>,
+    pub limit_tokens: Option<u64>,
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    pub reset_requests: Option<Duration>,
+    pub reset_tokens: Option<Duration>,
+}
+
+/// The last [`RateLimitInfo`] seen by a connector for each API key, used to wait before hitting
+/// an exhausted limit (or to use another key).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RateLimitState {
+    limits: Arc<RwLock<RateLimits>>,
+}
+
+#[derive(Debug, Default)]
+struct RateLimits {
+    /// By API key, the empty key stands for the servers without authentication.
+    by_key: HashMap<String, (Instant, RateLimitInfo)>,
+    /// Key of the last response.
+    last: Option<String>,
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// The backoff before the retry number `attempt` (starting at 0), jitter included.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let backoff = backoff.min(self.max_backoff.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            1.0 - jitter * rand::thread_rng().gen::<f64>()
+        } else {
+            1.0
+        };
+        Duration::from_secs_f64(backoff * factor)
+    }
+
+    /// Run `request` until it succeeds, fails with a fatal error or the policy gives up.
+    /// The last error is returned when giving up.
+    pub async fn run<T, E, F, Fut>(&self, mut request: F) -> std::result::Result<T, E>
+    where
+        E: Retryable,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::result::Result<T, E>>,
+    {
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            let error = match request().await {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            if !error.is_retryable() || attempt >= self.max_retries {
+                return Err(error);
+            }
+            let delay = match error.retry_after() {
+                Some(delay) if self.respect_rate_limit_headers => delay,
+                _ => self.backoff(attempt),
+            };
+            if let Some(max_elapsed) = self.max_elapsed {
+                if start.elapsed() + delay > max_elapsed {
+                    return Err(error);
+                }
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_elapsed: Some(Duration::from_secs(120)),
+            respect_rate_limit_headers: true,
+        }
+    }
+}
+
+impl RateLimitInfo {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(str::trim);
+        let number = |name: &str| header(name).and_then(|value| value.parse().ok());
+        let retry_after = header("retry-after-ms")
+            .and_then(|value| value.parse::<f64>().ok())
+            .map(|ms| Duration::from_secs_f64(ms.max(0.0) / 1000.0))
+            .or_else(|| header("retry-after").and_then(parse_retry_after));
+        Self {
+            retry_after,
+            limit_requests: number("x-ratelimit-limit-requests"),
+            limit_tokens: number("x-ratelimit-limit-tokens"),
+            remaining_requests: number("x-ratelimit-remaining-requests"),
+            remaining_tokens: number("x-ratelimit-remaining-tokens"),
+            reset_requests: header("x-ratelimit-reset-requests").and_then(parse_duration),
+            reset_tokens: header("x-ratelimit-reset-tokens").and_then(parse_duration),
+        }
+    }
+
+    /// How long to wait before a request can succeed: the `Retry-After` delay or the reset delay
+    /// of an exhausted limit.
+    pub fn delay(&self) -> Option<Duration> {
+        self.retry_after.or_else(|| self.reset_delay())
+    }
+
+    /// The time until the exhausted limits (if any) are reset.
+    pub fn reset_delay(&self) -> Option<Duration> {
+        let exhausted = |remaining: Option<u64>, reset: Option<Duration>| match remaining {
+            Some(0) => reset,
+            _ => None,
+        };
+        exhausted(self.remaining_requests, self.reset_requests)
+            .into_iter()
+            .chain(exhausted(self.remaining_tokens, self.reset_tokens))
+            .max()
+    }
+}
+
+impl RateLimitState {
+    /// Record the limits of `key` reported by a response.
+    pub fn update(&self, key: &str, headers: &HeaderMap) {
+        let info = RateLimitInfo::from_headers(headers);
+        if let Ok(mut limits) = self.limits.write() {
+            limits.by_key.insert(key.to_string(), (Instant::now(), info));
+            limits.last = Some(key.to_string());
+        }
+    }
+
+    /// The limits reported by the last response.
+    pub fn get(&self) -> Option<RateLimitInfo> {
+        let limits = self.limits.read().ok()?;
+        limits.by_key.get(limits.last.as_ref()?).map(|(_, info)| info.clone())
+    }
+
+    /// The time left until the limits of `key` exhausted by its previous response are reset.
+    ///
+    /// The connectors fail the attempt with a retryable error holding this delay, so waiting for
+    /// it is bounded by the retry policy like any other `Retry-After`.
+    pub fn delay(&self, key: &str) -> Option<Duration> {
+        let limits = self.limits.read().ok()?;
+        let (at, info) = limits.by_key.get(key)?;
+        info.reset_delay()?.checked_sub(at.elapsed()).filter(|delay| !delay.is_zero())
+    }
+
+    /// A key of `name` whose limits are not exhausted, the exhausted ones are left aside in the
+    /// keychain until their reset. When they all are, the one reset first.
+    pub fn select_key(&self, keychain: &KeyChain, name: &str) -> std::result::Result<ApiKey, KeyError> {
+        let mut exhausted = Vec::new();
+        let mut api_key = keychain.api_key(name)?;
+        while let Some(delay) = self.delay(&api_key.key) {
+            keychain.report_rate_limited(name, &api_key, Some(delay));
+            exhausted.push(api_key);
+            api_key = keychain.api_key(name)?;
+            if exhausted.contains(&api_key) {
+                break;
+            }
+        }
+        Ok(api_key)
+    }
+}
+
+/// Parse a `Retry-After` value: a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Some(Duration::from_secs_f64(seconds.max(0.0)));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Parse the durations used by the `x-ratelimit-reset-*` headers (`1s`, `6m0s`, `20ms`, `1h2m3.5s`).
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+        let (number, tail) = rest.split_at(split);
+        let number: f64 = number.parse().ok()?;
+        let unit_len = tail.find(|c: char| c.is_ascii_digit()).unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        total += number
+            * match unit {
+                "ms" => 0.001,
+                "s" => 1.0,
+                "m" => 60.0,
+                "h" => 3600.0,
+                _ => return None,
+            };
+        rest = tail;
+    }
+    Some(Duration::from_secs_f64(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_parse_rate_limit_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining-requests", HeaderValue::from_static("0"));
+        headers.insert("x-ratelimit-remaining-tokens", HeaderValue::from_static("1200"));
+        headers.insert("x-ratelimit-reset-requests", HeaderValue::from_static("6m0.5s"));
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("20ms"));
+        let info = RateLimitInfo::from_headers(&headers);
+        assert_eq!(info.reset_requests, Some(Duration::from_millis(360_500)));
+        assert_eq!(info.reset_tokens, Some(Duration::from_millis(20)));
+        assert_eq!(info.delay(), Some(Duration::from_millis(360_500)));
+
+        headers.insert("retry-after", HeaderValue::from_static("2"));
+        assert_eq!(RateLimitInfo::from_headers(&headers).delay(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(10), Duration::from_secs(30));
+        let jittered = RetryPolicy::default().backoff(1);
+        assert!(jittered <= Duration::from_secs(1) && jittered >= Duration::from_millis(500));
+    }
+}
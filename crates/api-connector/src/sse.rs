@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+
+/// Incremental decoder for `text/event-stream` bodies.
+///
+/// Bytes are pushed as they come from the network, complete events are returned
+/// once their terminating blank line has been received. Only the `data` field is
+/// kept since it is the only one used by the supported APIs.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    data: Option<String>,
+    events: VecDeque<String>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed raw bytes to the decoder.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=pos).collect::<Vec<u8>>();
+            let line = String::from_utf8_lossy(&line);
+            self.push_line(line.trim_end_matches(['\n', '\r']));
+        }
+    }
+
+    /// Flush the pending event (if any) once the underlying stream is closed.
+    pub fn finish(&mut self) {
+        if !self.buffer.is_empty() {
+            let line = std::mem::take(&mut self.buffer);
+            let line = String::from_utf8_lossy(&line);
+            self.push_line(line.trim_end_matches(['\n', '\r']));
+        }
+        self.push_line("");
+    }
+
+    /// Pop the next complete event's data.
+    pub fn next_event(&mut self) -> Option<String> {
+        self.events.pop_front()
+    }
+
+    fn push_line(&mut self, line: &str) {
+        if line.is_empty() {
+            if let Some(data) = self.data.take() {
+                self.events.push_back(data);
+            }
+        } else if let Some(value) = line.strip_prefix("data:") {
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match self.data.as_mut() {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_events() {
+        let mut decoder = SseDecoder::new();
+        decoder.push(b"data: {\"a\":1}\n\nda");
+        decoder.push(b"ta: {\"b\":2}\r\n\r\n: comment\n\ndata: [DONE]");
+        assert_eq!(decoder.next_event().as_deref(), Some("{\"a\":1}"));
+        assert_eq!(decoder.next_event().as_deref(), Some("{\"b\":2}"));
+        assert_eq!(decoder.next_event(), None);
+        decoder.finish();
+        assert_eq!(decoder.next_event().as_deref(), Some("[DONE]"));
+    }
+}
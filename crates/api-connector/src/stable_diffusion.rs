@@ -1,4 +1,18 @@
-use crate::{prelude::*, keyring::KeyChain};
+use crate::{
+    cassette::{Cassette, CassetteError},
+    image_generator::*,
+    keyring::{KeyChain, KeyError},
+    middleware::{Middleware, MiddlewareError, Middlewares},
+    prelude::*,
+    retry::{RateLimitInfo, RateLimitState, RetryPolicy, Retryable},
+    text_to_image::*,
+    webhook::{WebhookError, WebhookListener},
+};
+use base64::prelude::*;
+use reqwest::StatusCode;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -9,61 +23,355 @@ pub enum StableDiffusionError {
     JsonError(#[from] serde_json::Error),
     #[error("reqwest error: {}", _0)]
     HttpClientError(#[from] reqwest::Error),
+    #[error("cassette error: {}", _0)]
+    Cassette(#[from] CassetteError),
+    #[error("request failed: {} (http status: {})", message, status)]
+    RequestFailed { message: String, status: StatusCode },
+    #[error("authentication failed: {} (http status: {})", message, status)]
+    Unauthorized { message: String, status: StatusCode },
+    #[error("rate limited: {}", message)]
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("server error: {} (http status: {})", message, status)]
+    ServerError {
+        message: String,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    #[error("invalid profile: {}", _0)]
+    InvalidProfile(#[from] ProfileError),
+    #[error("generation failed: {}", _0)]
+    GenerationFailed(String),
+    #[error("generation {} still processing after {:?}", id, elapsed)]
+    Timeout { id: u64, elapsed: Duration },
+    #[error("keychain error: {}", _0)]
+    Key(#[from] KeyError),
+    #[error("webhook error: {}", _0)]
+    Webhook(#[from] WebhookError),
+    #[error("middleware error: {}", _0)]
+    Middleware(String),
+}
+
+impl From<MiddlewareError> for StableDiffusionError {
+    fn from(error: MiddlewareError) -> Self {
+        match error {
+            MiddlewareError::HttpClientError(e) => Self::HttpClientError(e),
+            MiddlewareError::Cassette(e) => Self::Cassette(e),
+            error => Self::Middleware(error.to_string()),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, StableDiffusionError>;
 
 pub struct StableDiffusionConnector {
-    speech_to_text_profile: SpeechToTextProfile,
+    profile: TextToImageProfile,
     client: Client,
-    api_key: String,
+    keychain: KeyChain,
+    retry_policy: RetryPolicy,
+    rate_limit: RateLimitState,
+    cassette: Option<Cassette>,
+    poll_options: PollOptions,
+    webhook: Option<Arc<WebhookListener>>,
+    middlewares: Middlewares,
 }
+
 #[derive(Debug, Serialize)]
-pub struct SpeechToTextProfile {
-    #[serde(skip)]
-    pub api_endpoint: String,
-    pub width: String,
-    pub height: String,
-    pub samples: String,
-    pub num_inference_steps: String,
-    pub safety_checker: String,
-    pub enhance_prompt: String,
-    pub guidance_scale: f64,
-    pub multi_lingual: String,
-    pub panorama: String,
-    pub self_attention: String,
-    pub upscale: String,
-    pub embeddings_model: Option<String>,
+pub struct GenerateImageRequest<'a> {
+    #[serde(flatten)]
+    pub profile: &'a TextToImageProfile,
+    pub key: String,
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    pub seed: Option<u64>,
+    pub webhook: Option<String>,
+    pub track_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lora_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lora_strength: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
-pub struct GenerateImageRequest<'a> {
+pub struct ImageToImageRequest<'a> {
     #[serde(flatten)]
-    pub profile: &'a SpeechToTextProfile,
+    pub profile: &'a TextToImageProfile,
     pub key: String,
     pub prompt: String,
     pub negative_prompt: Option<String>,
+    pub init_image: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mask_image: Option<String>,
+    pub strength: f64,
     pub seed: Option<u64>,
     pub webhook: Option<String>,
     pub track_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lora_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lora_strength: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SuperResolutionRequest {
+    pub key: String,
+    pub url: String,
+    pub scale: u32,
+    pub face_enhance: bool,
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadImageRequest {
+    pub key: String,
+    /// Data URI of the image.
+    pub image: String,
+    pub crop: &'static str,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct GenerateImageResponse {
-    pub status: String,
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UploadImageResponse {
+    Success { link: String },
+    #[serde(alias = "failed")]
+    Error(GenerationError),
+}
+
+/// An input image: local images are uploaded before being sent to the API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageSource {
+    Url(String),
+    File(PathBuf),
+    Bytes { data: Vec<u8>, mime: String },
+}
+
+impl From<&str> for ImageSource {
+    /// `http(s)://` URLs or else local paths.
+    fn from(value: &str) -> Self {
+        if value.starts_with("http://") || value.starts_with("https://") {
+            Self::Url(value.to_string())
+        } else {
+            Self::File(PathBuf::from(value))
+        }
+    }
+}
+
+impl From<PathBuf> for ImageSource {
+    fn from(path: PathBuf) -> Self {
+        Self::File(path)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FetchImageRequest {
+    pub key: String,
+}
+
+/// Answer of the generation and fetch endpoints, by `status`.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum GenerateImageResponse {
+    Success(GeneratedImages),
+    /// The images are not ready yet, they can be fetched once the ETA is elapsed.
+    Processing(ProcessingImages),
+    #[serde(alias = "failed")]
+    Error(GenerationError),
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct GeneratedImages {
+    #[serde(default)]
     pub id: u64,
+    /// URLs of the images.
+    #[serde(deserialize_with = "deserialize_output")]
     pub output: Vec<String>,
+    #[serde(default, rename = "generationTime")]
+    pub generation_time: Option<f64>,
+    #[serde(default)]
     pub meta: serde_json::Value,
 }
 
-impl StableDiffusionConnector {
-    pub fn new(keychain: &KeyChain) -> Self {
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ProcessingImages {
+    pub id: u64,
+    /// Estimated seconds before the images are ready.
+    #[serde(default)]
+    pub eta: Option<f64>,
+    /// Endpoint to fetch the images from.
+    #[serde(default)]
+    pub fetch_result: Option<String>,
+    /// Where the images will be available.
+    #[serde(default)]
+    pub future_links: Vec<String>,
+    #[serde(default)]
+    pub meta: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct GenerationError {
+    #[serde(default)]
+    pub id: Option<u64>,
+    /// The API spells it `messege` on some endpoints and may send an object.
+    #[serde(default, alias = "messege", deserialize_with = "deserialize_message")]
+    pub message: String,
+}
+
+/// How [`StableDiffusionConnector::wait_for_images`] polls a processing generation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PollOptions {
+    /// Delay between two fetches when the API gives no ETA.
+    pub interval: Duration,
+    /// Give up once the images are not ready after this duration.
+    pub timeout: Duration,
+}
+
+impl Default for PollOptions {
+    fn default() -> Self {
         Self {
-            speech_to_text_profile: SpeechToTextProfile::default(),
-            client: Client::new(),
-            api_key: keychain.get_api_key("STABLE_DIFFUSION").unwrap(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(600),
         }
     }
+}
+
+/// The super resolution endpoint answers with a single URL.
+fn deserialize_output<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Output {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Output::deserialize(deserializer)? {
+        Output::One(url) if url.is_empty() => Vec::new(),
+        Output::One(url) => vec![url],
+        Output::Many(urls) => urls,
+    })
+}
+
+fn deserialize_message<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<String, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(message) => message,
+        value => value.to_string(),
+    })
+}
+
+/// Name of the keychain entry holding the API keys.
+pub const API_KEY_NAME: &str = "STABLE_DIFFUSION";
+
+impl StableDiffusionConnector {
+    /// Fails when the keychain has no [`API_KEY_NAME`] key.
+    pub fn new(keychain: &KeyChain) -> std::result::Result<Self, KeyError> {
+        keychain.api_keys(API_KEY_NAME)?;
+        Ok(Self {
+            profile: TextToImageProfile::default(),
+            client: Client::new(),
+            keychain: keychain.clone(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: RateLimitState::default(),
+            cassette: None,
+            middlewares: Middlewares::default(),
+            poll_options: PollOptions::default(),
+            webhook: None,
+        })
+    }
+
+    pub fn with_profile(mut self, profile: TextToImageProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// How [`ImageGenerator::generate`] waits for the images.
+    pub fn with_poll_options(mut self, poll_options: PollOptions) -> Self {
+        self.poll_options = poll_options;
+        self
+    }
+
+    /// Wait for the callbacks of `listener` instead of polling the generations, the listener can
+    /// be shared between connectors.
+    pub fn with_webhook(mut self, listener: Arc<WebhookListener>) -> Self {
+        self.webhook = Some(listener);
+        self
+    }
+
+    pub fn profile(&self) -> &TextToImageProfile {
+        &self.profile
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Record the exchanges to (or replay them from) `cassette`.
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Run the HTTP exchanges through `middleware`, after the ones already registered.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// Rate limits reported by the last response.
+    pub fn rate_limit(&self) -> Option<RateLimitInfo> {
+        self.rate_limit.get()
+    }
+
+    /// The API endpoint `name` (`fetch/<id>`, `img2img`...), next to the text to image one.
+    fn endpoint(&self, name: &str) -> String {
+        let endpoint = &self.profile.api_endpoint;
+        let base = endpoint.rsplit_once('/').map_or(endpoint.as_str(), |(base, _)| base);
+        format!("{}/{}", base, name)
+    }
+
+    /// Send the request built by `request` (from the API key picked for each attempt) following
+    /// the retry policy, non success responses are turned into errors. A rate limited key is
+    /// left aside by the keychain so the next attempt uses another one.
+    async fn send<T: Serialize>(&self, endpoint: &str, request: impl Fn(String) -> T) -> Result<reqwest::Response> {
+        self.retry_policy
+            .run(|| async {
+                let respect_rate_limit = self.retry_policy.respect_rate_limit_headers;
+                let api_key = match respect_rate_limit {
+                    true => self.rate_limit.select_key(&self.keychain, API_KEY_NAME)?,
+                    false => self.keychain.api_key(API_KEY_NAME)?,
+                };
+                if let Some(delay) = self.rate_limit.delay(&api_key.key).filter(|_| respect_rate_limit) {
+                    return Err(StableDiffusionError::RateLimited {
+                        message: format!("rate limit exhausted, reset in {:?}", delay),
+                        retry_after: Some(delay),
+                    });
+                }
+                let request = request(api_key.key.clone());
+                let builder = self.client.post(endpoint).json(&request);
+                let response = self
+                    .middlewares
+                    .send("stable_diffusion", &self.client, self.cassette.as_ref(), builder, &request)
+                    .await?;
+                self.rate_limit.update(&api_key.key, response.headers());
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(response);
+                }
+                let retry_after = RateLimitInfo::from_headers(response.headers()).delay();
+                let message = response.text().await.unwrap_or_default();
+                Err(if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+                    StableDiffusionError::Unauthorized { message, status }
+                } else if status == StatusCode::TOO_MANY_REQUESTS {
+                    self.keychain.report_rate_limited(API_KEY_NAME, &api_key, retry_after);
+                    StableDiffusionError::RateLimited { message, retry_after }
+                } else if status.is_server_error() {
+                    StableDiffusionError::ServerError { message, status, retry_after }
+                } else {
+                    StableDiffusionError::RequestFailed { message, status }
+                })
+            })
+            .await
+    }
 
     pub async fn generate_image(
         &self,
@@ -71,40 +379,707 @@ impl StableDiffusionConnector {
         negative_prompt: Option<String>,
         seed: Option<u64>,
     ) -> Result<GenerateImageResponse> {
+        self.send_generate_image(prompt, negative_prompt, seed, None).await
+    }
+
+    /// `track_id`: the generation result is posted to the webhook listener along with it.
+    async fn send_generate_image(
+        &self,
+        prompt: String,
+        negative_prompt: Option<String>,
+        seed: Option<u64>,
+        track_id: Option<String>,
+    ) -> Result<GenerateImageResponse> {
+        self.profile.validate()?;
+        let (lora_model, lora_strength) = self.profile.lora_parameters().unzip();
+        let webhook = track_id
+            .as_ref()
+            .and(self.webhook.as_ref())
+            .map(|listener| listener.url().to_string());
+        let request = |key| GenerateImageRequest {
+            profile: &self.profile,
+            key,
+            prompt: prompt.clone(),
+            negative_prompt: negative_prompt.clone(),
+            seed,
+            webhook: webhook.clone(),
+            track_id: track_id.clone(),
+            lora_model: lora_model.clone(),
+            lora_strength: lora_strength.clone(),
+        };
+        let response = self.send(&self.profile.api_endpoint, request).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Upload a local image (as base64), returns its URL. URLs are returned as is.
+    pub async fn upload_image(&self, image: &ImageSource) -> Result<String> {
+        let (data, mime) = match image {
+            ImageSource::Url(url) => return Ok(url.clone()),
+            ImageSource::File(path) => (tokio::fs::read(path).await?, image_mime(path).to_string()),
+            ImageSource::Bytes { data, mime } => (data.clone(), mime.clone()),
+        };
+        let image = format!("data:{};base64,{}", mime, BASE64_STANDARD.encode(data));
+        let request = |key| UploadImageRequest {
+            key,
+            image: image.clone(),
+            crop: "false",
+        };
+        let response = self.send(&self.endpoint("base64_crop"), request).await?;
+        match response.json().await? {
+            UploadImageResponse::Success { link } => Ok(link),
+            UploadImageResponse::Error(error) => Err(StableDiffusionError::GenerationFailed(error.message)),
+        }
+    }
+
+    /// Generate images from `image` and `prompt` with the generation profile. `strength` (from
+    /// `0.0` to `1.0`) is how much the image is changed.
+    pub async fn img2img(
+        &self,
+        image: &ImageSource,
+        prompt: String,
+        negative_prompt: Option<String>,
+        strength: f64,
+        seed: Option<u64>,
+    ) -> Result<GenerateImageResponse> {
+        self.image_to_image("img2img", image, None, prompt, negative_prompt, strength, seed)
+            .await
+    }
+
+    /// Regenerate the white areas of `mask` in `image`.
+    pub async fn inpaint(
+        &self,
+        image: &ImageSource,
+        mask: &ImageSource,
+        prompt: String,
+        negative_prompt: Option<String>,
+        strength: f64,
+        seed: Option<u64>,
+    ) -> Result<GenerateImageResponse> {
+        self.image_to_image("inpaint", image, Some(mask), prompt, negative_prompt, strength, seed)
+            .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn image_to_image(
+        &self,
+        endpoint: &str,
+        image: &ImageSource,
+        mask: Option<&ImageSource>,
+        prompt: String,
+        negative_prompt: Option<String>,
+        strength: f64,
+        seed: Option<u64>,
+    ) -> Result<GenerateImageResponse> {
+        self.profile.validate()?;
+        if !(0.0..=1.0).contains(&strength) {
+            return Err(ProfileError::OutOfRange {
+                field: "strength",
+                value: strength,
+                min: 0.0,
+                max: 1.0,
+            }
+            .into());
+        }
+        let init_image = self.upload_image(image).await?;
+        let mask_image = match mask {
+            Some(mask) => Some(self.upload_image(mask).await?),
+            None => None,
+        };
+        let (lora_model, lora_strength) = self.profile.lora_parameters().unzip();
+        let request = |key| ImageToImageRequest {
+            profile: &self.profile,
+            key,
+            prompt: prompt.clone(),
+            negative_prompt: negative_prompt.clone(),
+            init_image: init_image.clone(),
+            mask_image: mask_image.clone(),
+            strength,
+            seed,
+            webhook: None,
+            track_id: None,
+            lora_model: lora_model.clone(),
+            lora_strength: lora_strength.clone(),
+        };
+        let response = self.send(&self.endpoint(endpoint), request).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Upscale `image` `scale` times (2 to 4), optionally restoring the faces.
+    pub async fn super_resolution(
+        &self,
+        image: &ImageSource,
+        scale: u32,
+        face_enhance: bool,
+    ) -> Result<GenerateImageResponse> {
+        if !(2..=4).contains(&scale) {
+            return Err(ProfileError::OutOfRange {
+                field: "scale",
+                value: scale as f64,
+                min: 2.0,
+                max: 4.0,
+            }
+            .into());
+        }
+        let url = self.upload_image(image).await?;
+        let request = |key| SuperResolutionRequest {
+            key,
+            url: url.clone(),
+            scale,
+            face_enhance,
+            webhook: None,
+        };
+        let response = self.send(&self.endpoint("super_resolution"), request).await?;
+        Ok(response.json().await?)
+    }
+
+    /// The current state of the generation `id`.
+    pub async fn fetch_images(&self, id: u64) -> Result<GenerateImageResponse> {
+        self.fetch(&self.endpoint(&format!("fetch/{}", id))).await
+    }
+
+    async fn fetch(&self, endpoint: &str) -> Result<GenerateImageResponse> {
+        let request = |key| FetchImageRequest { key };
+        Ok(self.send(endpoint, request).await?.json().await?)
+    }
+
+    /// Fetch a processing generation until its images are ready, waiting for the ETA given by
+    /// the API (or `options.interval`) between two fetches.
+    pub async fn wait_for_images(
+        &self,
+        response: GenerateImageResponse,
+        options: &PollOptions,
+    ) -> Result<GeneratedImages> {
+        self.poll_images(response, options, Instant::now()).await
+    }
+
+    /// [`StableDiffusionConnector::wait_for_images`] giving up `options.timeout` after `start`.
+    async fn poll_images(
+        &self,
+        response: GenerateImageResponse,
+        options: &PollOptions,
+        start: Instant,
+    ) -> Result<GeneratedImages> {
+        let mut response = response;
+        loop {
+            let processing = match response {
+                GenerateImageResponse::Success(images) => return Ok(images),
+                GenerateImageResponse::Error(error) => {
+                    return Err(StableDiffusionError::GenerationFailed(error.message))
+                }
+                GenerateImageResponse::Processing(processing) => processing,
+            };
+            let elapsed = start.elapsed();
+            if elapsed >= options.timeout {
+                return Err(StableDiffusionError::Timeout {
+                    id: processing.id,
+                    elapsed,
+                });
+            }
+            let delay = processing
+                .eta
+                .filter(|eta| eta.is_finite() && *eta > 0.0)
+                .map_or(options.interval, Duration::from_secs_f64);
+            tokio::time::sleep(delay.min(options.timeout - elapsed)).await;
+            response = match &processing.fetch_result {
+                Some(endpoint) => self.fetch(endpoint).await?,
+                None => self.fetch_images(processing.id).await?,
+            };
+        }
+    }
+
+    /// [`StableDiffusionConnector::generate_image`] then wait for the images, `options.timeout`
+    /// in all. With a webhook listener, the callback is waited for during half of it, then the
+    /// generation is polled in case the callback was lost.
+    pub async fn generate_image_and_wait(
+        &self,
+        prompt: String,
+        negative_prompt: Option<String>,
+        seed: Option<u64>,
+        options: &PollOptions,
+    ) -> Result<GeneratedImages> {
+        let Some(listener) = &self.webhook else {
+            let response = self.generate_image(prompt, negative_prompt, seed).await?;
+            return self.wait_for_images(response, options).await;
+        };
+        let start = Instant::now();
+        let pending = listener.register();
+        let track_id = Some(pending.track_id().to_string());
+        let response = match self.send_generate_image(prompt, negative_prompt, seed, track_id).await? {
+            GenerateImageResponse::Processing(processing) => {
+                let remaining = options.timeout.saturating_sub(start.elapsed());
+                match pending.wait(remaining / 2).await {
+                    Ok(response) => response,
+                    Err(WebhookError::Timeout { .. }) => GenerateImageResponse::Processing(processing),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            response => response,
+        };
+        self.poll_images(response, options, start).await
+    }
+
+    /// Save every image of `images` in `directory` (created if needed) as `<id>-<index>.<ext>`,
+    /// the extension follows the response's content type. Returns the written files.
+    pub async fn download_images(&self, images: &GeneratedImages, directory: &Path) -> Result<Vec<PathBuf>> {
+        let mut downloaded = Vec::with_capacity(images.output.len());
+        for url in images.output.iter() {
+            downloaded.push(self.download_image(url).await?);
+        }
+        Ok(save_images(&downloaded, directory, &images.id.to_string()).await?)
+    }
+
+    /// Fetch the image at `url`, its type is read from the response (or else from the URL).
+    pub async fn download_image(&self, url: &str) -> Result<Image> {
         let response = self
-            .client
-            .post(self.speech_to_text_profile.api_endpoint.clone())
-            .json(&GenerateImageRequest {
-                profile: &self.speech_to_text_profile,
-                key: self.api_key.clone(),
-                prompt,
-                negative_prompt,
-                seed,
-                webhook: None,
-                track_id: None,
+            .retry_policy
+            .run(|| async {
+                let builder = self.client.get(url);
+                let response = self.middlewares.send("stable_diffusion", &self.client, None, builder, &()).await?;
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(response);
+                }
+                let message = response.text().await.unwrap_or_default();
+                Err(if status.is_server_error() {
+                    StableDiffusionError::ServerError {
+                        message,
+                        status,
+                        retry_after: None,
+                    }
+                } else {
+                    StableDiffusionError::RequestFailed { message, status }
+                })
             })
-            .send()
             .await?;
-        Ok(response.json().await?)
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .filter(|content_type| mime_extension(content_type).is_some())
+            .map(str::to_string);
+        let mime = content_type
+            .or_else(|| url_extension(url).and_then(extension_mime).map(str::to_string))
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+        Ok(Image::new(response.bytes().await?.to_vec(), mime))
     }
 }
 
-impl Default for SpeechToTextProfile {
-    fn default() -> Self {
-        Self {
-            api_endpoint: "https://stablediffusionapi.com/api/v3/text2img".to_string(),
-            width: "720".to_string(),
-            height: "480".to_string(),
-            samples: "1".to_string(),
-            num_inference_steps: "100".to_string(),
-            safety_checker: "no".to_string(),
-            enhance_prompt: "no".to_string(),
-            guidance_scale: 10.0,
-            multi_lingual: "no".to_string(),
-            panorama: "no".to_string(),
-            self_attention: "no".to_string(),
-            upscale: "no".to_string(),
-            embeddings_model: None,
+impl ImageGenerator for StableDiffusionConnector {
+    type Error = StableDiffusionError;
+
+    async fn generate<'a>(&'a self, request: &'a ImageRequest) -> Result<Vec<Image>> {
+        let images = self
+            .generate_image_and_wait(
+                request.prompt.clone(),
+                request.negative_prompt.clone(),
+                request.seed,
+                &self.poll_options,
+            )
+            .await?;
+        let mut downloaded = Vec::with_capacity(images.output.len());
+        for url in images.output.iter() {
+            downloaded.push(self.download_image(url).await?);
         }
+        Ok(downloaded)
+    }
+}
+
+/// Content type of a local image, from its extension.
+fn image_mime(path: &Path) -> &'static str {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(extension_mime)
+        .unwrap_or("image/png")
+}
+
+/// Extension of the file name of `url`.
+fn url_extension(url: &str) -> Option<&str> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let name = path.rsplit_once('/').map_or(path, |(_, name)| name);
+    name.rsplit_once('.').map(|(_, extension)| extension)
+}
+
+impl Retryable for StableDiffusionError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::RateLimited { .. } | Self::ServerError { .. } => true,
+            Self::HttpClientError(e) => e.is_timeout() || e.is_connect(),
+            _ => false,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } | Self::ServerError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_retry_server_error() {
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .respond_with(ResponseTemplate::new(502))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::body_partial_json(serde_json::json!({"key": "sd-test", "prompt": "an elf"})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "success",
+                "id": 42,
+                "output": ["https://example.com/42.png"],
+                "meta": {}
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let keychain = KeyChain::new();
+        keychain.set_api_key("STABLE_DIFFUSION", "sd-test".to_string());
+        let connector = StableDiffusionConnector::new(&keychain).unwrap()
+            .with_profile(TextToImageProfile {
+                api_endpoint: server.uri(),
+                ..Default::default()
+            })
+            .with_retry_policy(RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            });
+        let response = connector.generate_image("an elf".to_string(), None, None).await.unwrap();
+        assert!(matches!(response, GenerateImageResponse::Success(images) if images.id == 42));
+    }
+
+    #[tokio::test]
+    async fn test_key_failover() {
+        let server = MockServer::start().await;
+        Mock::given(matchers::body_partial_json(serde_json::json!({"key": "sd-a"})))
+            .respond_with(ResponseTemplate::new(429))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::body_partial_json(serde_json::json!({"key": "sd-b"})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "success",
+                "id": 42,
+                "output": ["https://example.com/42.png"],
+                "meta": {}
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let keychain = KeyChain::new().with_key_file(
+            serde_yaml::from_str("profiles:\n  default:\n    STABLE_DIFFUSION: [sd-a, sd-b]\n").unwrap(),
+        );
+        let connector = StableDiffusionConnector::new(&keychain)
+            .unwrap()
+            .with_profile(TextToImageProfile {
+                api_endpoint: server.uri(),
+                ..Default::default()
+            })
+            .with_retry_policy(RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                ..Default::default()
+            });
+        let response = connector.generate_image("an elf".to_string(), None, None).await.unwrap();
+        assert!(matches!(response, GenerateImageResponse::Success(images) if images.id == 42));
+    }
+
+    #[tokio::test]
+    async fn test_poll_and_download() {
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/api/v3/text2img"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "processing",
+                "id": 7,
+                "eta": 0.01,
+                "output": "",
+                "messege": "Try to fetch request after given estimated time"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/api/v3/fetch/7"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "processing",
+                "id": 7
+            })))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/api/v3/fetch/7"))
+            .and(matchers::body_json(serde_json::json!({"key": "sd-test"})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "success",
+                "id": 7,
+                "output": [format!("{}/images/7-a", server.uri()), format!("{}/images/7-b.webp?size=1", server.uri())]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path("/images/7-a"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(b"png".to_vec(), "image/png"))
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path("/images/7-b.webp"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(b"webp".to_vec(), "application/octet-stream"))
+            .mount(&server)
+            .await;
+
+        let keychain = KeyChain::new();
+        keychain.set_api_key("STABLE_DIFFUSION", "sd-test".to_string());
+        let connector = StableDiffusionConnector::new(&keychain).unwrap().with_profile(TextToImageProfile {
+            api_endpoint: format!("{}/api/v3/text2img", server.uri()),
+            ..Default::default()
+        });
+        let options = PollOptions {
+            interval: Duration::from_millis(1),
+            timeout: Duration::from_secs(5),
+        };
+        let images = connector
+            .generate_image_and_wait("an elf".to_string(), None, None, &options)
+            .await
+            .unwrap();
+        assert_eq!(images.output.len(), 2);
+
+        let directory = tempfile::tempdir().unwrap();
+        let paths = connector.download_images(&images, directory.path()).await.unwrap();
+        assert_eq!(paths, [directory.path().join("7-0.png"), directory.path().join("7-1.webp")]);
+        assert_eq!(std::fs::read(&paths[0]).unwrap(), b"png");
+
+        let failed = serde_json::from_value(serde_json::json!({"status": "error", "message": {"prompt": ["required"]}}));
+        let failed: GenerateImageResponse = failed.unwrap();
+        assert!(matches!(
+            connector.wait_for_images(failed, &options).await,
+            Err(StableDiffusionError::GenerationFailed(message)) if message.contains("required")
+        ));
+        let processing = GenerateImageResponse::Processing(ProcessingImages {
+            id: 8,
+            eta: None,
+            fetch_result: None,
+            future_links: Vec::new(),
+            meta: serde_json::Value::Null,
+        });
+        let options = PollOptions {
+            timeout: Duration::ZERO,
+            ..options
+        };
+        assert!(matches!(
+            connector.wait_for_images(processing, &options).await,
+            Err(StableDiffusionError::Timeout { id: 8, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_image_to_image() {
+        let server = MockServer::start().await;
+        let uploaded = format!("{}/uploads/base.png", server.uri());
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/api/v3/base64_crop"))
+            .and(matchers::body_partial_json(serde_json::json!({"image": "data:image/png;base64,YmFzZQ=="})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "success",
+                "link": uploaded
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/api/v3/img2img"))
+            .and(matchers::body_partial_json(serde_json::json!({
+                "init_image": uploaded,
+                "strength": 0.6,
+                "width": "512",
+                "lora_model": "arcane"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "success",
+                "id": 1,
+                "output": ["https://example.com/1.png"]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/api/v3/inpaint"))
+            .and(matchers::body_partial_json(serde_json::json!({
+                "init_image": "https://example.com/card.png",
+                "mask_image": "https://example.com/face.png"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "processing",
+                "id": 2,
+                "eta": 3.5
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/api/v3/super_resolution"))
+            .and(matchers::body_partial_json(serde_json::json!({"url": "https://example.com/1.png", "scale": 4})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "success",
+                "id": 3,
+                "output": "https://example.com/3.png"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let keychain = KeyChain::new();
+        keychain.set_api_key("STABLE_DIFFUSION", "sd-test".to_string());
+        let connector = StableDiffusionConnector::new(&keychain).unwrap().with_profile(TextToImageProfile {
+            api_endpoint: format!("{}/api/v3/text2img", server.uri()),
+            width: 512,
+            loras: vec![Lora {
+                model: "arcane".to_string(),
+                strength: 0.8,
+            }],
+            ..Default::default()
+        });
+
+        let directory = tempfile::tempdir().unwrap();
+        let base = directory.path().join("base.png");
+        std::fs::write(&base, b"base").unwrap();
+        let response = connector
+            .img2img(&ImageSource::from(base), "an elf".to_string(), None, 0.6, None)
+            .await
+            .unwrap();
+        assert!(matches!(response, GenerateImageResponse::Success(images) if images.output == ["https://example.com/1.png"]));
+
+        let response = connector
+            .inpaint(
+                &"https://example.com/card.png".into(),
+                &"https://example.com/face.png".into(),
+                "an elf face".to_string(),
+                None,
+                1.0,
+                Some(3),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(response, GenerateImageResponse::Processing(ProcessingImages { id: 2, eta: Some(_), .. })));
+        assert!(matches!(
+            connector.img2img(&"https://example.com/card.png".into(), String::new(), None, 1.5, None).await,
+            Err(StableDiffusionError::InvalidProfile(ProfileError::OutOfRange { field: "strength", .. }))
+        ));
+
+        let response = connector
+            .super_resolution(&"https://example.com/1.png".into(), 4, true)
+            .await
+            .unwrap();
+        assert!(matches!(response, GenerateImageResponse::Success(images) if images.output == ["https://example.com/3.png"]));
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        let server = MockServer::start().await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/api/v3/text2img"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "processing",
+                "id": 9,
+                "eta": 30.0
+            })))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let listener = Arc::new(WebhookListener::bind(([127, 0, 0, 1], 0).into()).unwrap());
+        let keychain = KeyChain::new();
+        keychain.set_api_key("STABLE_DIFFUSION", "sd-test".to_string());
+        let connector = StableDiffusionConnector::new(&keychain).unwrap()
+            .with_profile(TextToImageProfile {
+                api_endpoint: format!("{}/api/v3/text2img", server.uri()),
+                ..Default::default()
+            })
+            .with_webhook(listener.clone());
+        let options = PollOptions {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_secs(5),
+        };
+        let callback = async {
+            let request = loop {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                if let Some(request) = server.received_requests().await.unwrap().pop() {
+                    break request.body_json::<serde_json::Value>().unwrap();
+                }
+            };
+            assert_eq!(request["webhook"], listener.url());
+            let client = Client::new();
+            let unknown = client
+                .post(listener.url())
+                .json(&serde_json::json!({"track_id": "unknown", "status": "success", "id": 9, "output": []}))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+            let response = client
+                .post(listener.url())
+                .json(&serde_json::json!({
+                    "track_id": request["track_id"],
+                    "webhook_status": "success",
+                    "status": "success",
+                    "id": 9,
+                    "output": ["https://example.com/9.png"]
+                }))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        };
+        let (images, ()) = tokio::join!(
+            connector.generate_image_and_wait("an elf".to_string(), None, None, &options),
+            callback
+        );
+        assert_eq!(images.unwrap().output, ["https://example.com/9.png"]);
+
+        // Without a callback, the generation is polled until the same deadline.
+        Mock::given(matchers::path("/api/v3/fetch/9"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"status": "processing", "id": 9})))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(matchers::path("/api/v3/fetch/9"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "success",
+                "id": 9,
+                "output": ["https://example.com/9-polled.png"]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let options = PollOptions {
+            timeout: Duration::from_millis(50),
+            ..options
+        };
+        // Times out on the first (processing) poll, the mock expectations check nothing more was sent.
+        assert!(matches!(
+            connector.generate_image_and_wait("an elf".to_string(), None, None, &options).await,
+            Err(StableDiffusionError::Timeout { id: 9, .. })
+        ));
+        let options = PollOptions {
+            timeout: Duration::from_secs(1),
+            ..options
+        };
+        let images = connector.generate_image_and_wait("an elf".to_string(), None, None, &options).await;
+        assert_eq!(images.unwrap().output, ["https://example.com/9-polled.png"]);
+        assert_eq!(listener.pending(), 0);
     }
 }
@@ -0,0 +1,315 @@
+use crate::prelude::*;
+use serde::Serializer;
+use thiserror::Error;
+
+pub const DEFAULT_TEXT_TO_IMAGE_ENDPOINT: &str = "https://stablediffusionapi.com/api/v3/text2img";
+pub const MIN_DIMENSION: u32 = 64;
+pub const MAX_DIMENSION: u32 = 1024;
+pub const MAX_SAMPLES: u32 = 4;
+pub const MAX_INFERENCE_STEPS: u32 = 50;
+pub const MAX_GUIDANCE_SCALE: f64 = 20.0;
+
+/// Why a [`TextToImageProfile`] would be rejected by the API.
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ProfileError {
+    #[error("`{}` must be a multiple of 8 between {} and {}, got {}", field, MIN_DIMENSION, MAX_DIMENSION, value)]
+    InvalidDimension { field: &'static str, value: u32 },
+    #[error("`{}` must be between {} and {}, got {}", field, min, max, value)]
+    OutOfRange {
+        field: &'static str,
+        value: f64,
+        min: f64,
+        max: f64,
+    },
+    #[error("`{}` must not be empty", _0)]
+    Empty(&'static str),
+    #[error("`{}` is not supported by {}", field, backend)]
+    Unsupported { field: &'static str, backend: &'static str },
+}
+
+/// Generation settings of the text to image endpoint, loadable from YAML.
+///
+/// Fields are typed, they are sent in the (stringly typed) wire format of the API: numbers as
+/// strings and booleans as `"yes"` / `"no"`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TextToImageProfile {
+    #[serde(skip_serializing)]
+    pub api_endpoint: String,
+    /// Community model (`model_id`), the API's default model when `None`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_id: Option<String>,
+    #[serde(serialize_with = "as_string")]
+    pub width: u32,
+    #[serde(serialize_with = "as_string")]
+    pub height: u32,
+    /// Images per generation.
+    #[serde(serialize_with = "as_string")]
+    pub samples: u32,
+    #[serde(serialize_with = "as_string")]
+    pub num_inference_steps: u32,
+    pub guidance_scale: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduler: Option<Scheduler>,
+    /// Sampler of Automatic1111 like servers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampler: Option<Sampler>,
+    #[serde(serialize_with = "yes_no")]
+    pub safety_checker: bool,
+    #[serde(serialize_with = "yes_no")]
+    pub enhance_prompt: bool,
+    #[serde(serialize_with = "yes_no")]
+    pub multi_lingual: bool,
+    #[serde(serialize_with = "yes_no")]
+    pub panorama: bool,
+    #[serde(serialize_with = "yes_no")]
+    pub self_attention: bool,
+    /// Upscale the images two times.
+    #[serde(serialize_with = "yes_no")]
+    pub upscale: bool,
+    #[serde(serialize_with = "yes_no")]
+    pub use_karras_sigmas: bool,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "option_as_string")]
+    pub clip_skip: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vae: Option<String>,
+    /// Sent as `lora_model` and `lora_strength`.
+    #[serde(skip_serializing)]
+    pub loras: Vec<Lora>,
+    /// Textual inversion embeddings, sent as `embeddings_model`.
+    #[serde(skip_serializing_if = "Vec::is_empty", rename = "embeddings_model", alias = "embeddings", serialize_with = "comma_separated")]
+    pub embeddings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Lora {
+    pub model: String,
+    #[serde(default = "default_lora_strength")]
+    pub strength: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Scheduler {
+    #[serde(rename = "DDPMScheduler")]
+    Ddpm,
+    #[serde(rename = "DDIMScheduler")]
+    Ddim,
+    #[serde(rename = "PNDMScheduler")]
+    Pndm,
+    #[serde(rename = "LMSDiscreteScheduler")]
+    LmsDiscrete,
+    #[serde(rename = "EulerDiscreteScheduler")]
+    EulerDiscrete,
+    #[serde(rename = "EulerAncestralDiscreteScheduler")]
+    EulerAncestralDiscrete,
+    #[serde(rename = "DPMSolverMultistepScheduler")]
+    DpmSolverMultistep,
+    #[serde(rename = "HeunDiscreteScheduler")]
+    HeunDiscrete,
+    #[serde(rename = "KDPM2DiscreteScheduler")]
+    Kdpm2Discrete,
+    #[serde(rename = "DEISMultistepScheduler")]
+    DeisMultistep,
+    #[serde(rename = "UniPCMultistepScheduler")]
+    UniPcMultistep,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Sampler {
+    #[serde(rename = "Euler")]
+    Euler,
+    #[serde(rename = "Euler a")]
+    EulerAncestral,
+    #[serde(rename = "LMS")]
+    Lms,
+    #[serde(rename = "Heun")]
+    Heun,
+    #[serde(rename = "DPM2")]
+    Dpm2,
+    #[serde(rename = "DPM2 a")]
+    Dpm2Ancestral,
+    #[serde(rename = "DPM++ 2S a")]
+    DpmPlusPlus2SAncestral,
+    #[serde(rename = "DPM++ 2M")]
+    DpmPlusPlus2M,
+    #[serde(rename = "DPM++ SDE")]
+    DpmPlusPlusSde,
+    #[serde(rename = "DPM++ 2M Karras")]
+    DpmPlusPlus2MKarras,
+    #[serde(rename = "DPM++ SDE Karras")]
+    DpmPlusPlusSdeKarras,
+    #[serde(rename = "DDIM")]
+    Ddim,
+    #[serde(rename = "PLMS")]
+    Plms,
+    #[serde(rename = "UniPC")]
+    UniPc,
+}
+
+impl Scheduler {
+    /// The equivalent sampler of Automatic1111 like servers, if any.
+    pub fn sampler(&self) -> Option<Sampler> {
+        match self {
+            Self::Ddim => Some(Sampler::Ddim),
+            Self::Pndm => Some(Sampler::Plms),
+            Self::LmsDiscrete => Some(Sampler::Lms),
+            Self::EulerDiscrete => Some(Sampler::Euler),
+            Self::EulerAncestralDiscrete => Some(Sampler::EulerAncestral),
+            Self::DpmSolverMultistep => Some(Sampler::DpmPlusPlus2M),
+            Self::HeunDiscrete => Some(Sampler::Heun),
+            Self::Kdpm2Discrete => Some(Sampler::Dpm2),
+            Self::UniPcMultistep => Some(Sampler::UniPc),
+            Self::Ddpm | Self::DeisMultistep => None,
+        }
+    }
+}
+
+fn default_lora_strength() -> f64 {
+    1.0
+}
+
+impl Default for TextToImageProfile {
+    fn default() -> Self {
+        Self {
+            api_endpoint: DEFAULT_TEXT_TO_IMAGE_ENDPOINT.to_string(),
+            model_id: None,
+            width: 720,
+            height: 480,
+            samples: 1,
+            num_inference_steps: 30,
+            guidance_scale: 10.0,
+            scheduler: None,
+            sampler: None,
+            safety_checker: false,
+            enhance_prompt: false,
+            multi_lingual: false,
+            panorama: false,
+            self_attention: false,
+            upscale: false,
+            use_karras_sigmas: false,
+            clip_skip: None,
+            vae: None,
+            loras: Vec::new(),
+            embeddings: Vec::new(),
+        }
+    }
+}
+
+impl TextToImageProfile {
+    /// Check the settings against the limits of the API.
+    pub fn validate(&self) -> std::result::Result<(), ProfileError> {
+        for (field, value) in [("width", self.width), ("height", self.height)] {
+            if value % 8 != 0 || !(MIN_DIMENSION..=MAX_DIMENSION).contains(&value) {
+                return Err(ProfileError::InvalidDimension { field, value });
+            }
+        }
+        check_range("samples", self.samples as f64, 1.0, MAX_SAMPLES as f64)?;
+        check_range("num_inference_steps", self.num_inference_steps as f64, 1.0, MAX_INFERENCE_STEPS as f64)?;
+        check_range("guidance_scale", self.guidance_scale, 1.0, MAX_GUIDANCE_SCALE)?;
+        if let Some(clip_skip) = self.clip_skip {
+            check_range("clip_skip", clip_skip as f64, 1.0, 8.0)?;
+        }
+        for lora in self.loras.iter() {
+            if lora.model.is_empty() {
+                return Err(ProfileError::Empty("loras.model"));
+            }
+            check_range("loras.strength", lora.strength, 0.0, 1.0)?;
+        }
+        if self.embeddings.iter().any(String::is_empty) {
+            return Err(ProfileError::Empty("embeddings"));
+        }
+        Ok(())
+    }
+
+    /// `lora_model` and `lora_strength` of the request, `None` without LoRA.
+    pub fn lora_parameters(&self) -> Option<(String, String)> {
+        if self.loras.is_empty() {
+            return None;
+        }
+        let models = self.loras.iter().map(|lora| lora.model.as_str()).collect::<Vec<_>>();
+        let strengths = self.loras.iter().map(|lora| lora.strength.to_string()).collect::<Vec<_>>();
+        Some((models.join(","), strengths.join(",")))
+    }
+}
+
+fn check_range(field: &'static str, value: f64, min: f64, max: f64) -> std::result::Result<(), ProfileError> {
+    match value >= min && value <= max {
+        true => Ok(()),
+        false => Err(ProfileError::OutOfRange { field, value, min, max }),
+    }
+}
+
+fn as_string<S: Serializer>(value: &u32, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn option_as_string<S: Serializer>(value: &Option<u32>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn yes_no<S: Serializer>(value: &bool, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(if *value { "yes" } else { "no" })
+}
+
+fn comma_separated<S: Serializer>(values: &[String], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&values.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_wire_format() {
+        let profile: TextToImageProfile = serde_json::from_value(serde_json::json!({
+            "width": 512,
+            "height": 768,
+            "num_inference_steps": 40,
+            "scheduler": "DPMSolverMultistepScheduler",
+            "upscale": true,
+            "loras": [{"model": "arcane-style", "strength": 0.6}, {"model": "more_details"}],
+            "embeddings": ["easynegative"],
+        }))
+        .unwrap();
+        assert_eq!(profile.validate(), Ok(()));
+        let wire = serde_json::to_value(&profile).unwrap();
+        assert_eq!(wire["width"], "512");
+        assert_eq!(wire["num_inference_steps"], "40");
+        assert_eq!(wire["upscale"], "yes");
+        assert_eq!(wire["safety_checker"], "no");
+        assert_eq!(wire["scheduler"], "DPMSolverMultistepScheduler");
+        assert_eq!(wire["embeddings_model"], "easynegative");
+        assert!(wire.get("api_endpoint").is_none() && wire.get("loras").is_none() && wire.get("clip_skip").is_none());
+        assert_eq!(
+            profile.lora_parameters(),
+            Some(("arcane-style,more_details".to_string(), "0.6,1".to_string()))
+        );
+
+        let invalid = TextToImageProfile {
+            width: 500,
+            ..profile.clone()
+        };
+        assert_eq!(
+            invalid.validate(),
+            Err(ProfileError::InvalidDimension { field: "width", value: 500 })
+        );
+        let invalid = TextToImageProfile {
+            num_inference_steps: 100,
+            ..profile
+        };
+        assert!(matches!(invalid.validate(), Err(ProfileError::OutOfRange { field: "num_inference_steps", .. })));
+    }
+
+    #[test]
+    fn test_presets() {
+        for preset in ["portrait_card", "landscape", "upscale"] {
+            let path = format!("../../data/profiles/stable_diffusion/{}.yaml", preset);
+            let profile: TextToImageProfile = serde_yaml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            assert_eq!(profile.validate(), Ok(()), "{}", path);
+            assert_eq!(profile.api_endpoint, DEFAULT_TEXT_TO_IMAGE_ENDPOINT);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use crate::chat::*;
+use tiktoken_rs::CoreBPE;
+
+/// Tokens added to every message by the chat format (`<|start|>{role}\n{content}<|end|>\n`).
+const TOKENS_PER_MESSAGE: usize = 3;
+/// Extra token when a message has a `name`.
+const TOKENS_PER_NAME: usize = 1;
+/// Every reply is primed with `<|start|>assistant<|message|>`.
+const REPLY_PRIMING: usize = 3;
+/// Tokens of a `low` detail image.
+const TOKENS_PER_LOW_DETAIL_IMAGE: usize = 85;
+/// Tokens of a 1024x1024 `high` (or `auto`) detail image, the actual cost depends on its size.
+const TOKENS_PER_IMAGE: usize = 765;
+
+/// Context window of known models, models are matched by their longest prefix.
+const CONTEXT_WINDOWS: [(&str, usize); 16] = [
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-3.5-turbo-0301", 4_096),
+    ("gpt-3.5-turbo-0613", 4_096),
+    ("gpt-3.5-turbo-16k", 16_385),
+    ("gpt-4", 8_192),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4-0125", 128_000),
+    ("gpt-4-1106", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-vision", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("o1", 200_000),
+    ("o1-mini", 128_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+];
+
+/// The BPE encodings used by the chat models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// `gpt-4`, `gpt-3.5-turbo` and the embedding models.
+    Cl100kBase,
+    /// `gpt-4o`, `gpt-4.1` and the `o` series.
+    O200kBase,
+}
+
+/// Counts tokens the way the OpenAI API does.
+///
+/// Other servers use their own tokenizer so counts are only an estimate for them.
+#[derive(Clone, Copy)]
+pub struct Tokenizer {
+    encoding: Encoding,
+    bpe: &'static CoreBPE,
+}
+
+impl Encoding {
+    /// The encoding of `model`, models we do not know about are assumed to use `cl100k_base`.
+    pub fn for_model(model: &str) -> Self {
+        let o200k = ["gpt-4o", "gpt-4.1", "gpt-5", "o1", "o3", "o4", "chatgpt-4o"];
+        if o200k.iter().any(|prefix| model.starts_with(prefix)) {
+            Self::O200kBase
+        } else {
+            Self::Cl100kBase
+        }
+    }
+}
+
+impl Tokenizer {
+    /// The rank tables are parsed once, on the first use of an encoding.
+    pub fn new(encoding: Encoding) -> Self {
+        let bpe = match encoding {
+            Encoding::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+            Encoding::O200kBase => tiktoken_rs::o200k_base_singleton(),
+        };
+        Self { encoding, bpe }
+    }
+
+    pub fn for_model(model: &str) -> Self {
+        Self::new(Encoding::for_model(model))
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Encode `text`, special tokens are encoded as regular text.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        self.bpe.encode_ordinary(text)
+    }
+
+    /// `None` when a token is not part of the encoding.
+    pub fn decode(&self, tokens: &[u32]) -> Option<String> {
+        let bytes = self.bpe.decode_bytes(tokens).ok()?;
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    /// Tokens of a message's text and images.
+    pub fn count_content(&self, content: &MessageContent) -> usize {
+        let images = content
+            .images()
+            .map(|image| match image.detail {
+                Some(ImageDetail::Low) => TOKENS_PER_LOW_DETAIL_IMAGE,
+                _ => TOKENS_PER_IMAGE,
+            })
+            .sum::<usize>();
+        self.count(&content.text()) + images
+    }
+
+    /// Tokens used by `messages` in a chat completion prompt, reply priming included.
+    pub fn count_messages(&self, messages: &[ChatCompletionMessage]) -> usize {
+        let mut tokens = REPLY_PRIMING;
+        for message in messages {
+            tokens += TOKENS_PER_MESSAGE + self.count(message.role.as_str()) + self.count_content(&message.content);
+            if let Some(name) = &message.name {
+                tokens += TOKENS_PER_NAME + self.count(name);
+            }
+            if let Some(call) = &message.function_call {
+                tokens += self.count(&call.name) + self.count(&call.arguments);
+            }
+        }
+        tokens
+    }
+
+    /// Tokens used by the function definitions.
+    ///
+    /// The API renders them in an undocumented format, their JSON size is a close upper bound.
+    pub fn count_functions(&self, functions: &[ChatCompletionFunction]) -> usize {
+        functions
+            .iter()
+            .map(|function| serde_json::to_string(function).map_or(0, |json| self.count(&json)))
+            .sum()
+    }
+
+    /// Tokens used by the prompt of `request`.
+    pub fn count_request(&self, request: &ChatCompletionRequest<'_>) -> usize {
+        self.count_messages(request.messages) + request.functions.map_or(0, |functions| self.count_functions(functions))
+    }
+
+    /// Usage of `response` estimated from its answers, for the servers that do not report it.
+    pub fn estimate_usage(&self, prompt_tokens: usize, response: &ChatCompletionResponse) -> Usage {
+        let completion_tokens = response
+            .choices
+            .iter()
+            .map(|choice| {
+                let call = choice.message.function_call.as_ref();
+                self.count_content(&choice.message.content)
+                    + call.map_or(0, |call| self.count(&call.name) + self.count(&call.arguments))
+            })
+            .sum::<usize>();
+        Usage {
+            prompt_tokens: prompt_tokens as u64,
+            completion_tokens: completion_tokens as u64,
+            total_tokens: (prompt_tokens + completion_tokens) as u64,
+        }
+    }
+}
+
+impl std::fmt::Debug for Tokenizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Tokenizer").field(&self.encoding).finish()
+    }
+}
+
+/// The number of tokens (prompt and completion) `model` can handle, `None` for unknown models.
+pub fn context_window(model: &str) -> Option<usize> {
+    CONTEXT_WINDOWS
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, window)| *window)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenizer() {
+        let tokenizer = Tokenizer::for_model("gpt-4-0613");
+        assert_eq!(tokenizer.encoding(), Encoding::Cl100kBase);
+        let tokens = tokenizer.encode("hello world");
+        assert_eq!(tokens, [15339, 1917]);
+        assert_eq!(tokenizer.decode(&tokens).as_deref(), Some("hello world"));
+        assert_eq!(Tokenizer::for_model("gpt-4o").encode("hello world"), [24912, 2375]);
+
+        // Matches the prompt_tokens reported by the API for this conversation.
+        let messages = [
+            ChatCompletionMessage::new(ChatCompletionRole::System, "You are a helpful assistant.".to_string()),
+            ChatCompletionMessage::new(ChatCompletionRole::User, "Hello!".to_string()),
+        ];
+        assert_eq!(tokenizer.count_messages(&messages), 19);
+        let response = ChatCompletionResponse {
+            id: "chatcmpl-1".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: "gpt-4-0613".to_string(),
+            choices: vec![ChatCompletionChoice {
+                message: ChatCompletionMessage::new(ChatCompletionRole::Assistant, "Hello!".to_string()),
+                index: 0,
+                finish_reason: "stop".to_string(),
+            }],
+            usage: None,
+        };
+        let usage = tokenizer.estimate_usage(19, &response);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (19, 2, 21));
+
+        assert_eq!(context_window("gpt-4-0613"), Some(8_192));
+        assert_eq!(context_window("gpt-4-32k-0613"), Some(32_768));
+        assert_eq!(context_window("gpt-4o-mini"), Some(128_000));
+        assert_eq!(context_window("mistral-7b-instruct"), None);
+    }
+}
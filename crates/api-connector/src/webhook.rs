@@ -0,0 +1,155 @@
+use crate::{prelude::*, stable_diffusion::GenerateImageResponse};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("webhook server error: {}", _0)]
+    Server(#[from] hyper::Error),
+    #[error("no webhook received for {} after {:?}", track_id, elapsed)]
+    Timeout { track_id: String, elapsed: Duration },
+    #[error("webhook listener closed before {} was received", _0)]
+    Closed(String),
+}
+
+pub type Result<T> = std::result::Result<T, WebhookError>;
+
+type PendingSenders = Arc<Mutex<HashMap<String, oneshot::Sender<GenerateImageResponse>>>>;
+
+/// An embedded HTTP server receiving the generation callbacks of the Stable Diffusion API.
+///
+/// Every generation registers a `track_id`, the API posts the result along with it to the
+/// webhook URL once the images are ready. The server stops when the listener is dropped.
+pub struct WebhookListener {
+    local_addr: SocketAddr,
+    url: String,
+    pending: PendingSenders,
+    _shutdown: oneshot::Sender<()>,
+}
+
+/// A callback body: the fetch response plus the `track_id` of the request.
+#[derive(Debug, Deserialize)]
+pub struct WebhookPayload {
+    pub track_id: String,
+    #[serde(flatten)]
+    pub response: GenerateImageResponse,
+}
+
+/// A registered `track_id`, unregistered once dropped.
+pub struct PendingWebhook {
+    track_id: String,
+    receiver: oneshot::Receiver<GenerateImageResponse>,
+    pending: PendingSenders,
+}
+
+impl WebhookListener {
+    /// Start listening on `addr` (must be called from a tokio runtime). The webhook URL is
+    /// `http://<local address>`, see [`WebhookListener::with_public_url`].
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        let pending = PendingSenders::default();
+        let senders = pending.clone();
+        let make_service = make_service_fn(move |_| {
+            let senders = senders.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |request| handle(senders.clone(), request))) }
+        });
+        let server = Server::try_bind(&addr)?.serve(make_service);
+        let local_addr = server.local_addr();
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = shutdown_signal.await;
+        }));
+        Ok(Self {
+            local_addr,
+            url: format!("http://{}", local_addr),
+            pending,
+            _shutdown: shutdown,
+        })
+    }
+
+    /// The URL given to the API when the listener is only reachable through a proxy or a tunnel.
+    pub fn with_public_url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The URL sent as `webhook`.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Generate a new `track_id` and wait for its callback.
+    pub fn register(&self) -> PendingWebhook {
+        let track_id = format!("{:032x}", rand::random::<u128>());
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(track_id.clone(), sender);
+        PendingWebhook {
+            track_id,
+            receiver,
+            pending: self.pending.clone(),
+        }
+    }
+
+    /// Number of callbacks not received yet.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+impl PendingWebhook {
+    pub fn track_id(&self) -> &str {
+        &self.track_id
+    }
+
+    /// Wait for the callback, at most `timeout`.
+    pub async fn wait(mut self, timeout: Duration) -> Result<GenerateImageResponse> {
+        match tokio::time::timeout(timeout, &mut self.receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(WebhookError::Closed(self.track_id.clone())),
+            Err(_) => Err(WebhookError::Timeout {
+                track_id: self.track_id.clone(),
+                elapsed: timeout,
+            }),
+        }
+    }
+}
+
+impl Drop for PendingWebhook {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.track_id);
+    }
+}
+
+/// Route a callback to its pending generation: `404` for unknown (or timed out) `track_id`s.
+async fn handle(pending: PendingSenders, request: Request<Body>) -> std::result::Result<Response<Body>, Infallible> {
+    if request.method() != Method::POST {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let payload = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => serde_json::from_slice::<WebhookPayload>(&body),
+        Err(_) => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+    let Ok(payload) = payload else {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    };
+    let sender = pending.lock().unwrap().remove(&payload.track_id);
+    Ok(match sender.map(|sender| sender.send(payload.response)) {
+        Some(Ok(())) => status(StatusCode::OK),
+        _ => status(StatusCode::NOT_FOUND),
+    })
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
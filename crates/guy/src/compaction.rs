@@ -0,0 +1,197 @@
+use crate::prelude::*;
+use api_connector::tokenizer::Tokenizer;
+use chrono::{DateTime, Utc};
+
+/// Name of the `System` messages holding a summary written by [`CompactionStrategy::Summarize`].
+pub const SUMMARY_MESSAGE_NAME: &str = "summary";
+
+const DEFAULT_SUMMARY_PROMPT: &str = "Summarize the following conversation between a user and an assistant. \
+Keep every fact, decision and open question needed to continue it, be concise.";
+
+/// How and when a guy's history is compacted.
+///
+/// The compaction runs before a completion once the history has more than `max_messages`
+/// messages, once the prompt is larger than `max_tokens` or when it does not fit in the
+/// provider's context window. `System` messages are always kept.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Compaction {
+    #[serde(flatten)]
+    pub strategy: CompactionStrategy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum CompactionStrategy {
+    /// Keep the last `keep_messages` messages.
+    SlidingWindow { keep_messages: usize },
+    /// Drop the oldest messages until the prompt is at most `target_tokens` tokens.
+    DropOldest { target_tokens: usize },
+    /// Replace all but the last `keep_messages` messages by a summary written by the model.
+    Summarize {
+        keep_messages: usize,
+        /// Instructions given to the model, a generic summary prompt is used when `None`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prompt: Option<String>,
+    },
+}
+
+/// Messages removed from a guy's history by a compaction.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CompactionRecord {
+    pub date: DateTime<Utc>,
+    pub strategy: CompactionStrategy,
+    pub messages: Vec<ChatCompletionMessage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+impl CompactionStrategy {
+    /// Whether the strategy may remove `message`.
+    fn removes(&self, message: &ChatCompletionMessage) -> bool {
+        match self {
+            Self::Summarize { .. } => {
+                message.role != ChatCompletionRole::System || message.name.as_deref() == Some(SUMMARY_MESSAGE_NAME)
+            }
+            _ => message.role != ChatCompletionRole::System,
+        }
+    }
+}
+
+impl Guy {
+    /// Whether the history crossed a threshold of the guy's [`Compaction`].
+    pub fn needs_compaction<P: ChatProvider>(&self, provider: &P) -> bool {
+        let Some(compaction) = &self.compaction else {
+            return false;
+        };
+        if compaction.max_messages.is_some_and(|max| self.history.len() > max) {
+            return true;
+        }
+        let context = self.context_usage(provider);
+        context.overflows() || compaction.max_tokens.is_some_and(|max| context.tokens > max)
+    }
+
+    /// Compact the history with the guy's strategy, the removed messages are returned and kept in
+    /// [`Guy::compactions`].
+    pub async fn compact<P>(&mut self, provider: &P) -> Result<Option<&CompactionRecord>>
+    where
+        P: ChatProvider,
+        GuyError: From<P::Error>,
+    {
+        let Some(compaction) = self.compaction.clone() else {
+            return Ok(None);
+        };
+        let candidates = self
+            .history
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| compaction.strategy.removes(message))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let mut removed = match &compaction.strategy {
+            CompactionStrategy::SlidingWindow { keep_messages } | CompactionStrategy::Summarize { keep_messages, .. } => {
+                candidates.len().saturating_sub(*keep_messages)
+            }
+            CompactionStrategy::DropOldest { target_tokens } => {
+                let tokenizer = Tokenizer::for_model(self.model(provider));
+                let mut tokens = self.token_count(self.model(provider));
+                let mut removed = 0;
+                // The last message is never dropped, there would be nothing left to answer.
+                while tokens > *target_tokens && removed + 1 < candidates.len() {
+                    tokens -= tokenizer.count_messages(&self.history[candidates[removed]..=candidates[removed]])
+                        - tokenizer.count_messages(&[]);
+                    removed += 1;
+                }
+                removed
+            }
+        };
+        // A function result can not be sent without the call it answers.
+        while removed < candidates.len() && self.history[candidates[removed]].role == ChatCompletionRole::Function {
+            removed += 1;
+        }
+        if removed == 0 {
+            return Ok(None);
+        }
+
+        let position = candidates[0];
+        let mut messages = Vec::with_capacity(removed);
+        for index in candidates[..removed].iter().rev() {
+            messages.push(self.history.remove(*index));
+        }
+        messages.reverse();
+
+        let summary = match &compaction.strategy {
+            CompactionStrategy::Summarize { prompt, .. } => {
+                let summary = self.summarize(provider, prompt.as_deref(), &messages).await;
+                let summary = match summary {
+                    Ok(summary) => summary,
+                    Err(e) => {
+                        // Nothing is lost when the summary fails.
+                        for (offset, message) in candidates[..removed].iter().zip(messages) {
+                            self.history.insert(*offset, message);
+                        }
+                        return Err(e);
+                    }
+                };
+                let message = ChatCompletionMessage {
+                    name: Some(SUMMARY_MESSAGE_NAME.to_string()),
+                    ..ChatCompletionMessage::new(
+                        ChatCompletionRole::System,
+                        format!("Summary of the earlier conversation:\n{}", summary),
+                    )
+                };
+                self.history.insert(position, message);
+                Some(summary)
+            }
+            _ => None,
+        };
+
+        self.compactions.push(CompactionRecord {
+            date: Utc::now(),
+            strategy: compaction.strategy,
+            messages,
+            summary,
+        });
+        Ok(self.compactions.last())
+    }
+
+    async fn summarize<P>(&mut self, provider: &P, prompt: Option<&str>, messages: &[ChatCompletionMessage]) -> Result<String>
+    where
+        P: ChatProvider,
+        GuyError: From<P::Error>,
+    {
+        let transcript = messages.iter().map(transcript_line).collect::<Vec<_>>().join("\n");
+        let messages = [
+            ChatCompletionMessage::new(ChatCompletionRole::System, prompt.unwrap_or(DEFAULT_SUMMARY_PROMPT).to_string()),
+            ChatCompletionMessage::new(ChatCompletionRole::User, transcript),
+        ];
+        self.check_budget()?;
+        let response = provider
+            .chat_completion(ChatCompletionRequest {
+                messages: &messages,
+                parameters: ChatCompletionParameters {
+                    model: self.parameters.model.clone(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .await?;
+        self.record_usage(provider, &response);
+        let choice = response.choices.into_iter().next().ok_or(GuyError::EmptyCompletion)?;
+        Ok(choice.message.content.text().into_owned())
+    }
+}
+
+fn transcript_line(message: &ChatCompletionMessage) -> String {
+    let mut line = match &message.name {
+        Some(name) => format!("{} ({}): {}", message.role.as_str(), name, message.content),
+        None => format!("{}: {}", message.role.as_str(), message.content),
+    };
+    if let Some(call) = &message.function_call {
+        line.push_str(&format!("[calls {}({})]", call.name, call.arguments));
+    }
+    line
+}
@@ -6,6 +6,38 @@ pub enum GuyError {
     IoError(#[from] std::io::Error),
     #[error("YAML error: {}", _0)]
     YamlError(#[from] serde_yaml::Error),
+    #[error("JSON error: {}", _0)]
+    JsonError(#[from] serde_json::Error),
+    #[error("OpenAI error: {}", _0)]
+    OpenAIError(#[from] api_connector::openai::OpenAIError),
+    #[error("The completion returned no choice")]
+    EmptyCompletion,
+    #[error("The embedding returned no vector")]
+    EmptyEmbedding,
+    #[error("No handler registered for function `{}`", _0)]
+    FunctionNotRegistered(String),
+    #[error("Invalid function: {}", _0)]
+    InvalidFunction(#[from] api_connector::openai::FunctionSchemaError),
+    #[error("Function failed: {}", _0)]
+    Function(String),
+    #[error("Function call limit reached ({} calls without a final answer)", _0)]
+    FunctionCallLimit(usize),
+    #[error("Budget exceeded (spent ${:.4} of ${:.4})", spent, budget)]
+    BudgetExceeded { spent: f64, budget: f64 },
+    #[error("The prompt does not fit in the context window ({} tokens with `max_tokens` for a {} tokens window)", tokens, context_window)]
+    ContextOverflow { tokens: usize, context_window: usize },
+    #[error("Invalid glob pattern: {}", _0)]
+    Glob(#[from] glob::PatternError),
+    #[error("Vector index error: {}", _0)]
+    Index(#[from] vector_index::IndexError),
+    #[error("{:?} message blocked by moderation ({})", _0.role, _0.categories.join(", "))]
+    ModerationBlocked(crate::guard::ModerationFlag),
+}
+
+impl From<std::convert::Infallible> for GuyError {
+    fn from(infallible: std::convert::Infallible) -> Self {
+        match infallible {}
+    }
 }
 
 pub type Result<T> = std::result::Result<T, GuyError>;
@@ -0,0 +1,76 @@
+use crate::prelude::*;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type FunctionFuture = Pin<Box<dyn Future<Output = Result<serde_json::Value>> + Send>>;
+
+/// A rust function the model can call, it receives the decoded `arguments` object.
+pub type FunctionHandler = Arc<dyn Fn(serde_json::Value) -> FunctionFuture + Send + Sync>;
+
+/// Handlers registered on a [`Guy`], indexed by function name.
+///
+/// Handlers are runtime only: they are neither serialized nor compared (two sets are equal
+/// when they handle the same function names).
+#[derive(Clone, Default)]
+pub struct FunctionHandlers {
+    handlers: HashMap<String, FunctionHandler>,
+}
+
+impl FunctionHandlers {
+    pub fn insert<F, Fut>(&mut self, name: String, handler: F)
+    where
+        F: Fn(serde_json::Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<serde_json::Value>> + Send + 'static,
+    {
+        self.handlers
+            .insert(name, Arc::new(move |arguments| Box::pin(handler(arguments))));
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.handlers.remove(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<FunctionHandler> {
+        self.handlers.get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.handlers.contains_key(name)
+    }
+
+    /// Run the handler of `call` and encode its output as the content of a `Function` message.
+    ///
+    /// Handler errors and invalid arguments are reported to the model instead of aborting the
+    /// completion so it gets a chance to fix its call.
+    pub async fn call(&self, call: &ChatCompletionFunctionCall) -> Result<ChatCompletionMessage> {
+        let handler = self
+            .get(&call.name)
+            .ok_or_else(|| GuyError::FunctionNotRegistered(call.name.clone()))?;
+        let output = match call.parse_arguments() {
+            Ok(arguments) => handler(arguments).await,
+            Err(e) => Err(e.into()),
+        };
+        let content = match output {
+            Ok(serde_json::Value::String(content)) => content,
+            Ok(value) => serde_json::to_string(&value)?,
+            Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+        };
+        Ok(ChatCompletionMessage::function_result(call.name.clone(), content))
+    }
+}
+
+impl std::fmt::Debug for FunctionHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+impl PartialEq for FunctionHandlers {
+    fn eq(&self, other: &Self) -> bool {
+        self.handlers.len() == other.handlers.len()
+            && self.handlers.keys().all(|name| other.handlers.contains_key(name))
+    }
+}
+
+impl Eq for FunctionHandlers {}
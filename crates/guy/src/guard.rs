@@ -0,0 +1,228 @@
+use crate::prelude::*;
+use api_connector::moderation::*;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Moderation of a guy's messages: the user messages are checked before being sent and the
+/// assistant answers before being kept.
+///
+/// ```yaml
+/// guard:
+///   default_policy: block
+///   policies:
+///     harassment: warn
+///     self-harm: redact
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Guard {
+    #[serde(default = "default_true")]
+    pub input: bool,
+    #[serde(default = "default_true")]
+    pub output: bool,
+    /// Policy of the flagged categories without their own.
+    #[serde(default)]
+    pub default_policy: Policy,
+    /// Policy by category, a category (`self-harm`) also covers its subcategories
+    /// (`self-harm/intent`).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub policies: BTreeMap<String, Policy>,
+    /// Also flag the categories scored at least this (from `0.0` to `1.0`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f64>,
+}
+
+/// What is done with a flagged message, from the most to the least permissive.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    Allow,
+    /// Keep the message, the flag is reported.
+    Warn,
+    /// Replace the message's content by [`redaction`].
+    Redact,
+    /// Fail with [`GuyError::ModerationBlocked`].
+    #[default]
+    Block,
+}
+
+/// A message flagged by the guard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModerationFlag {
+    pub role: ChatCompletionRole,
+    /// Position of the message in the history, `None` for an answer which was not kept.
+    pub index: Option<usize>,
+    pub categories: Vec<String>,
+    pub policy: Policy,
+}
+
+pub type ModerateFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<ModerationResult>>> + Send + 'a>>;
+
+/// Object safe [`ModerationProvider`], implemented by every provider whose errors convert into
+/// [`GuyError`].
+pub trait Moderate: Send + Sync {
+    fn moderate_texts<'a>(&'a self, input: &'a [String]) -> ModerateFuture<'a>;
+}
+
+impl<P> Moderate for P
+where
+    P: ModerationProvider,
+    GuyError: From<P::Error>,
+{
+    fn moderate_texts<'a>(&'a self, input: &'a [String]) -> ModerateFuture<'a> {
+        Box::pin(async move { Ok(self.moderate(input).await?) })
+    }
+}
+
+/// The moderation backend of a guy's [`Guard`], runtime only like the function handlers.
+#[derive(Clone)]
+pub struct Moderator(Arc<dyn Moderate>);
+
+impl Moderator {
+    pub fn new(provider: impl Moderate + 'static) -> Self {
+        Self(Arc::new(provider))
+    }
+}
+
+impl std::fmt::Debug for Moderator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Moderator").finish_non_exhaustive()
+    }
+}
+
+/// Two moderators are equal when they share the same backend.
+impl PartialEq for Moderator {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for Guard {
+    fn default() -> Self {
+        Self {
+            input: true,
+            output: true,
+            default_policy: Policy::default(),
+            policies: BTreeMap::new(),
+            threshold: None,
+        }
+    }
+}
+
+impl Guard {
+    /// The policy of `category`, or of its parent category.
+    pub fn policy(&self, category: &str) -> Policy {
+        let parent = category.split_once('/').map(|(parent, _)| parent);
+        self.policies
+            .get(category)
+            .or_else(|| parent.and_then(|parent| self.policies.get(parent)))
+            .copied()
+            .unwrap_or(self.default_policy)
+    }
+
+    /// The strictest policy of the flagged categories and the categories not allowed, `None`
+    /// when the message passes.
+    pub fn verdict(&self, result: &ModerationResult) -> Option<(Policy, Vec<String>)> {
+        let flagged = result
+            .flagged_categories(self.threshold)
+            .into_iter()
+            .map(|category| (self.policy(category), category.to_string()))
+            .filter(|(policy, _)| *policy != Policy::Allow)
+            .collect::<Vec<_>>();
+        let policy = flagged.iter().map(|(policy, _)| *policy).max()?;
+        Some((policy, flagged.into_iter().map(|(_, category)| category).collect()))
+    }
+}
+
+/// Content of a redacted message.
+pub fn redaction(categories: &[String]) -> String {
+    format!("[redacted by moderation: {}]", categories.join(", "))
+}
+
+impl Guy {
+    fn active_guard(&self) -> Option<(&Guard, &Moderator)> {
+        self.guard.as_ref().zip(self.moderator.as_ref())
+    }
+
+    /// Whether the answers are moderated before being kept.
+    pub(crate) fn moderates_output(&self) -> bool {
+        self.active_guard().is_some_and(|(guard, _)| guard.output)
+    }
+
+    /// Moderate the user messages sent since the last answer.
+    pub(crate) async fn moderate_input(&mut self) -> Result<()> {
+        let Some((guard, moderator)) = self.active_guard().filter(|(guard, _)| guard.input) else {
+            return Ok(());
+        };
+        let start = self
+            .history
+            .iter()
+            .rposition(|message| message.role == ChatCompletionRole::Assistant)
+            .map_or(0, |index| index + 1);
+        let (indexes, input): (Vec<usize>, Vec<String>) = self.history[start..]
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| message.role == ChatCompletionRole::User && !message.content.text().is_empty())
+            .map(|(index, message)| (start + index, message.content.text().into_owned()))
+            .unzip();
+        if input.is_empty() {
+            return Ok(());
+        }
+        let guard = guard.clone();
+        let results = moderator.0.moderate_texts(&input).await?;
+        for (index, result) in indexes.into_iter().zip(results.iter()) {
+            let Some((policy, categories)) = guard.verdict(result) else {
+                continue;
+            };
+            let flag = ModerationFlag {
+                role: ChatCompletionRole::User,
+                index: Some(index),
+                categories,
+                policy,
+            };
+            match policy {
+                Policy::Block => return Err(GuyError::ModerationBlocked(flag)),
+                Policy::Redact => self.history[index].content = redaction(&flag.categories).into(),
+                Policy::Warn | Policy::Allow => {}
+            }
+            self.moderation_flags.push(flag);
+        }
+        Ok(())
+    }
+
+    /// Moderate an answer before it is kept, a redacted answer is returned redacted.
+    pub(crate) async fn moderate_output(&mut self, mut message: ChatCompletionMessage) -> Result<ChatCompletionMessage> {
+        let Some((guard, moderator)) = self.active_guard().filter(|(guard, _)| guard.output) else {
+            return Ok(message);
+        };
+        if message.content.text().is_empty() {
+            return Ok(message);
+        }
+        let input = [message.content.text().into_owned()];
+        let results = moderator.0.moderate_texts(&input).await?;
+        let Some((policy, categories)) = results.first().and_then(|result| guard.verdict(result)) else {
+            return Ok(message);
+        };
+        let mut flag = ModerationFlag {
+            role: message.role.clone(),
+            index: Some(self.history.len()),
+            categories,
+            policy,
+        };
+        match policy {
+            Policy::Block => {
+                flag.index = None;
+                return Err(GuyError::ModerationBlocked(flag));
+            }
+            Policy::Redact => message.content = redaction(&flag.categories).into(),
+            Policy::Warn | Policy::Allow => {}
+        }
+        self.moderation_flags.push(flag);
+        Ok(message)
+    }
+}
@@ -0,0 +1,302 @@
+use crate::prelude::*;
+use api_connector::embeddings::{EmbeddingOptions, EmbeddingProvider, Embeddings};
+use api_connector::tokenizer::{Encoding, Tokenizer};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use vector_index::{Metric, VectorIndex};
+
+/// Name of the transient `System` message holding the retrieved passages.
+pub const KNOWLEDGE_MESSAGE_NAME: &str = "knowledge";
+
+/// Metric of the knowledge indexes.
+pub const KNOWLEDGE_METRIC: Metric = Metric::Cosine;
+
+const KNOWLEDGE_PROMPT: &str = "Passages of the documentation related to the last user message. \
+Use them when relevant and cite their source (e.g. `[1] path:10-20`).";
+
+/// Documents a guy retrieves passages from, indexed in a [`KnowledgeBase`].
+///
+/// Paths and globs are relative to the working directory (like `UserFromFile` messages).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Knowledge {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<PathBuf>,
+    /// Glob patterns such as `docs/**/*.md`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub globs: Vec<String>,
+    #[serde(default)]
+    pub chunking: Chunking,
+    /// Passages injected in each completion.
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    /// Passages with a lower (cosine) similarity are not injected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_score: Option<f32>,
+    #[serde(default)]
+    pub embedding: EmbeddingOptions,
+}
+
+/// How documents are split into passages, passages are cut on line boundaries.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Chunking {
+    #[serde(default = "default_chunk_tokens")]
+    pub max_tokens: usize,
+    /// Lines of a passage repeated at the start of the next one.
+    #[serde(default = "default_overlap_lines")]
+    pub overlap_lines: usize,
+}
+
+/// A passage of a document.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Chunk {
+    pub source: String,
+    /// First and last lines of the passage, from 1.
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Passage {
+    pub chunk: Chunk,
+    pub score: f32,
+}
+
+/// Passages retrieved for a query, with the cost of embedding the query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retrieval {
+    pub passages: Vec<Passage>,
+    pub model: String,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexingReport {
+    pub sources: Vec<PathBuf>,
+    /// Files skipped because they are not UTF-8 text.
+    pub skipped: Vec<PathBuf>,
+    pub chunks: usize,
+    pub model: String,
+    pub usage: Usage,
+}
+
+fn default_top_k() -> usize {
+    4
+}
+
+fn default_chunk_tokens() -> usize {
+    256
+}
+
+fn default_overlap_lines() -> usize {
+    2
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Self {
+            max_tokens: default_chunk_tokens(),
+            overlap_lines: default_overlap_lines(),
+        }
+    }
+}
+
+impl Chunk {
+    pub fn citation(&self) -> String {
+        format!("{}:{}-{}", self.source, self.start_line, self.end_line)
+    }
+}
+
+impl Knowledge {
+    /// The listed files and the files matched by the globs, sorted and deduplicated.
+    pub fn sources(&self) -> Result<Vec<PathBuf>> {
+        let mut sources = self.files.clone();
+        for pattern in self.globs.iter() {
+            for path in glob::glob(pattern)? {
+                let path = path.map_err(std::io::Error::from)?;
+                if path.is_file() {
+                    sources.push(path);
+                }
+            }
+        }
+        sources.sort();
+        sources.dedup();
+        Ok(sources)
+    }
+}
+
+impl Chunking {
+    /// Split `text` into passages of at most `max_tokens` tokens (a longer line is a passage on
+    /// its own), blank passages are dropped.
+    pub fn split(&self, source: &str, text: &str) -> Vec<Chunk> {
+        let tokenizer = Tokenizer::new(Encoding::Cl100kBase);
+        let lines = text.lines().map(|line| (line, tokenizer.count(line) + 1)).collect::<Vec<_>>();
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < lines.len() {
+            let mut end = start;
+            let mut tokens = 0;
+            while end < lines.len() && (end == start || tokens + lines[end].1 <= self.max_tokens) {
+                tokens += lines[end].1;
+                end += 1;
+            }
+            let text = lines[start..end].iter().map(|(line, _)| *line).collect::<Vec<_>>().join("\n");
+            if !text.trim().is_empty() {
+                chunks.push(Chunk {
+                    source: source.to_string(),
+                    start_line: start + 1,
+                    end_line: end,
+                    text,
+                });
+            }
+            if end == lines.len() {
+                break;
+            }
+            start = end.saturating_sub(self.overlap_lines).max(start + 1);
+        }
+        chunks
+    }
+}
+
+pub type EmbedFuture<'a> = Pin<Box<dyn Future<Output = Result<Embeddings>> + Send + 'a>>;
+
+/// Object safe [`EmbeddingProvider`], implemented by every provider whose errors convert into
+/// [`GuyError`].
+pub trait Embedder: Send + Sync {
+    fn embed_texts<'a>(&'a self, input: &'a [String], options: &'a EmbeddingOptions) -> EmbedFuture<'a>;
+}
+
+impl<P> Embedder for P
+where
+    P: EmbeddingProvider,
+    GuyError: From<P::Error>,
+{
+    fn embed_texts<'a>(&'a self, input: &'a [String], options: &'a EmbeddingOptions) -> EmbedFuture<'a> {
+        Box::pin(async move { Ok(self.embed(input, options).await?) })
+    }
+}
+
+/// The indexed passages of a guy's [`Knowledge`] and the embedder used to search them.
+///
+/// Runtime only like the function handlers: the stores open it next to the guy.
+#[derive(Clone)]
+pub struct KnowledgeBase {
+    index: Arc<VectorIndex>,
+    embedder: Arc<dyn Embedder>,
+}
+
+impl KnowledgeBase {
+    /// `index` must use the [`KNOWLEDGE_METRIC`].
+    pub fn new(index: VectorIndex, embedder: impl Embedder + 'static) -> Self {
+        Self {
+            index: Arc::new(index),
+            embedder: Arc::new(embedder),
+        }
+    }
+
+    pub fn index(&self) -> &VectorIndex {
+        &self.index
+    }
+
+    /// Replace the indexed passages by the ones of the `knowledge` sources.
+    pub async fn rebuild(&self, knowledge: &Knowledge) -> Result<IndexingReport> {
+        let mut sources = Vec::new();
+        let mut skipped = Vec::new();
+        let mut chunks = Vec::new();
+        for path in knowledge.sources()? {
+            match tokio::fs::read_to_string(&path).await {
+                Ok(text) => {
+                    chunks.extend(knowledge.chunking.split(&path.to_string_lossy(), &text));
+                    sources.push(path);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => skipped.push(path),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let input = chunks.iter().map(|chunk| chunk.text.clone()).collect::<Vec<_>>();
+        let embeddings = match input.is_empty() {
+            true => Embeddings::default(),
+            false => self.embedder.embed_texts(&input, &knowledge.embedding).await?,
+        };
+        self.index.clear()?;
+        for (chunk, vector) in chunks.iter().zip(embeddings.vectors) {
+            let id = format!("{}:{}", chunk.source, chunk.start_line);
+            self.index.insert(&id, vector, serde_json::to_value(chunk)?)?;
+        }
+        self.index.flush()?;
+        Ok(IndexingReport {
+            sources,
+            skipped,
+            chunks: chunks.len(),
+            model: embeddings.model,
+            usage: embeddings.usage,
+        })
+    }
+
+    /// The passages most similar to `query`, most similar first.
+    pub async fn retrieve(&self, knowledge: &Knowledge, query: &str) -> Result<Retrieval> {
+        let embeddings = self
+            .embedder
+            .embed_texts(&[query.to_string()], &knowledge.embedding)
+            .await?;
+        let vector = embeddings.vectors.first().ok_or(GuyError::EmptyEmbedding)?;
+        let mut passages = Vec::new();
+        for result in self.index.search(vector, knowledge.top_k)? {
+            if knowledge.min_score.is_some_and(|min| result.score < min) {
+                continue;
+            }
+            passages.push(Passage {
+                chunk: serde_json::from_value(result.metadata)?,
+                score: result.score,
+            });
+        }
+        Ok(Retrieval {
+            passages,
+            model: embeddings.model,
+            usage: embeddings.usage,
+        })
+    }
+}
+
+impl std::fmt::Debug for KnowledgeBase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KnowledgeBase").field("passages", &self.index.len()).finish()
+    }
+}
+
+/// Two knowledge bases are equal when they share the same index.
+impl PartialEq for KnowledgeBase {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.index, &other.index)
+    }
+}
+
+impl Guy {
+    /// Passages of the guy's knowledge related to its last user message, as a `System` message
+    /// citing their sources.
+    ///
+    /// `None` without knowledge base, without user message or when nothing relevant was found.
+    /// The query embedding is recorded in the usage ledger.
+    pub async fn retrieve_context(&mut self) -> Result<Option<ChatCompletionMessage>> {
+        let (Some(knowledge), Some(base)) = (&self.knowledge, &self.knowledge_base) else {
+            return Ok(None);
+        };
+        let Some(query) = self.history.iter().rev().find(|message| message.role == ChatCompletionRole::User) else {
+            return Ok(None);
+        };
+        let retrieval = base.retrieve(knowledge, &query.content.text()).await?;
+        self.usage.record(retrieval.model, retrieval.usage, &self.pricing);
+        if retrieval.passages.is_empty() {
+            return Ok(None);
+        }
+        let mut content = KNOWLEDGE_PROMPT.to_string();
+        for (index, passage) in retrieval.passages.iter().enumerate() {
+            content.push_str(&format!("\n\n[{}] {}\n{}", index + 1, passage.chunk.citation(), passage.chunk.text));
+        }
+        Ok(Some(ChatCompletionMessage {
+            name: Some(KNOWLEDGE_MESSAGE_NAME.to_string()),
+            ..ChatCompletionMessage::new(ChatCompletionRole::System, content)
+        }))
+    }
+}
@@ -1,15 +1,82 @@
 use crate::prelude::*;
 use crate::template::*;
+use api_connector::tokenizer::Tokenizer;
+use std::borrow::Cow;
 
+pub mod compaction;
 pub mod error;
+pub mod function;
+pub mod guard;
+pub mod knowledge;
 pub mod prelude;
 pub mod template;
+pub mod usage;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// Number of function calls a single completion can chain before giving up.
+pub const DEFAULT_MAX_FUNCTION_CALLS: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Guy {
     pub description: Option<String>,
     pub history: Vec<ChatCompletionMessage>,
     pub functions: Vec<ChatCompletionFunction>,
+    /// Model and sampling options of the guy's completions.
+    #[serde(default, skip_serializing_if = "ChatCompletionParameters::is_empty")]
+    pub parameters: ChatCompletionParameters,
+    #[serde(default = "default_max_function_calls")]
+    pub max_function_calls: usize,
+    #[serde(default)]
+    pub budget: Budget,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compaction: Option<Compaction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub knowledge: Option<Knowledge>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guard: Option<Guard>,
+    #[serde(skip)]
+    pub handlers: FunctionHandlers,
+    /// The indexed `knowledge`, passages are retrieved only when it is set.
+    #[serde(skip)]
+    pub knowledge_base: Option<KnowledgeBase>,
+    /// Moderation backend of the `guard`, messages are moderated only when it is set.
+    #[serde(skip)]
+    pub moderator: Option<Moderator>,
+    /// Calls made by this guy, kept apart from its definition by the stores.
+    #[serde(skip)]
+    pub usage: UsageLedger,
+    #[serde(skip)]
+    pub pricing: PricingTable,
+    /// Compactions made since the guy was loaded, kept by the stores for audit.
+    #[serde(skip)]
+    pub compactions: Vec<CompactionRecord>,
+    /// Messages flagged (but not blocked) by the guard since the guy was loaded.
+    #[serde(skip)]
+    pub moderation_flags: Vec<ModerationFlag>,
+}
+
+/// Size of the prompt of the next completion compared to the model's context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextUsage {
+    pub tokens: usize,
+    /// Tokens reserved for the completion (`max_tokens`).
+    pub max_tokens: usize,
+    /// `None` when the provider does not know its model's context window.
+    pub context_window: Option<usize>,
+}
+
+impl ContextUsage {
+    pub fn overflows(&self) -> bool {
+        self.context_window.is_some_and(|window| self.tokens + self.max_tokens > window)
+    }
+
+    /// Tokens left for the completion, `max_tokens` included.
+    pub fn remaining(&self) -> Option<usize> {
+        self.context_window.map(|window| window.saturating_sub(self.tokens))
+    }
+}
+
+fn default_max_function_calls() -> usize {
+    DEFAULT_MAX_FUNCTION_CALLS
 }
 
 impl Guy {
@@ -18,11 +85,33 @@ impl Guy {
             description: None,
             history: Vec::new(),
             functions: Vec::new(),
+            parameters: ChatCompletionParameters::default(),
+            max_function_calls: DEFAULT_MAX_FUNCTION_CALLS,
+            budget: Budget::default(),
+            compaction: None,
+            knowledge: None,
+            guard: None,
+            handlers: FunctionHandlers::default(),
+            knowledge_base: None,
+            moderator: None,
+            usage: UsageLedger::new(),
+            pricing: PricingTable::default(),
+            compactions: Vec::new(),
+            moderation_flags: Vec::new(),
         }
     }
 
     pub async fn load_template(&mut self, template: GuyTemplate) -> Result<()> {
+        let functions = template
+            .functions
+            .into_iter()
+            .map(ChatCompletionFunction::try_from)
+            .collect::<Result<_>>()?;
         self.description = template.description;
+        self.compaction = template.compaction;
+        self.knowledge = template.knowledge;
+        self.guard = template.guard;
+        self.parameters.merge(template.parameters);
         for message in template.history {
             match message {
                 ChatCompletionMessageTemplate::User(content) => {
@@ -40,38 +129,622 @@ impl Guy {
                 },
             }
         }
-        self.functions = template.functions.into_iter().map(|e| e.into()).collect();
+        self.functions = functions;
         Ok(())
     }
 
-    pub fn push_message(&mut self, content: String, role: ChatCompletionRole) {
-        let completion = ChatCompletionMessage { content, role };
+    /// Append a message to the history, a `Function` message is attached to the pending
+    /// function call (if any).
+    pub fn push_message(&mut self, content: impl Into<MessageContent>, role: ChatCompletionRole) {
+        let mut completion = ChatCompletionMessage::new(role, content);
+        if completion.role == ChatCompletionRole::Function {
+            completion.name = self.pending_function_call().map(|call| call.name.clone());
+        }
         self.history.push(completion);
     }
 
-    pub async fn completion(&mut self, connector: &OpenAIConnector) -> Result<ChatCompletionResponse> {
-        let request = ChatCompletionRequest {
-            messages: &self.history[..],
+    /// The function call of the last message if it has not been answered yet.
+    pub fn pending_function_call(&self) -> Option<&ChatCompletionFunctionCall> {
+        self.history.last().and_then(|message| message.function_call.as_ref())
+    }
+
+    /// Register the rust handler called when the model asks for the function `name`.
+    pub fn register_function<F, Fut>(&mut self, name: impl Into<String>, handler: F)
+    where
+        F: Fn(serde_json::Value) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<serde_json::Value>> + Send + 'static,
+    {
+        self.handlers.insert(name.into(), handler);
+    }
+
+    pub fn budget_status(&self) -> BudgetStatus {
+        self.budget.status(self.usage.total_cost())
+    }
+
+    fn check_budget(&self) -> Result<()> {
+        match (self.budget_status(), self.budget.hard) {
+            (BudgetStatus::HardExceeded, Some(budget)) => Err(GuyError::BudgetExceeded {
+                spent: self.usage.total_cost(),
+                budget,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The guy's model, or the provider's default one.
+    pub fn model<'a, P: ChatProvider>(&'a self, provider: &'a P) -> &'a str {
+        self.parameters.model.as_deref().unwrap_or_else(|| provider.default_model())
+    }
+
+    /// Tokens used by the prompt of the next completion (history and functions) for `model`.
+    pub fn token_count(&self, model: &str) -> usize {
+        Tokenizer::for_model(model).count_request(&self.completion_request())
+    }
+
+    pub fn context_usage<P: ChatProvider>(&self, provider: &P) -> ContextUsage {
+        self.request_usage(provider, &self.completion_request())
+    }
+
+    fn request_usage<P: ChatProvider>(&self, provider: &P, request: &ChatCompletionRequest<'_>) -> ContextUsage {
+        let model = self.model(provider);
+        ContextUsage {
+            tokens: Tokenizer::for_model(model).count_request(request),
+            max_tokens: self.parameters.max_tokens.unwrap_or_default() as usize,
+            context_window: provider.context_window(model),
+        }
+    }
+
+    /// Pre-flight check: fails when the next prompt does not fit in the provider's context window.
+    pub fn check_context<P: ChatProvider>(&self, provider: &P) -> Result<ContextUsage> {
+        self.check_request(provider, &self.completion_request())
+    }
+
+    fn check_request<P: ChatProvider>(&self, provider: &P, request: &ChatCompletionRequest<'_>) -> Result<ContextUsage> {
+        let usage = self.request_usage(provider, request);
+        match usage.context_window {
+            Some(context_window) if usage.overflows() => Err(GuyError::ContextOverflow {
+                tokens: usage.tokens + usage.max_tokens,
+                context_window,
+            }),
+            _ => Ok(usage),
+        }
+    }
+
+    fn record_usage<P: ChatProvider>(&mut self, provider: &P, response: &ChatCompletionResponse) {
+        let model = match response.model.is_empty() {
+            true => self.model(provider).to_string(),
+            false => response.model.clone(),
+        };
+        self.usage
+            .record(model, response.usage.unwrap_or_default(), &self.pricing);
+    }
+
+    fn completion_request(&self) -> ChatCompletionRequest<'_> {
+        self.request_with(&self.history)
+    }
+
+    fn request_with<'a>(&'a self, messages: &'a [ChatCompletionMessage]) -> ChatCompletionRequest<'a> {
+        ChatCompletionRequest {
+            messages,
+            functions: (!self.functions.is_empty()).then_some(&self.functions[..]),
+            parameters: self.parameters.clone(),
             ..Default::default()
+        }
+    }
+
+    /// The history with the retrieved `context` inserted before the last user message.
+    fn prompt(&self, context: Option<&ChatCompletionMessage>) -> Cow<'_, [ChatCompletionMessage]> {
+        let Some(context) = context else {
+            return Cow::Borrowed(&self.history);
         };
-        let response = connector.chat_completion_request(request).await.unwrap();
-        self.history.push(response.choices[0].message.clone());
-        Ok(response)
+        let position = self
+            .history
+            .iter()
+            .rposition(|message| message.role == ChatCompletionRole::User)
+            .unwrap_or(self.history.len());
+        let mut messages = self.history.clone();
+        messages.insert(position, context.clone());
+        Cow::Owned(messages)
+    }
+
+    /// Push the model's answer and run the function it asked for (if any), the call is only kept
+    /// in the history along with its result.
+    ///
+    /// Returns `true` when the answer is final.
+    async fn handle_answer(&mut self, message: ChatCompletionMessage, calls: &mut usize) -> Result<bool> {
+        let Some(function_call) = message.function_call.clone() else {
+            self.history.push(message);
+            return Ok(true);
+        };
+        if *calls >= self.max_function_calls {
+            return Err(GuyError::FunctionCallLimit(*calls));
+        }
+        *calls += 1;
+        let result = self.handlers.call(&function_call).await?;
+        self.history.push(message);
+        self.history.push(result);
+        Ok(false)
+    }
+
+    /// Ask the model for the next message.
+    ///
+    /// Function calls are dispatched to the registered handlers and their results are sent back
+    /// until the model gives a regular answer (or `max_function_calls` is reached), the returned
+    /// response is the final one.
+    ///
+    /// The history is compacted first when it crossed a threshold of the guy's [`Compaction`].
+    /// With a [`KnowledgeBase`], the passages related to the last user message are sent along
+    /// (see [`Guy::retrieve_context`]) but not kept in the history.
+    /// With a [`Guard`] and a [`Moderator`], the new user messages and the answers are moderated
+    /// (see [`Policy`]).
+    /// Every call is recorded in the usage ledger, no call is made once the hard budget is reached
+    /// or when the prompt does not fit in the context window.
+    pub async fn completion<P>(&mut self, provider: &P) -> Result<ChatCompletionResponse>
+    where
+        P: ChatProvider,
+        GuyError: From<P::Error>,
+    {
+        self.moderate_input().await?;
+        if self.needs_compaction(provider) {
+            self.compact(provider).await?;
+        }
+        self.check_budget()?;
+        let context = self.retrieve_context().await?;
+        let mut calls = 0;
+        loop {
+            self.check_budget()?;
+            let mut response = {
+                let messages = self.prompt(context.as_ref());
+                let request = self.request_with(&messages);
+                self.check_request(provider, &request)?;
+                provider.chat_completion(request).await?
+            };
+            self.record_usage(provider, &response);
+            if self.handle_response(&mut response, &mut calls).await? {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Moderate the first choice of `response` (redacted in place) then handle it.
+    ///
+    /// Returns `true` when the answer is final.
+    async fn handle_response(&mut self, response: &mut ChatCompletionResponse, calls: &mut usize) -> Result<bool> {
+        let choice = response.choices.first_mut().ok_or(GuyError::EmptyCompletion)?;
+        choice.message = self.moderate_output(choice.message.clone()).await?;
+        self.handle_answer(choice.message.clone(), calls).await
+    }
+
+    /// Same as [`Guy::completion`] but the response is streamed, `on_token` is called with
+    /// every content fragment as soon as it is received. When the answers are moderated, it is
+    /// only called once the whole answer passed moderation (with its redacted content).
+    /// The usage is estimated with the [`Tokenizer`] when the provider does not report it.
+    pub async fn completion_stream<P, F>(&mut self, provider: &P, mut on_token: F) -> Result<ChatCompletionResponse>
+    where
+        P: ChatProvider,
+        GuyError: From<P::Error>,
+        F: FnMut(&str),
+    {
+        self.moderate_input().await?;
+        if self.needs_compaction(provider) {
+            self.compact(provider).await?;
+        }
+        self.check_budget()?;
+        let context = self.retrieve_context().await?;
+        let buffered = self.moderates_output();
+        let mut calls = 0;
+        loop {
+            self.check_budget()?;
+            let (mut stream, prompt_tokens) = {
+                let messages = self.prompt(context.as_ref());
+                let request = self.request_with(&messages);
+                let usage = self.check_request(provider, &request)?;
+                (provider.chat_completion_stream(request).await?, usage.tokens)
+            };
+            let mut accumulator = ChatCompletionAccumulator::new();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                if !buffered {
+                    for content in chunk.choices.iter().filter(|e| e.index == 0).filter_map(|e| e.delta.content.as_deref()) {
+                        on_token(content);
+                    }
+                }
+                accumulator.push(&chunk);
+            }
+            let mut response = accumulator.finish();
+            if response.usage.is_none() {
+                let tokenizer = Tokenizer::for_model(self.model(provider));
+                response.usage = Some(tokenizer.estimate_usage(prompt_tokens, &response));
+            }
+            self.record_usage(provider, &response);
+            let done = self.handle_response(&mut response, &mut calls).await?;
+            if buffered {
+                let content = response.choices[0].message.content.text();
+                if !content.is_empty() {
+                    on_token(&content);
+                }
+            }
+            if done {
+                return Ok(response);
+            }
+        }
     }
 }
 
+impl Default for Guy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use api_connector::{cassette::Cassette, keyring::KeyChain};
 
     #[tokio::test]
     async fn test_guy() {
-        dotenv::dotenv().ok();
-        let template = GuyTemplate::from_yaml_file("../../data/guys/crypto.yaml").unwrap();
+        let template = GuyTemplate::from_yaml_file("../../data/guys/prompt_enginer.yaml").unwrap();
         let mut guy = Guy::new();
         guy.load_template(template).await.unwrap();
-        
-        dbg!(&guy);
+        guy.push_message(
+            "Which model should I use to generate fantasy card illustrations?".to_string(),
+            ChatCompletionRole::User,
+        );
+
+        let keychain = KeyChain::new();
+        keychain.set_api_key("OPENAI", "sk-replay".to_string());
+        let connector = OpenAIConnector::new(&keychain).unwrap().with_cassette(Cassette::replay("../../tests-data/cassettes/guy"));
+        let response = guy.completion(&connector).await.unwrap();
+        assert_eq!(response.choices[0].finish_reason, "stop");
+        assert_eq!(guy.history.last(), Some(&response.choices[0].message));
+        assert!(guy.history.last().unwrap().content.text().contains("Stable Diffusion"));
+    }
+
+    /// Answers with the scripted messages, in order.
+    struct ScriptedProvider(std::sync::Mutex<Vec<ChatCompletionMessage>>);
+
+    impl ChatProvider for ScriptedProvider {
+        type Error = OpenAIError;
+
+        fn default_model(&self) -> &str {
+            "scripted"
+        }
+
+        fn context_window(&self, _model: &str) -> Option<usize> {
+            Some(4096)
+        }
+
+        async fn chat_completion<'a>(
+            &'a self,
+            _request: ChatCompletionRequest<'a>,
+        ) -> std::result::Result<ChatCompletionResponse, OpenAIError> {
+            let message = self.0.lock().unwrap().remove(0);
+            Ok(ChatCompletionResponse {
+                id: "scripted".to_string(),
+                object: "chat.completion".to_string(),
+                created: 0,
+                model: "scripted".to_string(),
+                choices: vec![ChatCompletionChoice {
+                    message,
+                    index: 0,
+                    finish_reason: "stop".to_string(),
+                }],
+                usage: Some(Usage {
+                    prompt_tokens: 1000,
+                    completion_tokens: 500,
+                    total_tokens: 1500,
+                }),
+            })
+        }
+
+        async fn chat_completion_stream<'a>(
+            &'a self,
+            request: ChatCompletionRequest<'a>,
+        ) -> std::result::Result<ChatCompletionStream<OpenAIError>, OpenAIError> {
+            let response = self.chat_completion(request).await?;
+            let message = response.choices[0].message.clone();
+            let chunk = ChatCompletionChunk {
+                id: response.id,
+                object: "chat.completion.chunk".to_string(),
+                created: 0,
+                model: response.model,
+                choices: vec![ChatCompletionChunkChoice {
+                    delta: ChatCompletionDelta {
+                        role: Some(message.role),
+                        content: Some(message.content.text().into_owned()),
+                        function_call: message.function_call.map(|call| ChatCompletionFunctionCallDelta {
+                            name: Some(call.name),
+                            arguments: Some(call.arguments),
+                        }),
+                    },
+                    index: 0,
+                    finish_reason: Some("stop".to_string()),
+                }],
+                usage: response.usage,
+            };
+            Ok(Box::pin(futures_util::stream::iter([Ok(chunk)])))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_completion_with_provider() {
+        let call = ChatCompletionMessage {
+            function_call: Some(ChatCompletionFunctionCall {
+                name: "now".to_string(),
+                arguments: "{}".to_string(),
+            }),
+            ..ChatCompletionMessage::new(ChatCompletionRole::Assistant, String::new())
+        };
+        let answer = ChatCompletionMessage::new(ChatCompletionRole::Assistant, "It is noon".to_string());
+        let provider = ScriptedProvider(std::sync::Mutex::new(vec![
+            call.clone(),
+            answer.clone(),
+            call,
+            answer,
+        ]));
+
+        let mut guy = Guy::new();
+        guy.pricing.insert("scripted", ModelPricing { prompt: 0.01, completion: 0.02 });
+        guy.budget.hard = Some(0.08);
+        guy.register_function("now", |_| async { Ok(serde_json::json!("12:00")) });
+        guy.push_message("What time is it?".to_string(), ChatCompletionRole::User);
+        let response = guy.completion(&provider).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "It is noon");
+        assert_eq!(guy.history.len(), 4);
+
+        let mut streamed = String::new();
+        guy.completion_stream(&provider, |token| streamed.push_str(token)).await.unwrap();
+        assert_eq!(streamed, "It is noon");
+        assert_eq!(guy.history[5].name.as_deref(), Some("now"));
+
+        assert_eq!(guy.usage.total().calls, 4);
+        assert_eq!(guy.budget_status(), BudgetStatus::HardExceeded);
+        assert!(matches!(guy.completion(&provider).await, Err(GuyError::BudgetExceeded { .. })));
+    }
+
+    /// Flags the inputs containing a category name (`hate`, `violence/graphic`...).
+    struct KeywordModerator;
+
+    impl api_connector::moderation::ModerationProvider for KeywordModerator {
+        type Error = OpenAIError;
+
+        async fn moderate<'a>(
+            &'a self,
+            input: &'a [String],
+        ) -> std::result::Result<Vec<api_connector::moderation::ModerationResult>, OpenAIError> {
+            Ok(input
+                .iter()
+                .map(|text| {
+                    let categories = ["hate", "harassment", "violence/graphic"]
+                        .into_iter()
+                        .map(|category| (category.to_string(), text.contains(category)))
+                        .collect::<std::collections::BTreeMap<_, _>>();
+                    api_connector::moderation::ModerationResult {
+                        flagged: categories.values().any(|flagged| *flagged),
+                        categories,
+                        ..Default::default()
+                    }
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_guard() {
+        let provider = ScriptedProvider(std::sync::Mutex::new(vec![
+            ChatCompletionMessage::new(ChatCompletionRole::Assistant, "Calm down".to_string()),
+            ChatCompletionMessage::new(ChatCompletionRole::Assistant, "some violence/graphic details".to_string()),
+            ChatCompletionMessage::new(ChatCompletionRole::Assistant, "more hate".to_string()),
+        ]));
+        let mut guy = Guy::new();
+        guy.guard = Some(serde_yaml::from_str("policies:\n  harassment: warn\n  violence: redact\n").unwrap());
+        guy.moderator = Some(Moderator::new(KeywordModerator));
+
+        guy.push_message("Some hate speech".to_string(), ChatCompletionRole::User);
+        let error = guy.completion(&provider).await.unwrap_err();
+        assert!(matches!(
+            &error,
+            GuyError::ModerationBlocked(ModerationFlag { role: ChatCompletionRole::User, index: Some(0), policy: Policy::Block, .. })
+        ));
+        assert!(guy.usage.is_empty());
+
+        guy.history[0].content = "Some harassment".into();
+        guy.completion(&provider).await.unwrap();
+        assert_eq!(guy.moderation_flags[0].categories, ["harassment"]);
+        assert_eq!(guy.moderation_flags[0].policy, Policy::Warn);
+
+        guy.push_message("Tell me more".to_string(), ChatCompletionRole::User);
+        let response = guy.completion(&provider).await.unwrap();
+        assert_eq!(response.choices[0].message.content, redaction(&["violence/graphic".to_string()]));
+        assert_eq!(guy.history.last(), Some(&response.choices[0].message));
+
+        guy.push_message("And?".to_string(), ChatCompletionRole::User);
+        let history = guy.history.len();
+        let error = guy.completion(&provider).await.unwrap_err();
+        assert!(matches!(error, GuyError::ModerationBlocked(ModerationFlag { role: ChatCompletionRole::Assistant, index: None, .. })));
+        assert_eq!(guy.history.len(), history);
+    }
+
+    #[tokio::test]
+    async fn test_guard_stream() {
+        let provider = ScriptedProvider(std::sync::Mutex::new(vec![
+            ChatCompletionMessage::new(ChatCompletionRole::Assistant, "some violence/graphic details".to_string()),
+            ChatCompletionMessage::new(ChatCompletionRole::Assistant, "more hate".to_string()),
+        ]));
+        let mut guy = Guy::new();
+        guy.guard = Some(serde_yaml::from_str("policies:\n  violence: redact\n").unwrap());
+        guy.moderator = Some(Moderator::new(KeywordModerator));
+
+        // Only the redacted answer is streamed.
+        let mut streamed = String::new();
+        guy.push_message("Tell me".to_string(), ChatCompletionRole::User);
+        guy.completion_stream(&provider, |token| streamed.push_str(token)).await.unwrap();
+        assert_eq!(streamed, redaction(&["violence/graphic".to_string()]));
+
+        // Nothing of a blocked one.
+        streamed.clear();
+        guy.push_message("And?".to_string(), ChatCompletionRole::User);
+        let error = guy.completion_stream(&provider, |token| streamed.push_str(token)).await.unwrap_err();
+        assert!(matches!(error, GuyError::ModerationBlocked(_)));
+        assert!(streamed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_context_window() {
+        let provider = ScriptedProvider(std::sync::Mutex::new(Vec::new()));
+        let mut guy = Guy::new();
+        guy.push_message("hello world".to_string(), ChatCompletionRole::User);
+        assert_eq!(guy.token_count("gpt-4"), 3 + 1 + 2 + 3);
+        assert_eq!(guy.check_context(&provider).unwrap().remaining(), Some(4096 - 9));
+
+        guy.push_message("hello world ".repeat(2048), ChatCompletionRole::User);
+        assert!(guy.context_usage(&provider).overflows());
+        assert!(matches!(guy.completion(&provider).await, Err(GuyError::ContextOverflow { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_compaction() {
+        let provider = ScriptedProvider(std::sync::Mutex::new(vec![
+            ChatCompletionMessage::new(ChatCompletionRole::Assistant, "They said hi".to_string()),
+            ChatCompletionMessage::new(ChatCompletionRole::Assistant, "Hi".to_string()),
+        ]));
+        let mut guy = Guy::new();
+        guy.push_message("Be nice".to_string(), ChatCompletionRole::System);
+        for index in 0..4 {
+            guy.push_message(format!("hi {}", index), ChatCompletionRole::User);
+        }
+        guy.compaction = Some(Compaction {
+            strategy: CompactionStrategy::SlidingWindow { keep_messages: 2 },
+            max_messages: Some(4),
+            max_tokens: None,
+        });
+        assert!(guy.needs_compaction(&provider));
+        let record = guy.compact(&provider).await.unwrap().unwrap();
+        assert_eq!(record.messages.len(), 2);
+        let contents = guy.history.iter().map(|message| message.content.to_string()).collect::<Vec<_>>();
+        assert_eq!(contents, ["Be nice", "hi 2", "hi 3"]);
+
+        guy.compaction = Some(Compaction {
+            strategy: CompactionStrategy::Summarize {
+                keep_messages: 1,
+                prompt: None,
+            },
+            max_messages: Some(2),
+            max_tokens: None,
+        });
+        let response = guy.completion(&provider).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "Hi");
+        assert_eq!(guy.history[1].name.as_deref(), Some(SUMMARY_MESSAGE_NAME));
+        assert!(guy.history[1].content.text().ends_with("They said hi"));
+        assert_eq!(guy.history.len(), 4);
+        assert_eq!(guy.compactions.len(), 2);
+        assert_eq!(guy.usage.total().calls, 2);
+
+        guy.compaction = Some(Compaction {
+            strategy: CompactionStrategy::DropOldest { target_tokens: 20 },
+            max_messages: None,
+            max_tokens: Some(20),
+        });
+        assert!(guy.needs_compaction(&provider));
+        guy.compact(&provider).await.unwrap();
+        // System messages and the last message are kept even above the target.
+        let contents = guy.history.iter().map(|message| message.content.to_string()).collect::<Vec<_>>();
+        assert_eq!(contents.len(), 3);
+        assert_eq!((contents[0].as_str(), contents[2].as_str()), ("Be nice", "Hi"));
+    }
+
+    #[tokio::test]
+    async fn test_knowledge() {
+        let knowledge = Knowledge {
+            files: Vec::new(),
+            globs: vec!["../../tests-data/knowledge/*.md".to_string()],
+            chunking: Chunking {
+                max_tokens: 32,
+                overlap_lines: 1,
+            },
+            top_k: 1,
+            min_score: Some(0.1),
+            embedding: Default::default(),
+        };
+        let index = vector_index::VectorIndex::temporary(KNOWLEDGE_METRIC).unwrap();
+        let base = KnowledgeBase::new(index, api_connector::embeddings::HashingEmbedder::default());
+        let report = base.rebuild(&knowledge).await.unwrap();
+        assert_eq!(report.sources.len(), 2);
+        assert!(report.chunks > 2);
+        assert_eq!(base.index().len(), report.chunks);
+
+        let provider = ScriptedProvider(std::sync::Mutex::new(vec![ChatCompletionMessage::new(
+            ChatCompletionRole::Assistant,
+            "Run `gcloud sql backups create` [1]".to_string(),
+        )]));
+        let mut guy = Guy::new();
+        guy.knowledge = Some(knowledge);
+        guy.knowledge_base = Some(base);
+        guy.push_message("Be brief".to_string(), ChatCompletionRole::System);
+        guy.push_message("How do I create Cloud SQL backups?".to_string(), ChatCompletionRole::User);
+        let context = guy.retrieve_context().await.unwrap().unwrap();
+        assert_eq!(context.name.as_deref(), Some(KNOWLEDGE_MESSAGE_NAME));
+        assert!(context.content.text().contains("[1] ../../tests-data/knowledge/postgresql.md:"));
+        assert!(context.content.text().contains("gcloud sql backups create"));
+        let prompt = guy.prompt(Some(&context));
+        assert_eq!(prompt[1], context);
+        assert_eq!(prompt.len(), 3);
+
+        guy.completion(&provider).await.unwrap();
+        assert_eq!(guy.history.len(), 3);
+        assert!(guy.history.iter().all(|message| message.name.as_deref() != Some(KNOWLEDGE_MESSAGE_NAME)));
+        assert_eq!(guy.usage.total().calls, 3);
+    }
+
+    #[tokio::test]
+    async fn test_function_template() {
+        let template = GuyTemplate::from_yaml_file("../../data/guys/code_doc.yaml").unwrap();
+        let mut guy = Guy::new();
+        guy.load_template(template).await.unwrap();
+        let parameters = &guy.functions[0].parameters;
+        assert_eq!(parameters.properties["arrival_date"].format.as_deref(), Some("date"));
+        assert_eq!(parameters.required, ["arrival_date", "checkout_date", "people"]);
+        assert!(matches!(guy.compaction.as_ref().unwrap().strategy, CompactionStrategy::Summarize { keep_messages: 10, .. }));
+        assert_eq!(guy.parameters.temperature, Some(0.2));
+
+        let template: GuyTemplate = serde_yaml::from_str(
+            "functions:\n  - name: f\n    description: d\n    parameters:\n      type: object\n      properties:\n        tags:\n          type: array\n",
+        )
+        .unwrap();
+        assert!(matches!(template.validate(), Err(GuyError::InvalidFunction(e)) if e.path == "f.parameters.tags"));
+    }
+
+    #[tokio::test]
+    async fn test_function_call_dispatch() {
+        let mut guy = Guy::new();
+        guy.max_function_calls = 1;
+        guy.register_function("add", |arguments| async move {
+            Ok(serde_json::json!(arguments["a"].as_i64().unwrap() + arguments["b"].as_i64().unwrap()))
+        });
+        let call = |name: &str| ChatCompletionMessage {
+            function_call: Some(ChatCompletionFunctionCall {
+                name: name.to_string(),
+                arguments: r#"{"a": 1, "b": 2}"#.to_string(),
+            }),
+            ..ChatCompletionMessage::new(ChatCompletionRole::Assistant, String::new())
+        };
+
+        let mut calls = 0;
+        assert!(!guy.handle_answer(call("add"), &mut calls).await.unwrap());
+        assert_eq!(guy.history[1], ChatCompletionMessage::function_result("add".to_string(), "3".to_string()));
+        assert!(matches!(
+            guy.handle_answer(call("add"), &mut calls).await,
+            Err(GuyError::FunctionCallLimit(1))
+        ));
+        calls = 0;
+        assert!(matches!(
+            guy.handle_answer(call("sub"), &mut calls).await,
+            Err(GuyError::FunctionNotRegistered(_))
+        ));
+        // The failed calls are not kept without an answer.
+        assert_eq!(guy.history.len(), 2);
+        let answer = ChatCompletionMessage::new(ChatCompletionRole::Assistant, "3".to_string());
+        assert!(guy.handle_answer(answer, &mut calls).await.unwrap());
     }
 }
\ No newline at end of file
@@ -4,6 +4,12 @@ pub use std::{
     path::Path, path::PathBuf,
 };
 pub (crate)use api_connector::openai::*;
+pub(crate) use futures_util::StreamExt;
 pub(crate) use crate::error::*;
+pub use crate::compaction::*;
+pub use crate::function::*;
+pub use crate::guard::*;
+pub use crate::knowledge::*;
 pub use crate::template::*;
-pub use crate::Guy;
\ No newline at end of file
+pub use crate::usage::*;
+pub use crate::{ContextUsage, Guy};
\ No newline at end of file
@@ -7,6 +7,17 @@ pub struct GuyTemplate {
     pub history: Vec<ChatCompletionMessageTemplate>,
     #[serde(default)]
     pub functions: Vec<ChatCompletionFunctionTemplate>,
+    #[serde(default)]
+    pub compaction: Option<Compaction>,
+    /// Documents whose relevant passages are injected in the completions.
+    #[serde(default)]
+    pub knowledge: Option<Knowledge>,
+    /// Moderation of the user messages and of the answers.
+    #[serde(default)]
+    pub guard: Option<Guard>,
+    /// Model and sampling options, merged into the guy's ones.
+    #[serde(default)]
+    pub parameters: ChatCompletionParameters,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,24 +25,54 @@ pub struct ChatCompletionFunctionTemplate {
     pub name: String,
     pub description: String,
     pub parameters: ChatCompletionFunctionParametersTemplate,
+    /// Former location of `parameters.required`, still accepted and merged into it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub required: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletionFunctionParametersTemplate {
     #[serde(rename = "type")]
-    pub kind: String,
+    pub kind: JsonSchemaType,
+    #[serde(default)]
     pub properties: HashMap<String, ChatCompletionFunctionPropertyTemplate>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<String>,
 }
 
+/// A function argument, see [`ChatCompletionFunctionProperty`] for the supported JSON-Schema subset.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletionFunctionPropertyTemplate {
     #[serde(rename = "type")]
-    pub kind: String,
-    pub description: String,
+    pub kind: JsonSchemaType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, rename = "enum", skip_serializing_if = "Option::is_none")]
+    pub enum_values: Option<Vec<serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<ChatCompletionFunctionPropertyTemplate>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub properties: Option<HashMap<String, ChatCompletionFunctionPropertyTemplate>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<serde_json::Number>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<serde_json::Number>,
+    #[serde(default, rename = "minLength", alias = "min_length", skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<u64>,
+    #[serde(default, rename = "maxLength", alias = "max_length", skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<u64>,
+    #[serde(default, rename = "minItems", alias = "min_items", skip_serializing_if = "Option::is_none")]
+    pub min_items: Option<u64>,
+    #[serde(default, rename = "maxItems", alias = "max_items", skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<serde_json::Value>,
 }
 
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChatCompletionMessageTemplate {
     User(String),
@@ -42,35 +83,69 @@ pub enum ChatCompletionMessageTemplate {
 
 impl GuyTemplate {
     pub fn from_yaml_file(path: &str) -> crate::error::Result<Self> {
-        Ok(serde_yaml::from_str(&std::fs::read_to_string(path)?)?)
+        let template: Self = serde_yaml::from_str(&std::fs::read_to_string(path)?)?;
+        template.validate()?;
+        Ok(template)
+    }
+
+    /// Check the function schemas.
+    pub fn validate(&self) -> crate::error::Result<()> {
+        for function in self.functions.iter() {
+            ChatCompletionFunction::try_from(function.clone())?;
+        }
+        Ok(())
     }
 }
 
-impl Into<ChatCompletionFunction> for ChatCompletionFunctionTemplate {
-    fn into(self) -> ChatCompletionFunction {
-        ChatCompletionFunction {
-            name: self.name,
-            description: self.description,
-            parameters: self.parameters.into(),
-            required: self.required,
+impl TryFrom<ChatCompletionFunctionTemplate> for ChatCompletionFunction {
+    type Error = GuyError;
+
+    fn try_from(template: ChatCompletionFunctionTemplate) -> crate::error::Result<Self> {
+        let mut parameters: ChatCompletionFunctionParameters = template.parameters.into();
+        for name in template.required {
+            if !parameters.required.contains(&name) {
+                parameters.required.push(name);
+            }
         }
+        let function = ChatCompletionFunction {
+            name: template.name,
+            description: template.description,
+            parameters,
+        };
+        function.validate()?;
+        Ok(function)
     }
 }
 
-impl Into<ChatCompletionFunctionParameters> for ChatCompletionFunctionParametersTemplate {
-    fn into(self) -> ChatCompletionFunctionParameters {
+impl From<ChatCompletionFunctionParametersTemplate> for ChatCompletionFunctionParameters {
+    fn from(template: ChatCompletionFunctionParametersTemplate) -> Self {
         ChatCompletionFunctionParameters {
-            kind: self.kind,
-            properties: self.properties.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            kind: template.kind,
+            properties: template.properties.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            required: template.required,
         }
     }
 }
 
-impl  Into<ChatCompletionFunctionProperty> for ChatCompletionFunctionPropertyTemplate {
-    fn into(self) -> ChatCompletionFunctionProperty {
+impl From<ChatCompletionFunctionPropertyTemplate> for ChatCompletionFunctionProperty {
+    fn from(template: ChatCompletionFunctionPropertyTemplate) -> Self {
         ChatCompletionFunctionProperty {
-            kind: self.kind,
-            description: self.description,
+            kind: template.kind,
+            description: template.description,
+            enum_values: template.enum_values,
+            format: template.format,
+            items: template.items.map(|items| Box::new((*items).into())),
+            properties: template
+                .properties
+                .map(|properties| properties.into_iter().map(|(k, v)| (k, v.into())).collect()),
+            required: template.required,
+            minimum: template.minimum,
+            maximum: template.maximum,
+            min_length: template.min_length,
+            max_length: template.max_length,
+            min_items: template.min_items,
+            max_items: template.max_items,
+            default: template.default,
         }
     }
 }
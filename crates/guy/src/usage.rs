@@ -0,0 +1,257 @@
+use crate::prelude::*;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::BTreeMap;
+
+/// Price of a model in USD per 1K tokens.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct ModelPricing {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// Prices indexed by model name.
+///
+/// A model is priced by its longest matching prefix so dated snapshots (`gpt-4-0613`) use the
+/// price of their family (`gpt-4`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct PricingTable {
+    models: HashMap<String, ModelPricing>,
+}
+
+/// Spending limits of a guy in USD.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct Budget {
+    /// A warning is reported once the spend reaches this amount.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft: Option<f64>,
+    /// No more completion is performed once the spend reaches this amount.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hard: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetStatus {
+    Within,
+    SoftExceeded,
+    HardExceeded,
+}
+
+/// A single completion call.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UsageRecord {
+    pub date: DateTime<Utc>,
+    pub model: String,
+    pub usage: Usage,
+    /// `None` when the model is not in the pricing table.
+    pub cost: Option<f64>,
+}
+
+/// Every completion call made by a guy.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct UsageLedger {
+    records: Vec<UsageRecord>,
+}
+
+/// Aggregated usage of a set of [`UsageRecord`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UsageSummary {
+    pub calls: u64,
+    pub usage: Usage,
+    pub cost: f64,
+    /// Calls whose cost is unknown (not counted in `cost`).
+    pub unpriced_calls: u64,
+}
+
+impl PricingTable {
+    pub fn empty() -> Self {
+        Self {
+            models: HashMap::new(),
+        }
+    }
+
+    pub fn from_yaml_file(path: impl AsRef<Path>) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_yaml::from_reader(file)?)
+    }
+
+    pub fn insert(&mut self, model: impl Into<String>, pricing: ModelPricing) {
+        self.models.insert(model.into(), pricing);
+    }
+
+    /// Override the prices of `other`'s models.
+    pub fn extend(&mut self, other: PricingTable) {
+        self.models.extend(other.models);
+    }
+
+    pub fn get(&self, model: &str) -> Option<ModelPricing> {
+        self.models
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, pricing)| *pricing)
+    }
+
+    pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+        let pricing = self.get(model)?;
+        Some(
+            (usage.prompt_tokens as f64 * pricing.prompt + usage.completion_tokens as f64 * pricing.completion)
+                / 1000.0,
+        )
+    }
+}
+
+impl Default for PricingTable {
+    /// OpenAI's public prices.
+    fn default() -> Self {
+        let mut table = Self::empty();
+        for (model, prompt, completion) in [
+            ("gpt-4", 0.03, 0.06),
+            ("gpt-4-32k", 0.06, 0.12),
+            ("gpt-4-1106", 0.01, 0.03),
+            ("gpt-4-0125", 0.01, 0.03),
+            ("gpt-4-turbo", 0.01, 0.03),
+            ("gpt-4o", 0.0025, 0.01),
+            ("gpt-4o-mini", 0.00015, 0.0006),
+            ("gpt-4.1", 0.002, 0.008),
+            ("gpt-4.1-mini", 0.0004, 0.0016),
+            ("gpt-4.1-nano", 0.0001, 0.0004),
+            ("o1", 0.015, 0.06),
+            ("o1-mini", 0.0011, 0.0044),
+            ("o3", 0.002, 0.008),
+            ("o3-mini", 0.0011, 0.0044),
+            ("gpt-3.5-turbo", 0.0015, 0.002),
+            ("gpt-3.5-turbo-16k", 0.003, 0.004),
+            ("gpt-3.5-turbo-1106", 0.001, 0.002),
+            ("text-embedding-ada-002", 0.0001, 0.0),
+            ("text-embedding-3-small", 0.00002, 0.0),
+            ("text-embedding-3-large", 0.00013, 0.0),
+        ] {
+            table.insert(model, ModelPricing { prompt, completion });
+        }
+        table
+    }
+}
+
+impl Budget {
+    pub fn status(&self, spent: f64) -> BudgetStatus {
+        let reached = |limit: Option<f64>| limit.is_some_and(|limit| spent >= limit);
+        if reached(self.hard) {
+            BudgetStatus::HardExceeded
+        } else if reached(self.soft) {
+            BudgetStatus::SoftExceeded
+        } else {
+            BudgetStatus::Within
+        }
+    }
+}
+
+impl UsageLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a call made now, priced with `pricing`.
+    pub fn record(&mut self, model: String, usage: Usage, pricing: &PricingTable) -> &UsageRecord {
+        let cost = pricing.cost(&model, &usage);
+        self.records.push(UsageRecord {
+            date: Utc::now(),
+            model,
+            usage,
+            cost,
+        });
+        self.records.last().unwrap()
+    }
+
+    pub fn records(&self) -> &[UsageRecord] {
+        &self.records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn total_cost(&self) -> f64 {
+        self.records.iter().filter_map(|record| record.cost).sum()
+    }
+
+    pub fn total(&self) -> UsageSummary {
+        let mut summary = UsageSummary::default();
+        for record in self.records.iter() {
+            summary.push(record);
+        }
+        summary
+    }
+
+    pub fn by_model(&self) -> BTreeMap<String, UsageSummary> {
+        self.group_by(|record| record.model.clone())
+    }
+
+    /// Usage per (UTC) day.
+    pub fn by_day(&self) -> BTreeMap<NaiveDate, UsageSummary> {
+        self.group_by(|record| record.date.date_naive())
+    }
+
+    fn group_by<K: Ord>(&self, key: impl Fn(&UsageRecord) -> K) -> BTreeMap<K, UsageSummary> {
+        let mut groups = BTreeMap::<K, UsageSummary>::new();
+        for record in self.records.iter() {
+            groups.entry(key(record)).or_default().push(record);
+        }
+        groups
+    }
+}
+
+impl UsageSummary {
+    pub fn push(&mut self, record: &UsageRecord) {
+        self.calls += 1;
+        self.usage += record.usage;
+        match record.cost {
+            Some(cost) => self.cost += cost,
+            None => self.unpriced_calls += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ledger() {
+        let pricing = PricingTable::default();
+        assert_eq!(pricing.get("gpt-4-0613").unwrap().prompt, 0.03);
+        assert_eq!(pricing.get("gpt-4-32k-0613").unwrap().prompt, 0.06);
+        assert!(pricing.get("mistral-7b-instruct").is_none());
+        assert_eq!(pricing.get("gpt-4o-2024-08-06").unwrap().prompt, 0.0025);
+        assert_eq!(pricing.get("gpt-4o-mini").unwrap().prompt, 0.00015);
+        assert_eq!(pricing.get("gpt-4-0125-preview").unwrap().prompt, 0.01);
+        assert_eq!(pricing.get("text-embedding-3-small").unwrap().prompt, 0.00002);
+
+        let mut ledger = UsageLedger::new();
+        let usage = Usage {
+            prompt_tokens: 1000,
+            completion_tokens: 500,
+            total_tokens: 1500,
+        };
+        ledger.record("gpt-4-0613".to_string(), usage, &pricing);
+        ledger.record("gpt-4".to_string(), usage, &pricing);
+        ledger.record("mistral-7b-instruct".to_string(), usage, &pricing);
+        assert!((ledger.total_cost() - 0.12).abs() < 1e-9);
+
+        let total = ledger.total();
+        assert_eq!(total.calls, 3);
+        assert_eq!(total.unpriced_calls, 1);
+        assert_eq!(total.usage.total_tokens, 4500);
+        assert_eq!(ledger.by_model().len(), 3);
+        assert_eq!(ledger.by_day().len(), 1);
+
+        let budget = Budget {
+            soft: Some(0.1),
+            hard: Some(0.2),
+        };
+        assert_eq!(budget.status(0.05), BudgetStatus::Within);
+        assert_eq!(budget.status(ledger.total_cost()), BudgetStatus::SoftExceeded);
+        assert_eq!(budget.status(0.2), BudgetStatus::HardExceeded);
+    }
+}
@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum IndexError {
+    #[error("Storage error: {}", _0)]
+    Sled(#[from] sled::Error),
+    #[error("JSON error: {}", _0)]
+    Json(#[from] serde_json::Error),
+    #[error(
+        "Dimension mismatch: the index holds {} dimensions vectors, got {}",
+        expected,
+        found
+    )]
+    DimensionMismatch { expected: usize, found: usize },
+    #[error("Metric mismatch: the index was created with the `{:?}` metric", _0)]
+    MetricMismatch(crate::index::Metric),
+    #[error("Corrupted entry: `{}`", _0)]
+    Corrupted(String),
+}
+
+pub type Result<T> = std::result::Result<T, IndexError>;
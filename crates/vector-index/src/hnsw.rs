@@ -0,0 +1,242 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Parameters of the HNSW graph (Malkov & Yashunin), see [`crate::VectorIndex::build_hnsw`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HnswParams {
+    /// Neighbors per node and layer (twice as much on the bottom layer).
+    pub m: usize,
+    /// Size of the candidate list while inserting, higher is slower but more accurate.
+    pub ef_construction: usize,
+    /// Size of the candidate list while searching (at least `k`).
+    pub ef_search: usize,
+    /// Seed of the layer assignment, the same inserts always build the same graph.
+    pub seed: u64,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
+            seed: 0,
+        }
+    }
+}
+
+/// In memory HNSW graph over vectors compared by dot product.
+pub(crate) struct Hnsw {
+    params: HnswParams,
+    nodes: Vec<Node>,
+    ids: HashMap<String, usize>,
+    entry: Option<usize>,
+    max_level: usize,
+    rng: StdRng,
+}
+
+struct Node {
+    id: String,
+    vector: Vec<f32>,
+    /// Neighbors per layer, from the bottom one.
+    neighbors: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+/// A node and its similarity to the query, ordered by similarity.
+#[derive(Clone, Copy)]
+struct Scored(f32, usize);
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+pub(crate) fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+impl Hnsw {
+    pub fn new(params: HnswParams) -> Self {
+        Self {
+            params,
+            nodes: Vec::new(),
+            ids: HashMap::new(),
+            entry: None,
+            max_level: 0,
+            rng: StdRng::seed_from_u64(params.seed),
+        }
+    }
+
+    pub fn params(&self) -> HnswParams {
+        self.params
+    }
+
+    /// Insert (or replace) the vector of `id`.
+    pub fn insert(&mut self, id: String, vector: Vec<f32>) {
+        self.remove(&id);
+        let m = self.params.m.max(2);
+        let level = (-(1.0 - self.rng.gen::<f64>()).ln() / (m as f64).ln()).floor() as usize;
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            id: id.clone(),
+            vector,
+            neighbors: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.ids.insert(id, index);
+        let Some(entry) = self.entry else {
+            self.entry = Some(index);
+            self.max_level = level;
+            return;
+        };
+
+        let query = self.nodes[index].vector.clone();
+        let mut entries = vec![entry];
+        for layer in (level + 1..=self.max_level).rev() {
+            entries = self
+                .search_layer(&query, &entries, 1, layer)
+                .iter()
+                .map(|scored| scored.1)
+                .take(1)
+                .collect();
+        }
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let found =
+                self.search_layer(&query, &entries, self.params.ef_construction.max(m), layer);
+            let capacity = if layer == 0 { 2 * m } else { m };
+            let neighbors = found
+                .iter()
+                .map(|scored| scored.1)
+                .take(m)
+                .collect::<Vec<_>>();
+            for neighbor in neighbors.iter() {
+                self.nodes[*neighbor].neighbors[layer].push(index);
+                if self.nodes[*neighbor].neighbors[layer].len() > capacity {
+                    self.prune(*neighbor, layer, capacity);
+                }
+            }
+            self.nodes[index].neighbors[layer] = neighbors;
+            entries = found.iter().map(|scored| scored.1).collect();
+        }
+        if level > self.max_level {
+            self.entry = Some(index);
+            self.max_level = level;
+        }
+    }
+
+    /// Deleted nodes stay in the graph to keep it connected but are never returned.
+    pub fn remove(&mut self, id: &str) -> bool {
+        match self.ids.remove(id) {
+            Some(index) => {
+                self.nodes[index].deleted = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The ids of the `k` most similar vectors with their similarity, most similar first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(String, f32)> {
+        let Some(entry) = self.entry else {
+            return Vec::new();
+        };
+        let mut entries = vec![entry];
+        for layer in (1..=self.max_level).rev() {
+            entries = self
+                .search_layer(query, &entries, 1, layer)
+                .iter()
+                .map(|scored| scored.1)
+                .take(1)
+                .collect();
+        }
+        self.search_layer(query, &entries, self.params.ef_search.max(k), 0)
+            .into_iter()
+            .filter(|scored| !self.nodes[scored.1].deleted)
+            .take(k)
+            .map(|Scored(similarity, index)| (self.nodes[index].id.clone(), similarity))
+            .collect()
+    }
+
+    /// Greedy best-first search of a layer, the `ef` best nodes are returned, best first.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entries: &[usize],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Scored> {
+        let mut visited = entries.iter().copied().collect::<HashSet<_>>();
+        let mut candidates = BinaryHeap::new();
+        let mut results = BinaryHeap::new();
+        for entry in entries {
+            let scored = Scored(dot(query, &self.nodes[*entry].vector), *entry);
+            candidates.push(scored);
+            results.push(Reverse(scored));
+        }
+        while let Some(candidate) = candidates.pop() {
+            let worst = results
+                .peek()
+                .map(|Reverse(worst)| worst.0)
+                .unwrap_or(f32::MIN);
+            if results.len() >= ef && candidate.0 < worst {
+                break;
+            }
+            for neighbor in self.nodes[candidate.1].neighbors[layer].iter() {
+                if !visited.insert(*neighbor) {
+                    continue;
+                }
+                let scored = Scored(dot(query, &self.nodes[*neighbor].vector), *neighbor);
+                let worst = results
+                    .peek()
+                    .map(|Reverse(worst)| worst.0)
+                    .unwrap_or(f32::MIN);
+                if results.len() < ef || scored.0 > worst {
+                    candidates.push(scored);
+                    results.push(Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        let mut results = results
+            .into_iter()
+            .map(|Reverse(scored)| scored)
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| b.cmp(a));
+        results
+    }
+
+    /// Keep the `capacity` closest neighbors of `index` on `layer`.
+    fn prune(&mut self, index: usize, layer: usize, capacity: usize) {
+        let vector = &self.nodes[index].vector;
+        let mut neighbors = self.nodes[index].neighbors[layer]
+            .iter()
+            .map(|neighbor| Scored(dot(vector, &self.nodes[*neighbor].vector), *neighbor))
+            .collect::<Vec<_>>();
+        neighbors.sort_by(|a, b| b.cmp(a));
+        self.nodes[index].neighbors[layer] = neighbors
+            .into_iter()
+            .take(capacity)
+            .map(|scored| scored.1)
+            .collect();
+    }
+}
@@ -0,0 +1,419 @@
+use crate::error::*;
+use crate::hnsw::{dot, Hnsw, HnswParams};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const META_KEY: &[u8] = b"meta";
+const VECTOR_PREFIX: &[u8] = b"vector/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Vectors are normalized when inserted, scores are in `[-1, 1]`.
+    Cosine,
+    DotProduct,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Meta {
+    metric: Metric,
+    dimensions: Option<usize>,
+}
+
+/// A stored vector and the metadata it was inserted with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub id: String,
+    pub vector: Vec<f32>,
+    pub metadata: serde_json::Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub id: String,
+    pub score: f32,
+    pub metadata: serde_json::Value,
+}
+
+/// Vectors (and their JSON metadata) persisted in a sled tree, searched by brute force or with
+/// an in memory HNSW graph once [`VectorIndex::build_hnsw`] has been called.
+///
+/// The tree must be dedicated to the index, a store can keep several indexes in its own db.
+pub struct VectorIndex {
+    tree: sled::Tree,
+    meta: RwLock<Meta>,
+    hnsw: RwLock<Option<Hnsw>>,
+}
+
+impl Metric {
+    pub fn similarity(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Self::Cosine => {
+                let norm = (dot(a, a) * dot(b, b)).sqrt();
+                if norm == 0.0 {
+                    0.0
+                } else {
+                    dot(a, b) / norm
+                }
+            }
+            Self::DotProduct => dot(a, b),
+        }
+    }
+
+    /// Prepare a vector so that the similarity is a dot product.
+    fn prepare(&self, mut vector: Vec<f32>) -> Vec<f32> {
+        if *self == Self::Cosine {
+            let norm = dot(&vector, &vector).sqrt();
+            if norm > 0.0 {
+                vector.iter_mut().for_each(|value| *value /= norm);
+            }
+        }
+        vector
+    }
+}
+
+impl VectorIndex {
+    /// Open the index stored in `tree`, `metric` must be the one the index was created with.
+    pub fn open(tree: sled::Tree, metric: Metric) -> Result<Self> {
+        let meta = match tree.get(META_KEY)? {
+            Some(bytes) => {
+                let meta: Meta = serde_json::from_slice(&bytes)?;
+                if meta.metric != metric {
+                    return Err(IndexError::MetricMismatch(meta.metric));
+                }
+                meta
+            }
+            None => {
+                let meta = Meta {
+                    metric,
+                    dimensions: None,
+                };
+                tree.insert(META_KEY, serde_json::to_vec(&meta)?)?;
+                meta
+            }
+        };
+        Ok(Self {
+            tree,
+            meta: RwLock::new(meta),
+            hnsw: RwLock::new(None),
+        })
+    }
+
+    /// An index in a temporary sled db, deleted when dropped.
+    pub fn temporary(metric: Metric) -> Result<Self> {
+        let db = sled::Config::new().temporary(true).open()?;
+        Self::open(db.open_tree("index")?, metric)
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.meta().metric
+    }
+
+    /// Size of the stored vectors, `None` until the first insert.
+    pub fn dimensions(&self) -> Option<usize> {
+        self.meta().dimensions
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.scan_prefix(VECTOR_PREFIX).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.scan_prefix(VECTOR_PREFIX).next().is_none()
+    }
+
+    /// Insert (or replace) the vector of `id`.
+    pub fn insert(&self, id: &str, vector: Vec<f32>, metadata: serde_json::Value) -> Result<()> {
+        {
+            let mut meta = self.meta.write().unwrap_or_else(|e| e.into_inner());
+            match meta.dimensions {
+                Some(expected) if expected != vector.len() => {
+                    return Err(IndexError::DimensionMismatch {
+                        expected,
+                        found: vector.len(),
+                    })
+                }
+                Some(_) => {}
+                None => {
+                    meta.dimensions = Some(vector.len());
+                    self.tree.insert(META_KEY, serde_json::to_vec(&*meta)?)?;
+                }
+            }
+        }
+        let vector = self.metric().prepare(vector);
+        self.tree.insert(key(id), encode(&vector, &metadata)?)?;
+        if let Some(hnsw) = self.hnsw_mut().as_mut() {
+            hnsw.insert(id.to_string(), vector);
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        if let Some(hnsw) = self.hnsw_mut().as_mut() {
+            hnsw.remove(id);
+        }
+        Ok(self.tree.remove(key(id))?.is_some())
+    }
+
+    /// Remove every vector, the next insert sets the dimensions again.
+    pub fn clear(&self) -> Result<()> {
+        for key in self.tree.scan_prefix(VECTOR_PREFIX).keys() {
+            self.tree.remove(key?)?;
+        }
+        let mut meta = self.meta.write().unwrap_or_else(|e| e.into_inner());
+        meta.dimensions = None;
+        self.tree.insert(META_KEY, serde_json::to_vec(&*meta)?)?;
+        if let Some(hnsw) = self.hnsw_mut().as_mut() {
+            *hnsw = Hnsw::new(hnsw.params());
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Entry>> {
+        match self.tree.get(key(id))? {
+            Some(bytes) => Ok(Some(decode(id.to_string(), &bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every entry, ordered by id.
+    pub fn iter(&self) -> impl Iterator<Item = Result<Entry>> {
+        self.tree.scan_prefix(VECTOR_PREFIX).map(|item| {
+            let (key, bytes) = item?;
+            let id = String::from_utf8_lossy(&key[VECTOR_PREFIX.len()..]).into_owned();
+            decode(id, &bytes)
+        })
+    }
+
+    /// The `k` entries most similar to `query`, most similar first.
+    pub fn search(&self, query: &[f32], k: usize) -> Result<Vec<SearchResult>> {
+        if let Some(expected) = self.dimensions() {
+            if expected != query.len() {
+                return Err(IndexError::DimensionMismatch {
+                    expected,
+                    found: query.len(),
+                });
+            }
+        }
+        let query = self.metric().prepare(query.to_vec());
+        if let Some(hnsw) = self.hnsw.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            let mut results = Vec::new();
+            for (id, score) in hnsw.search(&query, k) {
+                if let Some(entry) = self.get(&id)? {
+                    results.push(SearchResult {
+                        id,
+                        score,
+                        metadata: entry.metadata,
+                    });
+                }
+            }
+            return Ok(results);
+        }
+
+        // Brute force: keep the k best entries in a min-heap.
+        let mut best = BinaryHeap::new();
+        for entry in self.iter() {
+            let entry = entry?;
+            best.push(Reverse(Candidate(dot(&query, &entry.vector), entry)));
+            if best.len() > k {
+                best.pop();
+            }
+        }
+        let mut results = best
+            .into_iter()
+            .map(|Reverse(Candidate(score, entry))| SearchResult {
+                id: entry.id,
+                score,
+                metadata: entry.metadata,
+            })
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(results)
+    }
+
+    /// Build an HNSW graph of the stored vectors, searches use it from now on (approximate but
+    /// sub-linear). The graph lives in memory and follows the inserts and removals.
+    pub fn build_hnsw(&self, params: HnswParams) -> Result<()> {
+        let mut hnsw = Hnsw::new(params);
+        for entry in self.iter() {
+            let entry = entry?;
+            hnsw.insert(entry.id, entry.vector);
+        }
+        *self.hnsw_mut() = Some(hnsw);
+        Ok(())
+    }
+
+    /// Go back to exact (brute force) searches.
+    pub fn drop_hnsw(&self) {
+        *self.hnsw_mut() = None;
+    }
+
+    pub fn has_hnsw(&self) -> bool {
+        self.hnsw
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn meta(&self) -> RwLockReadGuard<'_, Meta> {
+        self.meta.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn hnsw_mut(&self) -> RwLockWriteGuard<'_, Option<Hnsw>> {
+        self.hnsw.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// An entry and its score, ordered by score.
+struct Candidate(f32, Entry);
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.total_cmp(&other.0).is_eq()
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+fn key(id: &str) -> Vec<u8> {
+    [VECTOR_PREFIX, id.as_bytes()].concat()
+}
+
+/// `[metadata length (u32 LE)][metadata JSON][vector (f32 LE)]`
+fn encode(vector: &[f32], metadata: &serde_json::Value) -> Result<Vec<u8>> {
+    let metadata = serde_json::to_vec(metadata)?;
+    let mut bytes = Vec::with_capacity(4 + metadata.len() + vector.len() * 4);
+    bytes.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&metadata);
+    for value in vector {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+fn decode(id: String, bytes: &[u8]) -> Result<Entry> {
+    let corrupted = || IndexError::Corrupted(id.clone());
+    let length = bytes.get(..4).ok_or_else(corrupted)?;
+    let length = u32::from_le_bytes(length.try_into().map_err(|_| corrupted())?) as usize;
+    let metadata = bytes.get(4..4 + length).ok_or_else(corrupted)?;
+    let vector = bytes.get(4 + length..).ok_or_else(corrupted)?;
+    if vector.len() % 4 != 0 {
+        return Err(corrupted());
+    }
+    Ok(Entry {
+        metadata: serde_json::from_slice(metadata)?,
+        vector: vector
+            .chunks_exact(4)
+            .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+            .collect(),
+        id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_vectors(count: usize, dimensions: usize) -> Vec<Vec<f32>> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..count)
+            .map(|_| (0..dimensions).map(|_| rng.gen_range(-1.0..1.0)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_persistence_and_search() {
+        let directory = tempfile::tempdir().unwrap();
+        let db = sled::open(directory.path()).unwrap();
+        let index = VectorIndex::open(db.open_tree("index").unwrap(), Metric::Cosine).unwrap();
+        index
+            .insert("x", vec![1.0, 0.0], serde_json::json!({"name": "x"}))
+            .unwrap();
+        index
+            .insert("y", vec![0.0, 2.0], serde_json::json!({"name": "y"}))
+            .unwrap();
+        index
+            .insert("xy", vec![1.0, 1.0], serde_json::Value::Null)
+            .unwrap();
+        assert!(matches!(
+            index.insert("z", vec![1.0], serde_json::Value::Null),
+            Err(IndexError::DimensionMismatch {
+                expected: 2,
+                found: 1
+            })
+        ));
+        drop(index);
+
+        assert!(matches!(
+            VectorIndex::open(db.open_tree("index").unwrap(), Metric::DotProduct),
+            Err(IndexError::MetricMismatch(Metric::Cosine))
+        ));
+        let index = VectorIndex::open(db.open_tree("index").unwrap(), Metric::Cosine).unwrap();
+        assert_eq!(index.len(), 3);
+        let results = index.search(&[0.0, 5.0], 2).unwrap();
+        assert_eq!(results[0].id, "y");
+        assert_eq!(results[0].metadata["name"], "y");
+        assert!((results[0].score - 1.0).abs() < 1e-6);
+        assert_eq!(results[1].id, "xy");
+
+        assert!(index.remove("y").unwrap());
+        assert_eq!(index.search(&[0.0, 5.0], 1).unwrap()[0].id, "xy");
+    }
+
+    #[test]
+    fn test_hnsw_matches_brute_force() {
+        let directory = tempfile::tempdir().unwrap();
+        let db = sled::open(directory.path()).unwrap();
+        let index = VectorIndex::open(db.open_tree("index").unwrap(), Metric::DotProduct).unwrap();
+        let vectors = random_vectors(500, 16);
+        for (position, vector) in vectors.iter().enumerate() {
+            index
+                .insert(
+                    &position.to_string(),
+                    vector.clone(),
+                    serde_json::Value::Null,
+                )
+                .unwrap();
+        }
+        let queries = random_vectors(520, 16).split_off(500);
+        let exact = queries
+            .iter()
+            .map(|query| index.search(query, 10).unwrap())
+            .collect::<Vec<_>>();
+
+        index.build_hnsw(HnswParams::default()).unwrap();
+        let mut found = 0;
+        for (query, exact) in queries.iter().zip(exact.iter()) {
+            let approximate = index.search(query, 10).unwrap();
+            found += approximate
+                .iter()
+                .filter(|result| exact.iter().any(|e| e.id == result.id))
+                .count();
+        }
+        // Recall@10 of the approximate search.
+        assert!(found as f32 / (queries.len() * 10) as f32 > 0.9);
+
+        index.remove(&exact[0][0].id).unwrap();
+        assert_ne!(index.search(&queries[0], 1).unwrap()[0].id, exact[0][0].id);
+    }
+}
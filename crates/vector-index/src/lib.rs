@@ -0,0 +1,7 @@
+pub mod error;
+pub mod hnsw;
+pub mod index;
+
+pub use crate::error::{IndexError, Result};
+pub use crate::hnsw::HnswParams;
+pub use crate::index::{Entry, Metric, SearchResult, VectorIndex};